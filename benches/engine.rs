@@ -0,0 +1,490 @@
+//! Throughput and memory benchmarks.
+//!
+//! `cargo bench` runs the criterion groups (rows/sec is reported as
+//! `thrpt`) and then prints the peak resident memory of one full run per
+//! strategy and input size. End to end inputs are generated once into
+//! `target/bench-data` and reused afterwards. Sizes default to 1M rows,
+//! set `TX2ACC_BENCH_ROWS=1000000,10000000` for the bigger comparison.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group};
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::hint::black_box;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::mpsc::{Receiver, sync_channel};
+use std::time::Instant;
+use tx2acc::asset::AssetRegistry;
+use tx2acc::client::Client;
+use tx2acc::compact_store::CompactTransactionStore;
+use tx2acc::config::Config;
+use tx2acc::disk_store::open_disk_stores;
+use tx2acc::generate::{GeneratorConfig, generate};
+use tx2acc::handlers::handle_transaction;
+use tx2acc::history::NoHistory;
+use tx2acc::ids::{ClientId, TransactionId};
+use tx2acc::ingest::{InputFormat, read_transactions};
+use tx2acc::process::process_transactions;
+use tx2acc::processed_transaction::ProcessedTransaction;
+use tx2acc::raw_transaction::RawTransaction;
+use tx2acc::sqlite_store::open_sqlite_stores;
+use tx2acc::store::{AccountStore, TransactionStore};
+
+/// Set on the child processes we spawn to measure peak memory,
+/// as `<strategy>:<rows>`.
+const PROBE_ENV: &str = "TX2ACC_MEMORY_PROBE";
+
+/// A way of turning an input stream into accounts, so that different
+/// storage/processing approaches can be compared on the same inputs.
+/// Returns the number of rows read.
+type Strategy = fn(&mut dyn Read) -> usize;
+
+const STRATEGIES: &[(&str, Strategy)] = &[
+    ("hashmap", run_hashmap),
+    ("compact", run_compact),
+    ("disk", run_disk),
+    ("sqlite", run_sqlite),
+    ("sharded", run_sharded),
+    ("external_sort", run_external_sort),
+];
+
+/// How many threads `run_sharded` spreads the clients over.
+const SHARDS: u64 = 4;
+/// How big the chunks of rows `run_sharded` hands its threads get.
+const SHARD_CHUNK_BYTES: usize = 64 * 1024;
+/// How many rows `run_external_sort` sorts in memory at a time.
+const SORT_RUN_ROWS: usize = 1_000_000;
+
+fn run_with_stores(
+    rdr: &mut dyn Read,
+    transactions: &mut impl TransactionStore,
+    clients: &mut impl AccountStore,
+) -> usize {
+    let summary = process_transactions(
+        rdr,
+        InputFormat::Csv,
+        &Config::default(),
+        transactions,
+        clients,
+        &mut NoHistory,
+    )
+    .unwrap();
+    transactions.flush().unwrap();
+    clients.flush().unwrap();
+    black_box(clients.clients().unwrap());
+    summary.rows
+}
+
+fn run_hashmap(rdr: &mut dyn Read) -> usize {
+    let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+    let mut clients: HashMap<ClientId, Client> = HashMap::new();
+    run_with_stores(rdr, &mut transactions, &mut clients)
+}
+
+fn run_compact(rdr: &mut dyn Read) -> usize {
+    let mut clients: HashMap<ClientId, Client> = HashMap::new();
+    run_with_stores(rdr, &mut CompactTransactionStore::new(), &mut clients)
+}
+
+/// Starts from an empty state directory every time, otherwise every row
+/// after the first iteration would be a duplicate.
+fn run_disk(rdr: &mut dyn Read) -> usize {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join(format!("target/bench-data/state-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let rows = {
+        let (mut transactions, mut clients) = open_disk_stores(&dir).unwrap();
+        run_with_stores(rdr, &mut transactions, &mut clients)
+    };
+    fs::remove_dir_all(&dir).unwrap();
+    rows
+}
+
+fn run_sqlite(rdr: &mut dyn Read) -> usize {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join(format!("target/bench-data/state-{}.db", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let rows = {
+        let (mut transactions, mut clients) =
+            open_sqlite_stores(&path, &AssetRegistry::new()).unwrap();
+        run_with_stores(rdr, &mut transactions, &mut clients)
+    };
+    fs::remove_file(&path).unwrap();
+    rows
+}
+
+/// The client ID of a CSV row, `None` if it doesn't have one, which makes
+/// the row fail to parse anyway.
+fn row_client(line: &[u8]) -> Option<u64> {
+    let client = line.split(|&byte| byte == b',').nth(1)?;
+    std::str::from_utf8(client).ok()?.trim().parse().ok()
+}
+
+/// The chunks of rows sent to one of `run_sharded`'s threads, read back
+/// as a single stream.
+struct ChunkReader {
+    chunks: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    read: usize,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.read = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.read);
+        buf[..len].copy_from_slice(&self.chunk[self.read..self.read + len]);
+        self.read += len;
+        Ok(len)
+    }
+}
+
+/// Spreads the clients over `SHARDS` threads, each with its own compact
+/// stores, while this one only splits the rows up.
+///
+/// That's only the same as a single store because clients don't share
+/// anything in generated inputs: there are no transfers or fees, and a
+/// transaction ID only comes back for a duplicate of the same client's row.
+fn run_sharded(rdr: &mut dyn Read) -> usize {
+    let mut lines = BufReader::new(rdr).split(b'\n');
+    let mut header = lines.next().unwrap().unwrap();
+    header.push(b'\n');
+
+    std::thread::scope(|scope| {
+        let mut senders = Vec::new();
+        let mut workers = Vec::new();
+        for _ in 0..SHARDS {
+            let (sender, chunks) = sync_channel(16);
+            senders.push(sender);
+            workers.push(scope.spawn(move || {
+                let mut rdr = ChunkReader {
+                    chunks,
+                    chunk: Vec::new(),
+                    read: 0,
+                };
+                let mut clients: HashMap<ClientId, Client> = HashMap::new();
+                run_with_stores(&mut rdr, &mut CompactTransactionStore::new(), &mut clients)
+            }));
+        }
+
+        let mut chunks = vec![header; SHARDS as usize];
+        for line in lines {
+            let line = line.unwrap();
+            let shard = (row_client(&line).unwrap_or(0) % SHARDS) as usize;
+            chunks[shard].extend_from_slice(&line);
+            chunks[shard].push(b'\n');
+            if chunks[shard].len() >= SHARD_CHUNK_BYTES {
+                senders[shard]
+                    .send(std::mem::take(&mut chunks[shard]))
+                    .unwrap();
+            }
+        }
+        for (sender, chunk) in senders.into_iter().zip(chunks) {
+            sender.send(chunk).unwrap();
+        }
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .sum()
+    })
+}
+
+/// A row of `run_external_sort`'s sorted runs: client, row number, row.
+type SortEntry = (u64, u64, Vec<u8>);
+
+/// Writes `entries` sorted to a run file in `dir`, one
+/// `<client> <row number> <row>` line each.
+fn write_sorted_run(dir: &Path, runs: &mut Vec<PathBuf>, entries: &mut Vec<SortEntry>) {
+    entries.sort_unstable();
+    let path = dir.join(format!("run-{}", runs.len()));
+    let mut out = BufWriter::new(File::create(&path).unwrap());
+    for (client, row, line) in entries.drain(..) {
+        write!(out, "{} {} ", client, row).unwrap();
+        out.write_all(&line).unwrap();
+        out.write_all(b"\n").unwrap();
+    }
+    out.flush().unwrap();
+    runs.push(path);
+}
+
+fn read_sorted_entry(rdr: &mut impl BufRead) -> Option<SortEntry> {
+    let mut line = Vec::new();
+    if rdr.read_until(b'\n', &mut line).unwrap() == 0 {
+        return None;
+    }
+    line.pop();
+    let mut fields = line.splitn(3, |&byte| byte == b' ');
+    let mut number = || -> u64 {
+        std::str::from_utf8(fields.next().unwrap())
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+    let (client, row) = (number(), number());
+    Some((client, row, fields.next().unwrap_or_default().to_vec()))
+}
+
+/// Sorts the rows by client on disk, each client's rows staying in the
+/// order they came in, and then runs one client at a time through fresh
+/// stores. Only a client's rows and `SORT_RUN_ROWS` rows at a time while
+/// sorting have to fit in memory, whatever the size of the input.
+///
+/// Same as `run_sharded`, that relies on clients not sharing anything.
+fn run_external_sort(rdr: &mut dyn Read) -> usize {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join(format!("target/bench-data/sort-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut lines = BufReader::new(rdr).split(b'\n');
+    let mut header = lines.next().unwrap().unwrap();
+    header.push(b'\n');
+    let mut runs = Vec::new();
+    let mut entries = Vec::new();
+    for (row, line) in lines.enumerate() {
+        let line = line.unwrap();
+        let client = row_client(&line).unwrap_or(u64::MAX);
+        entries.push((client, row as u64, line));
+        if entries.len() == SORT_RUN_ROWS {
+            write_sorted_run(&dir, &mut runs, &mut entries);
+        }
+    }
+    if !entries.is_empty() {
+        write_sorted_run(&dir, &mut runs, &mut entries);
+    }
+
+    let mut readers: Vec<_> = runs
+        .iter()
+        .map(|path| BufReader::new(File::open(path).unwrap()))
+        .collect();
+    let mut heap = BinaryHeap::new();
+    for (run, rdr) in readers.iter_mut().enumerate() {
+        if let Some(entry) = read_sorted_entry(rdr) {
+            heap.push(Reverse((entry, run)));
+        }
+    }
+
+    let run_client = |rows: &[u8]| {
+        let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+        run_with_stores(&mut &rows[..], &mut transactions, &mut clients)
+    };
+    let mut rows_read = 0;
+    let mut current = None;
+    let mut rows = header.clone();
+    while let Some(Reverse(((client, _, line), run))) = heap.pop() {
+        if current.is_some_and(|current| current != client) {
+            rows_read += run_client(&rows);
+            rows.truncate(header.len());
+        }
+        current = Some(client);
+        rows.extend_from_slice(&line);
+        rows.push(b'\n');
+        if let Some(entry) = read_sorted_entry(&mut readers[run]) {
+            heap.push(Reverse((entry, run)));
+        }
+    }
+    if current.is_some() {
+        rows_read += run_client(&rows);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+    rows_read
+}
+
+fn bench_sizes() -> Vec<usize> {
+    std::env::var("TX2ACC_BENCH_ROWS")
+        .unwrap_or("1000000".to_string())
+        .split(',')
+        .map(|rows| {
+            rows.trim()
+                .parse()
+                .expect("TX2ACC_BENCH_ROWS must be a list of numbers")
+        })
+        .collect()
+}
+
+fn generator_config(rows: usize) -> GeneratorConfig {
+    GeneratorConfig {
+        rows,
+        clients: 10_000,
+        ..GeneratorConfig::default()
+    }
+}
+
+fn generated_csv(rows: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    generate(&generator_config(rows), &mut bytes).unwrap();
+    bytes
+}
+
+/// Generates the input file for `rows` once and reuses it afterwards.
+///
+/// The file name has a hash of the whole generator config in it, seed and
+/// rates included, so changing any of them generates a new file rather than
+/// reusing one that no longer matches.
+fn generated_file(rows: usize) -> PathBuf {
+    let config = generator_config(rows);
+    let mut hasher = DefaultHasher::new();
+    format!("{:?}", config).hash(&mut hasher);
+
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/bench-data");
+    let path = dir.join(format!(
+        "generated-{}-{}-{:016x}.csv",
+        rows,
+        config.seed,
+        hasher.finish()
+    ));
+    if !path.exists() {
+        fs::create_dir_all(&dir).unwrap();
+        let file = BufWriter::new(File::create(&path).unwrap());
+        generate(&config, file).unwrap();
+    }
+    path
+}
+
+fn bench_handle_transaction(c: &mut Criterion) {
+    let rows = 100_000;
+    let raw_txs: Vec<RawTransaction> =
+        read_transactions(&generated_csv(rows)[..], InputFormat::Csv)
+            .flatten()
+            .collect();
+
+    let mut group = c.benchmark_group("handle_transaction");
+    group.throughput(Throughput::Elements(raw_txs.len() as u64));
+    group.bench_function("hashmap", |b| {
+        b.iter(|| {
+            let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+            let mut clients: HashMap<ClientId, Client> = HashMap::new();
+            for raw_tx in &raw_txs {
+                let _ =
+                    handle_transaction(raw_tx, &Config::default(), &mut transactions, &mut clients);
+            }
+            black_box(&clients);
+        })
+    });
+    group.finish();
+}
+
+fn bench_csv_parsing(c: &mut Criterion) {
+    let rows = 100_000;
+    let bytes = generated_csv(rows);
+
+    let mut group = c.benchmark_group("csv_parsing");
+    group.throughput(Throughput::Elements(rows as u64));
+    group.bench_function("read_transactions", |b| {
+        b.iter(|| read_transactions(&bytes[..], InputFormat::Csv).count())
+    });
+    group.finish();
+}
+
+fn bench_end_to_end(c: &mut Criterion) {
+    let mut group = c.benchmark_group("end_to_end");
+    group.sample_size(10);
+
+    for rows in bench_sizes() {
+        let path = generated_file(rows);
+        group.throughput(Throughput::Elements(rows as u64));
+        for (name, strategy) in STRATEGIES {
+            group.bench_with_input(BenchmarkId::new(*name, rows), &path, |b, path| {
+                b.iter(|| strategy(&mut File::open(path).unwrap()))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_handle_transaction,
+    bench_csv_parsing,
+    bench_end_to_end
+);
+
+/// Peak resident set size of the current process, in kB (Linux only).
+fn peak_rss_kb() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Runs a single strategy over a single input and prints
+/// `<rows read> <seconds> <peak rss kB>` for the parent to pick up.
+fn run_probe(spec: &str) {
+    let (name, rows) = spec
+        .split_once(':')
+        .expect("probe spec is <strategy>:<rows>");
+    let (_, strategy) = STRATEGIES
+        .iter()
+        .find(|(strategy, _)| *strategy == name)
+        .expect("unknown strategy");
+    let path = generated_file(rows.parse().expect("probe rows must be a number"));
+
+    let start = Instant::now();
+    let rows_read = strategy(&mut File::open(path).unwrap());
+    let elapsed = start.elapsed().as_secs_f64();
+    println!("{} {} {}", rows_read, elapsed, peak_rss_kb().unwrap_or(0));
+}
+
+/// Peak memory can't be measured from inside criterion (the process keeps
+/// its high-water mark across benchmarks), so every strategy/size pair
+/// runs once more in a fresh child process.
+fn report_memory() {
+    let exe = std::env::current_exe().unwrap();
+
+    println!();
+    println!(
+        "{:<14} {:>12} {:>14} {:>14}",
+        "strategy", "rows", "rows/sec", "peak RSS (MB)"
+    );
+    for rows in bench_sizes() {
+        // Make sure the probes don't pay for generating the input.
+        generated_file(rows);
+        for (name, _) in STRATEGIES {
+            let output = Command::new(&exe)
+                .env(PROBE_ENV, format!("{}:{}", name, rows))
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let fields: Vec<f64> = stdout
+                .split_whitespace()
+                .filter_map(|field| field.parse().ok())
+                .collect();
+            let [rows_read, seconds, peak_rss_kb] = fields[..] else {
+                eprintln!("Memory probe for {} on {} rows failed", name, rows);
+                continue;
+            };
+            println!(
+                "{:<14} {:>12} {:>14.0} {:>14.1}",
+                name,
+                rows,
+                rows_read / seconds,
+                peak_rss_kb / 1024.0
+            );
+        }
+    }
+}
+
+fn main() {
+    tx2acc::log::set_verbose(false);
+
+    if let Ok(spec) = std::env::var(PROBE_ENV) {
+        run_probe(&spec);
+        return;
+    }
+
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+    report_memory();
+}
@@ -0,0 +1,27 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::collections::HashMap;
+use tx2acc::client::Client;
+use tx2acc::config::Config;
+use tx2acc::handlers::handle_transaction;
+use tx2acc::ids::{ClientId, TransactionId};
+use tx2acc::ingest::csv_reader;
+use tx2acc::processed_transaction::ProcessedTransaction;
+use tx2acc::raw_transaction::RawTransaction;
+
+// Feeds arbitrary bytes through the same CSV reader the binary uses
+// and pushes every row that deserializes into `handle_transaction`.
+// Rows that fail to parse are skipped, exactly like in `main`.
+fuzz_target!(|data: &[u8]| {
+    let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+    let mut clients: HashMap<ClientId, Client> = HashMap::new();
+
+    let mut reader = csv_reader(data);
+    for result in reader.deserialize::<RawTransaction>() {
+        let Ok(raw_tx) = result else {
+            continue;
+        };
+        let _ = handle_transaction(&raw_tx, &Config::default(), &mut transactions, &mut clients);
+    }
+});
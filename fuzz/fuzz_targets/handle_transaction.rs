@@ -0,0 +1,126 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use std::collections::HashMap;
+use tx2acc::clearing::ClearingPeriod;
+use tx2acc::client::Client;
+use tx2acc::config::Config;
+use tx2acc::convert::RawAmount;
+use tx2acc::handlers::handle_transaction;
+use tx2acc::ids::{ClientId, TransactionId};
+use tx2acc::processed_transaction::ProcessedTransaction;
+use tx2acc::raw_transaction::{RawTransaction, RawTransactionType};
+
+#[derive(Debug, Arbitrary)]
+enum FuzzTransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Refund,
+    Reversal,
+    Transfer,
+    Fee,
+    Clear,
+    Activate,
+    Freeze,
+    Dormant,
+    Close,
+}
+
+/// Mirrors `RawTransaction` so the fuzzer can build well-formed rows
+/// without the main crate having to depend on `arbitrary`.
+///
+/// Client and transaction IDs are squeezed into a small range so that
+/// effects actually hit existing transactions most of the time.
+#[derive(Debug, Arbitrary)]
+struct FuzzTransaction {
+    transaction_type: FuzzTransactionType,
+    client_id: u8,
+    transaction_id: u8,
+    amount: Option<f64>,
+    /// Picks one of `ASSETS`, or no asset at all.
+    asset: Option<u8>,
+    /// Who a transfer or fee is to, in the same range as `client_id`.
+    destination: Option<u8>,
+}
+
+/// A sequence of rows, applied with or without a clearing period.
+///
+/// `handle_transaction` doesn't clear anything by itself, so with one
+/// deposits stay pending until a `clear` row comes along.
+#[derive(Debug, Arbitrary)]
+struct FuzzRun {
+    clearing: bool,
+    sequence: Vec<FuzzTransaction>,
+}
+
+const ASSETS: [&str; 3] = ["USD", "BTC", "usd"];
+
+impl From<&FuzzTransaction> for RawTransaction {
+    fn from(tx: &FuzzTransaction) -> Self {
+        let transaction_type = match tx.transaction_type {
+            FuzzTransactionType::Deposit => RawTransactionType::Deposit,
+            FuzzTransactionType::Withdrawal => RawTransactionType::Withdrawal,
+            FuzzTransactionType::Dispute => RawTransactionType::Dispute,
+            FuzzTransactionType::Resolve => RawTransactionType::Resolve,
+            FuzzTransactionType::Chargeback => RawTransactionType::Chargeback,
+            FuzzTransactionType::Refund => RawTransactionType::Refund,
+            FuzzTransactionType::Reversal => RawTransactionType::Reversal,
+            FuzzTransactionType::Transfer => RawTransactionType::Transfer,
+            FuzzTransactionType::Fee => RawTransactionType::Fee,
+            FuzzTransactionType::Clear => RawTransactionType::Clear,
+            FuzzTransactionType::Activate => RawTransactionType::Activate,
+            FuzzTransactionType::Freeze => RawTransactionType::Freeze,
+            FuzzTransactionType::Dormant => RawTransactionType::Dormant,
+            FuzzTransactionType::Close => RawTransactionType::Close,
+        };
+
+        RawTransaction {
+            transaction_type,
+            client_id: u64::from(tx.client_id % 8).into(),
+            transaction_id: u64::from(tx.transaction_id).into(),
+            amount: tx.amount.map(RawAmount::from),
+            timestamp: None,
+            asset: tx
+                .asset
+                .map(|asset| ASSETS[usize::from(asset) % ASSETS.len()].to_string()),
+            destination: tx.destination.map(|to| u64::from(to % 8).into()),
+            reason: None,
+        }
+    }
+}
+
+// Applies an arbitrary sequence of transactions and checks that the
+// `total = available + pending + held` invariant holds for every balance
+// afterwards.
+fuzz_target!(|run: FuzzRun| {
+    let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+    let mut clients: HashMap<ClientId, Client> = HashMap::new();
+    let config = Config {
+        clearing: run.clearing.then_some(ClearingPeriod::Rows(1)),
+        ..Config::default()
+    };
+
+    for tx in &run.sequence {
+        let _ = handle_transaction(
+            &RawTransaction::from(tx),
+            &config,
+            &mut transactions,
+            &mut clients,
+        );
+    }
+
+    for client in clients.values() {
+        for balance in client.balances.values() {
+            assert_eq!(
+                balance.total,
+                balance.available + balance.pending + balance.held,
+                "total != available + pending + held for {:?}",
+                client
+            );
+        }
+    }
+});
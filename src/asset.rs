@@ -0,0 +1,167 @@
+use crate::convert::{DEFAULT_DECIMALS, MAX_DECIMALS};
+use crate::ingest::csv_reader;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Read;
+
+/// The longest asset code we take, in ASCII characters.
+pub const MAX_ASSET_LEN: usize = 8;
+
+/// What a balance or a transaction is in, e.g. `USD` or `BTC`.
+///
+/// The code is packed big endian into a `u64` (so ordering is the same as
+/// for the string) which makes it `Copy` and as cheap to store as an amount.
+/// Rows without an asset are in the default asset, which is all zeros and
+/// has an empty code, so single-asset inputs keep working as before.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct Asset(u64);
+
+impl Asset {
+    pub const DEFAULT: Asset = Asset(0);
+
+    /// Parses an asset code: up to `MAX_ASSET_LEN` ASCII letters and digits,
+    /// case insensitive. An empty (or blank) code is the default asset.
+    pub fn new(code: &str) -> Option<Self> {
+        let code = code.trim();
+        if code.len() > MAX_ASSET_LEN || !code.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return None;
+        }
+
+        let mut bytes = [0; 8];
+        for (byte, c) in bytes.iter_mut().zip(code.bytes()) {
+            *byte = c.to_ascii_uppercase();
+        }
+        Some(Asset(u64::from_be_bytes(bytes)))
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::DEFAULT
+    }
+
+    /// The packed code, for stores that keep it as a number.
+    pub fn to_bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn from_bits(bits: u64) -> Self {
+        Asset(bits)
+    }
+}
+
+/// The code as it was given, uppercased. Empty for the default asset.
+impl std::fmt::Display for Asset {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for byte in self.0.to_be_bytes() {
+            if byte == 0 {
+                break;
+            }
+            write!(f, "{}", byte as char)?;
+        }
+        Ok(())
+    }
+}
+
+/// How many decimals each asset has. Amounts of an asset are kept as
+/// integers in units of its smallest fraction (cents for a 2 decimal one),
+/// so this decides both what's a valid amount and how balances are printed.
+///
+/// Assets it doesn't know about have `DEFAULT_DECIMALS`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AssetRegistry {
+    decimals: BTreeMap<Asset, u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AssetRow {
+    asset: String,
+    decimals: u32,
+}
+
+impl AssetRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, asset: Asset, decimals: u32) -> Result<(), String> {
+        if decimals > MAX_DECIMALS {
+            return Err(format!(
+                "{} can't have {} decimals, the most is {}",
+                asset, decimals, MAX_DECIMALS
+            ));
+        }
+        self.decimals.insert(asset, decimals);
+        Ok(())
+    }
+
+    pub fn decimals(&self, asset: Asset) -> u32 {
+        self.decimals
+            .get(&asset)
+            .copied()
+            .unwrap_or(DEFAULT_DECIMALS)
+    }
+
+    /// Whether no asset was registered, as without `--assets`.
+    pub fn is_empty(&self) -> bool {
+        self.decimals.is_empty()
+    }
+
+    /// The assets that were registered, with their decimals.
+    pub fn iter(&self) -> impl Iterator<Item = (Asset, u32)> + '_ {
+        self.decimals
+            .iter()
+            .map(|(asset, decimals)| (*asset, *decimals))
+    }
+
+    /// Reads a CSV with `asset` and `decimals` columns. An empty asset
+    /// sets the decimals of the default asset.
+    pub fn from_csv<R: Read>(rdr: R) -> Result<Self, Box<dyn Error>> {
+        let mut registry = AssetRegistry::new();
+        for row in csv_reader(rdr).deserialize() {
+            let row: AssetRow = row?;
+            let asset =
+                Asset::new(&row.asset).ok_or(format!("invalid asset code '{}'", row.asset))?;
+            registry.insert(asset, row.decimals)?;
+        }
+        Ok(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_asset_codes() {
+        let usd = Asset::new("usd").unwrap();
+        assert_eq!(usd, Asset::new(" USD ").unwrap());
+        assert_eq!(usd.to_string(), "USD");
+        assert_eq!(Asset::new("").unwrap(), Asset::DEFAULT);
+        assert_eq!(Asset::DEFAULT.to_string(), "");
+        assert_eq!(Asset::new("USDC1234").unwrap().to_string(), "USDC1234");
+        assert_eq!(Asset::new("USDC12345"), None);
+        assert_eq!(Asset::new("US D"), None);
+        assert_eq!(Asset::new("€"), None);
+
+        // Sorts like the codes do
+        let mut assets = ["USDC", "BTC", "USD", ""].map(|code| Asset::new(code).unwrap());
+        assets.sort();
+        assert_eq!(
+            assets.map(|asset| asset.to_string()),
+            ["", "BTC", "USD", "USDC"]
+        );
+        assert_eq!(Asset::from_bits(usd.to_bits()), usd);
+
+        let registry =
+            AssetRegistry::from_csv("asset, decimals\nusd, 2\n, 8\n".as_bytes()).unwrap();
+        assert_eq!(registry.decimals(usd), 2);
+        assert_eq!(registry.decimals(Asset::DEFAULT), 8);
+        assert_eq!(
+            registry.decimals(Asset::new("BTC").unwrap()),
+            DEFAULT_DECIMALS
+        );
+        assert!(AssetRegistry::from_csv("asset,decimals\nETH,19\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use crate::asset::Asset;
+use crate::ids::{ClientId, TransactionId};
+use crate::store::{AccountStore, StoreError, TransactionStore};
+use crate::timestamp::parse_duration;
+use crate::trace;
+use std::collections::BTreeMap;
+
+/// How long deposited funds stay pending before they can be withdrawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClearingPeriod {
+    /// This many rows after the deposit, whatever they are.
+    Rows(u64),
+    /// This many seconds after the deposit, going by the timestamps of the
+    /// rows. Time only moves forward, a deposit without a timestamp (or with
+    /// one from before the latest) happened at the latest time seen.
+    Seconds(u64),
+}
+
+impl ClearingPeriod {
+    /// Parses a duration (`90s`, `3d`) or a number of rows (`100rows`).
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.strip_suffix("rows") {
+            Some(rows) => rows.parse().ok().map(ClearingPeriod::Rows),
+            None => parse_duration(value).map(ClearingPeriod::Seconds),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PendingFunds {
+    /// A deposit of this run, whose `ProcessedTransaction::pending` is what's
+    /// left of it to clear once disputes took their part.
+    Deposit(TransactionId),
+    /// Funds still pending when an earlier run ended, whose deposits
+    /// aren't known anymore.
+    Carried(i128),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PendingDeposit {
+    client_id: ClientId,
+    asset: Asset,
+    funds: PendingFunds,
+}
+
+/// Funds of a deposit that became available once its clearing period was over.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClearedDeposit {
+    pub client_id: ClientId,
+    /// The deposit's, or the default for the funds that were still pending
+    /// from an earlier run, since which deposit they were isn't known anymore.
+    pub transaction_id: TransactionId,
+    pub asset: Asset,
+    /// Only what was still pending, in units of the asset's smallest fraction.
+    pub amount: i128,
+}
+
+/// Deposits still waiting to clear (see `Config::clearing`), ordered by
+/// when they do.
+///
+/// Like `OpenDisputes`, this only lives for a run. Funds still pending
+/// when a run with `--state` ends are picked up again by the next one,
+/// which has them wait a whole period again since it can't tell when
+/// they were deposited.
+#[derive(Debug, Default)]
+pub struct PendingDeposits {
+    period: Option<ClearingPeriod>,
+    /// By row or time, depending on the period, and then in the order the
+    /// deposits came in.
+    by_clearing: BTreeMap<(u64, u64), PendingDeposit>,
+    tracked: u64,
+    rows: u64,
+    now: u64,
+}
+
+impl PendingDeposits {
+    /// Picks up the funds still pending in `clients`, which only has any
+    /// if the state comes from an earlier run.
+    pub fn load(
+        period: Option<ClearingPeriod>,
+        clients: &impl AccountStore,
+    ) -> Result<Self, StoreError> {
+        let mut pending = PendingDeposits {
+            period,
+            ..Self::default()
+        };
+        if pending.period.is_some() {
+            for client in clients.clients()? {
+                for (asset, balance) in client.balances() {
+                    if balance.pending > 0 {
+                        pending.start(
+                            client.client_id(),
+                            asset,
+                            PendingFunds::Carried(balance.pending),
+                        );
+                    }
+                }
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Moves the clock forward for the next row, `timestamp` being its own.
+    pub fn advance(&mut self, timestamp: Option<u64>) {
+        self.rows += 1;
+        self.now = timestamp.map_or(self.now, |timestamp| timestamp.max(self.now));
+    }
+
+    /// Starts the clock on a deposit that went into pending.
+    pub fn track(&mut self, client_id: ClientId, transaction_id: TransactionId, asset: Asset) {
+        self.start(client_id, asset, PendingFunds::Deposit(transaction_id));
+    }
+
+    fn start(&mut self, client_id: ClientId, asset: Asset, funds: PendingFunds) {
+        // The first row, or point in time, at which the funds are available.
+        let clears_at = match self.period {
+            Some(ClearingPeriod::Rows(rows)) => self.rows.saturating_add(rows).saturating_add(1),
+            Some(ClearingPeriod::Seconds(seconds)) => self.now.saturating_add(seconds),
+            None => return,
+        };
+        self.tracked += 1;
+        self.by_clearing.insert(
+            (clears_at, self.tracked),
+            PendingDeposit {
+                client_id,
+                asset,
+                funds,
+            },
+        );
+    }
+
+    /// Makes available every deposit whose clearing period is over, and
+    /// returns what did. Whatever of a deposit is held by a dispute or was
+    /// charged back doesn't clear, see `handle_dispute`.
+    pub fn clear(
+        &mut self,
+        transactions: &mut impl TransactionStore,
+        clients: &mut impl AccountStore,
+    ) -> Result<Vec<ClearedDeposit>, StoreError> {
+        let now = match self.period {
+            Some(ClearingPeriod::Rows(_)) => self.rows,
+            Some(ClearingPeriod::Seconds(_)) => self.now,
+            None => return Ok(Vec::new()),
+        };
+        let mut cleared_deposits = Vec::new();
+
+        while let Some(entry) = self.by_clearing.first_entry()
+            && entry.key().0 <= now
+        {
+            let deposit = entry.remove();
+            let Some(mut client) = clients.get(deposit.client_id)? else {
+                continue;
+            };
+            let (transaction_id, cleared) = match deposit.funds {
+                PendingFunds::Deposit(transaction_id) => {
+                    let Some(mut transaction) = transactions.get(transaction_id)? else {
+                        continue;
+                    };
+                    let cleared = client.clear(deposit.asset, transaction.pending);
+                    transaction.pending = 0;
+                    transactions.insert(transaction_id, transaction)?;
+                    (transaction_id, cleared)
+                }
+                PendingFunds::Carried(amount) => (
+                    TransactionId::default(),
+                    client.clear(deposit.asset, amount),
+                ),
+            };
+            trace!(
+                "Client {} had {} {} clear",
+                deposit.client_id, cleared, deposit.asset
+            );
+            clients.insert(deposit.client_id, client)?;
+            if cleared > 0 {
+                cleared_deposits.push(ClearedDeposit {
+                    client_id: deposit.client_id,
+                    transaction_id,
+                    asset: deposit.asset,
+                    amount: cleared,
+                });
+            }
+        }
+        Ok(cleared_deposits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Balance, ClientError};
+    use crate::config::Config;
+    use crate::convert::Decimal;
+    use crate::history::HistoryEvent;
+    use crate::output::{AccountColumns, write_accounts};
+    use crate::process::Summary;
+    use crate::process::tests::{run, run_with_history};
+    use crate::raw_transaction::RawTransactionType;
+    use crate::rejection::Rejection;
+
+    #[test]
+    fn test_deposits_clear_after_rows_or_time() {
+        let run_clearing = |period: &str, csv: &str| {
+            let config = Config {
+                clearing: ClearingPeriod::parse(period),
+                ..Config::default()
+            };
+            let (summary, _, clients) = run(csv, &config);
+            (summary, clients)
+        };
+        let insufficient = |summary: &Summary| {
+            summary
+                .rejections
+                .get(&Rejection::Client(ClientError::InsufficientFunds))
+                .copied()
+        };
+
+        // tx 1 is only available from the second row after it on, the
+        // unparseable row counting as one.
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 10.0\n\
+                   withdrawal, 1, 2, 5.0\n\
+                   deposit, 2, 3, 1.0\n\
+                   oops\n\
+                   withdrawal, 1, 4, 5.0\n";
+        let (summary, clients) = run_clearing("2rows", csv);
+        assert_eq!(insufficient(&summary), Some(1));
+        let balance = clients[&ClientId(1)].balance(Asset::DEFAULT);
+        assert_eq!((balance.available, balance.pending), (50000, 0));
+        let balance = clients[&ClientId(2)].balance(Asset::DEFAULT);
+        assert_eq!(
+            (balance.available, balance.pending, balance.total),
+            (0, 10000, 10000)
+        );
+
+        let csv = "type, client, tx, amount, timestamp\n\
+                   deposit, 1, 1, 10.0, 1000\n\
+                   withdrawal, 1, 2, 5.0, 1059\n\
+                   withdrawal, 1, 3, 5.0, 1060\n";
+        let (summary, clients) = run_clearing("1m", csv);
+        assert_eq!(insufficient(&summary), Some(1));
+        assert_eq!(
+            clients[&ClientId(1)].balance(Asset::DEFAULT).available,
+            50000
+        );
+
+        // Everything cleared, but the run had a clearing period so the
+        // output keeps its `pending` column.
+        let config = Config {
+            clearing: ClearingPeriod::parse("1m"),
+            ..Config::default()
+        };
+        let accounts = clients.clients().unwrap();
+        let mut out = Vec::new();
+        let columns = AccountColumns::new(&config, &accounts, false);
+        write_accounts(&mut out, &accounts, &config.assets, columns).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,pending,held,total,locked\n\
+             1,5.0000,0.0000,0.0000,5.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn test_disputes_hold_uncleared_funds_out_of_pending() {
+        let config = Config {
+            clearing: ClearingPeriod::parse("3rows"),
+            ..Config::default()
+        };
+
+        // Client 1's deposit is charged back before it cleared, so nothing
+        // of it was ever withdrawn and nothing of it clears later. Half of
+        // client 2's is disputed and the other half clears.
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 100.0\n\
+                   dispute, 1, 1,\n\
+                   chargeback, 1, 1,\n\
+                   deposit, 2, 2, 10.0\n\
+                   dispute, 2, 2, 4.0\n\
+                   deposit, 3, 3, 1.0\n\
+                   deposit, 3, 4, 1.0\n\
+                   deposit, 3, 5, 1.0\n";
+        let mut history = Vec::new();
+        let (summary, _, clients) = run_with_history(csv, &config, &mut history);
+
+        assert_eq!(summary.fraud_alerts, vec![]);
+        assert_eq!(
+            clients[&ClientId(1)].balance(Asset::DEFAULT),
+            Balance::default()
+        );
+        assert_eq!(
+            clients[&ClientId(2)].balance(Asset::DEFAULT),
+            Balance {
+                available: 60000,
+                pending: 0,
+                held: 40000,
+                total: 100000,
+            }
+        );
+        let cleared: Vec<(ClientId, Option<Decimal>)> = history
+            .iter()
+            .filter(|event: &&HistoryEvent| event.transaction_type == RawTransactionType::Clear)
+            .map(|event| (event.client_id, event.amount))
+            .collect();
+        assert_eq!(
+            cleared,
+            vec![(ClientId(2), Some(Decimal::from_units(60000, 4)))]
+        );
+    }
+}
@@ -0,0 +1,390 @@
+use std::fs::File;
+use std::str::FromStr;
+use tx2acc::asset::AssetRegistry;
+use tx2acc::clearing::ClearingPeriod;
+use tx2acc::config::Config;
+use tx2acc::convert::{Decimal, RawAmount, parse_amount};
+use tx2acc::fees::FeeSchedule;
+use tx2acc::generate::{GeneratorConfig, OutputFormat};
+use tx2acc::ids::{ClientId, TransactionId};
+use tx2acc::interest::InterestRates;
+use tx2acc::overdraft::{DisputeOverdraft, OverdraftLimits};
+use tx2acc::report::ReportFormat;
+use tx2acc::rules::Rules;
+use tx2acc::timestamp::{self, parse_timestamp};
+
+/// Where the processed transactions and accounts are kept during a run.
+pub enum StoreKind {
+    HashMap,
+    Compact,
+    /// On disk, in the directory given with `--state`.
+    Disk(String),
+    /// In the SQLite database given with `--state`.
+    Sqlite(String),
+}
+
+/// Files a run writes besides the accounts on stdout.
+#[derive(Default)]
+pub struct Outputs {
+    /// Disputes resolved automatically, see `--dispute-deadline`.
+    pub dispute_report: Option<String>,
+    /// Every applied event, appended to, see `history.rs`.
+    pub history: Option<String>,
+    /// Balances below zero at the end of the run, see `--overdrafts`.
+    pub overdraft_report: Option<String>,
+    /// Chargebacks of deposits that had already been withdrawn.
+    pub fraud_report: Option<String>,
+    /// What every client paid in fees, see `--fees`.
+    pub fee_report: Option<String>,
+    /// Chargebacks per reason code.
+    pub chargeback_report: Option<String>,
+    /// The end of run report, which goes to stderr if not given.
+    pub summary: Option<String>,
+    pub summary_format: ReportFormat,
+}
+
+pub enum Command {
+    /// `tx2acc <transactions.csv> [--max-amount <amount>] [--assets <file>] [--store hashmap|compact|disk|sqlite]
+    /// [--state <path>] [--dispute-window <duration>] [--dispute-deadline <duration>]
+    /// [--dispute-report <file>] [--history <file>] [--overdrafts <file>]
+    /// [--dispute-overdraft allow|limit|never] [--overdraft-report <file>] [--rules <file>]
+    /// [--fraud-report <file>] [--clearing <duration>|<n>rows] [--fees <file> --house-account <id>]
+    /// [--fee-report <file>] [--chargeback-report <file>] [--summary <file>]
+    /// [--summary-format human|json] [--quiet]`
+    Process {
+        input_file: String,
+        config: Box<Config>,
+        store: StoreKind,
+        outputs: Outputs,
+        quiet: bool,
+    },
+    /// `tx2acc as-of <when> <history> [--client <id>] [--disputes <file>] [--assets <file>]
+    /// [--clearing <duration>|<n>rows]`
+    AsOf {
+        at: u64,
+        history_file: String,
+        /// The run's, as far as replaying the history goes: its assets and clearing.
+        config: Config,
+        client: Option<ClientId>,
+        disputes: Option<String>,
+    },
+    /// `tx2acc accrue <when> <history> --rates <file> [--days N] [--first-tx <id>]
+    /// [--assets <file>] [--clearing <duration>|<n>rows] [--output <file>]`
+    Accrue {
+        at: u64,
+        history_file: String,
+        rates: InterestRates,
+        days: u64,
+        first_tx: Option<TransactionId>,
+        /// Same as for `AsOf`.
+        config: Config,
+        output: Option<String>,
+    },
+    /// `tx2acc generate [--rows N] [--clients N] [--seed N] [--format csv|jsonl]
+    /// [--withdrawal-rate R] [--dispute-rate R] [--resolve-rate R] [--chargeback-rate R]
+    /// [--duplicate-rate R] [--malformed-rate R] [--output <file>] [--expected <file>]`
+    Generate {
+        config: GeneratorConfig,
+        output: Option<String>,
+        expected: Option<String>,
+    },
+}
+
+/// Parses everything after the binary name.
+///
+/// Positional arguments and flags can come in any order.
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    match args.first().map(String::as_str) {
+        Some("generate") => parse_generate(&args[1..]),
+        Some("as-of") => parse_as_of(&args[1..]),
+        Some("accrue") => parse_accrue(&args[1..]),
+        _ => parse_process(args),
+    }
+}
+
+fn parse_process(args: &[String]) -> Result<Command, String> {
+    let mut input_file = None;
+    let mut config = Config::default();
+    let mut store = "compact";
+    let mut state = None;
+    let mut outputs = Outputs::default();
+    let mut quiet = false;
+    let mut overdrafts = None;
+    let mut rules = None;
+    let mut fees = None;
+    let mut house = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--max-amount" => {
+                let value = next_value(&mut args, arg)?;
+                let amount = match parse_amount(value) {
+                    Some(RawAmount::Decimal(amount)) if amount > Decimal::from_units(0, 0) => {
+                        amount
+                    }
+                    _ => return Err(format!("Invalid --max-amount '{}'", value)),
+                };
+                config.max_amount = Some(amount);
+            }
+            "--assets" => config.assets = parse_assets(next_value(&mut args, arg)?)?,
+            "--store" => {
+                let value = next_value(&mut args, arg)?;
+                store = match value.as_str() {
+                    "hashmap" | "compact" | "disk" | "sqlite" => value.as_str(),
+                    _ => return Err(format!("Invalid --store '{}'", value)),
+                }
+            }
+            "--state" => state = Some(next_value(&mut args, arg)?.clone()),
+            "--dispute-window" => {
+                config.dispute_window = Some(parse_duration(arg, next_value(&mut args, arg)?)?)
+            }
+            "--dispute-deadline" => {
+                config.dispute_deadline = Some(parse_duration(arg, next_value(&mut args, arg)?)?)
+            }
+            "--dispute-report" => {
+                outputs.dispute_report = Some(next_value(&mut args, arg)?.clone())
+            }
+            "--history" => outputs.history = Some(next_value(&mut args, arg)?.clone()),
+            "--overdrafts" => overdrafts = Some(next_value(&mut args, arg)?.clone()),
+            "--rules" => rules = Some(next_value(&mut args, arg)?.clone()),
+            "--dispute-overdraft" => {
+                let value = next_value(&mut args, arg)?;
+                config.dispute_overdraft = match value.as_str() {
+                    "allow" => DisputeOverdraft::Allow,
+                    "limit" => DisputeOverdraft::WithinLimit,
+                    "never" => DisputeOverdraft::Never,
+                    _ => return Err(format!("Invalid --dispute-overdraft '{}'", value)),
+                }
+            }
+            "--overdraft-report" => {
+                outputs.overdraft_report = Some(next_value(&mut args, arg)?.clone())
+            }
+            "--fraud-report" => outputs.fraud_report = Some(next_value(&mut args, arg)?.clone()),
+            "--clearing" => config.clearing = Some(parse_clearing(next_value(&mut args, arg)?)?),
+            "--fees" => fees = Some(next_value(&mut args, arg)?.clone()),
+            "--house-account" => {
+                house = Some(ClientId(parse_value(arg, next_value(&mut args, arg)?)?))
+            }
+            "--fee-report" => outputs.fee_report = Some(next_value(&mut args, arg)?.clone()),
+            "--chargeback-report" => {
+                outputs.chargeback_report = Some(next_value(&mut args, arg)?.clone())
+            }
+            "--summary" => outputs.summary = Some(next_value(&mut args, arg)?.clone()),
+            "--summary-format" => {
+                let value = next_value(&mut args, arg)?;
+                outputs.summary_format = match value.as_str() {
+                    "human" => ReportFormat::Human,
+                    "json" => ReportFormat::Json,
+                    _ => return Err(format!("Invalid --summary-format '{}'", value)),
+                }
+            }
+            "--quiet" => quiet = true,
+            flag if flag.starts_with("--") => return Err(format!("Unknown flag '{}'", flag)),
+            _ => input_file = Some(arg.clone()),
+        }
+    }
+
+    // Limits, rules and fees are in whole units, so they need the decimals of every asset first.
+    if let Some(path) = overdrafts {
+        config.overdrafts = File::open(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                OverdraftLimits::from_csv(file, &config.assets).map_err(|e| e.to_string())
+            })
+            .map_err(|e| format!("Invalid --overdrafts '{}': {}", path, e))?;
+    }
+    if let Some(path) = rules {
+        config.rules = File::open(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| Rules::from_csv(file, &config.assets).map_err(|e| e.to_string()))
+            .map_err(|e| format!("Invalid --rules '{}': {}", path, e))?;
+    }
+    match (fees, house) {
+        (Some(path), Some(house)) => {
+            config.fees = File::open(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|file| {
+                    FeeSchedule::from_csv(file, &config.assets, house).map_err(|e| e.to_string())
+                })
+                .map_err(|e| format!("Invalid --fees '{}': {}", path, e))?;
+        }
+        (Some(_), None) => return Err("--fees needs --house-account <id>".to_string()),
+        (None, Some(_)) => return Err("--house-account only works with --fees".to_string()),
+        (None, None) => {}
+    }
+
+    let store = match (store, state) {
+        ("disk", Some(state)) => StoreKind::Disk(state),
+        ("sqlite", Some(state)) => StoreKind::Sqlite(state),
+        ("disk" | "sqlite", None) => return Err(format!("--store {} needs --state <path>", store)),
+        (_, Some(_)) => return Err("--state only works with --store disk or sqlite".to_string()),
+        ("hashmap", None) => StoreKind::HashMap,
+        _ => StoreKind::Compact,
+    };
+
+    Ok(Command::Process {
+        input_file: input_file.unwrap_or("unknown".to_string()),
+        config: Box::new(config),
+        store,
+        outputs,
+        quiet,
+    })
+}
+
+fn parse_generate(args: &[String]) -> Result<Command, String> {
+    let mut config = GeneratorConfig::default();
+    let mut output = None;
+    let mut expected = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = next_value(&mut args, arg)?;
+        match arg.as_str() {
+            "--rows" => config.rows = parse_value(arg, value)?,
+            "--clients" => config.clients = parse_value(arg, value)?,
+            "--seed" => config.seed = parse_value(arg, value)?,
+            "--format" => {
+                config.format = match value.as_str() {
+                    "csv" => OutputFormat::Csv,
+                    "jsonl" => OutputFormat::Jsonl,
+                    _ => return Err(format!("Invalid --format '{}'", value)),
+                }
+            }
+            "--withdrawal-rate" => config.withdrawal_rate = parse_rate(arg, value)?,
+            "--dispute-rate" => config.dispute_rate = parse_rate(arg, value)?,
+            "--resolve-rate" => config.resolve_rate = parse_rate(arg, value)?,
+            "--chargeback-rate" => config.chargeback_rate = parse_rate(arg, value)?,
+            "--duplicate-rate" => config.duplicate_rate = parse_rate(arg, value)?,
+            "--malformed-rate" => config.malformed_rate = parse_rate(arg, value)?,
+            "--output" => output = Some(value.clone()),
+            "--expected" => expected = Some(value.clone()),
+            _ => return Err(format!("Unknown flag '{}'", arg)),
+        }
+    }
+
+    Ok(Command::Generate {
+        config,
+        output,
+        expected,
+    })
+}
+
+fn parse_as_of(args: &[String]) -> Result<Command, String> {
+    let mut positional = Vec::new();
+    let mut client = None;
+    let mut disputes = None;
+    let mut config = Config::default();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--client" => client = Some(ClientId(parse_value(arg, next_value(&mut args, arg)?)?)),
+            "--disputes" => disputes = Some(next_value(&mut args, arg)?.clone()),
+            "--assets" => config.assets = parse_assets(next_value(&mut args, arg)?)?,
+            "--clearing" => config.clearing = Some(parse_clearing(next_value(&mut args, arg)?)?),
+            flag if flag.starts_with("--") => return Err(format!("Unknown flag '{}'", flag)),
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    let [when, history_file] = <[String; 2]>::try_from(positional)
+        .map_err(|_| "as-of needs a point in time and a history file".to_string())?;
+    let at = parse_timestamp(&when).ok_or(format!("Invalid point in time '{}'", when))?;
+
+    Ok(Command::AsOf {
+        at,
+        history_file,
+        config,
+        client,
+        disputes,
+    })
+}
+
+fn parse_accrue(args: &[String]) -> Result<Command, String> {
+    let mut positional = Vec::new();
+    let mut rates = None;
+    let mut days = 1;
+    let mut first_tx = None;
+    let mut config = Config::default();
+    let mut output = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rates" => {
+                let path = next_value(&mut args, arg)?;
+                rates = Some(
+                    File::open(path)
+                        .map_err(|e| e.to_string())
+                        .and_then(|file| InterestRates::from_csv(file).map_err(|e| e.to_string()))
+                        .map_err(|e| format!("Invalid --rates '{}': {}", path, e))?,
+                );
+            }
+            "--days" => days = parse_value(arg, next_value(&mut args, arg)?)?,
+            "--first-tx" => {
+                first_tx = Some(TransactionId(parse_value(
+                    arg,
+                    next_value(&mut args, arg)?,
+                )?))
+            }
+            "--assets" => config.assets = parse_assets(next_value(&mut args, arg)?)?,
+            "--clearing" => config.clearing = Some(parse_clearing(next_value(&mut args, arg)?)?),
+            "--output" => output = Some(next_value(&mut args, arg)?.clone()),
+            flag if flag.starts_with("--") => return Err(format!("Unknown flag '{}'", flag)),
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    let [when, history_file] = <[String; 2]>::try_from(positional)
+        .map_err(|_| "accrue needs a point in time and a history file".to_string())?;
+    let at = parse_timestamp(&when).ok_or(format!("Invalid point in time '{}'", when))?;
+
+    Ok(Command::Accrue {
+        at,
+        history_file,
+        rates: rates.ok_or("accrue needs --rates <file>".to_string())?,
+        days,
+        first_tx,
+        config,
+        output,
+    })
+}
+
+fn next_value<'a>(
+    args: &mut impl Iterator<Item = &'a String>,
+    flag: &str,
+) -> Result<&'a String, String> {
+    args.next().ok_or(format!("{} needs a value", flag))
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid {} '{}'", flag, value))
+}
+
+/// Reads the `asset,decimals` CSV given with `--assets`.
+fn parse_assets(path: &str) -> Result<AssetRegistry, String> {
+    File::open(path)
+        .map_err(|e| e.to_string())
+        .and_then(|file| AssetRegistry::from_csv(file).map_err(|e| e.to_string()))
+        .map_err(|e| format!("Invalid --assets '{}': {}", path, e))
+}
+
+fn parse_clearing(value: &str) -> Result<ClearingPeriod, String> {
+    ClearingPeriod::parse(value).ok_or(format!("Invalid --clearing '{}'", value))
+}
+
+fn parse_rate(flag: &str, value: &str) -> Result<f64, String> {
+    let rate: f64 = parse_value(flag, value)?;
+    if (0.0..=1.0).contains(&rate) {
+        Ok(rate)
+    } else {
+        Err(format!("{} must be between 0 and 1, got '{}'", flag, value))
+    }
+}
+
+fn parse_duration(flag: &str, value: &str) -> Result<u64, String> {
+    timestamp::parse_duration(value).ok_or(format!("Invalid {} '{}'", flag, value))
+}
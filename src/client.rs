@@ -1,127 +1,396 @@
-#[derive(Debug, Clone)]
+use crate::asset::Asset;
+use crate::ids::{ClientId, TransactionId};
+use crate::lifecycle::AccountState;
+use crate::trace;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// How much of one asset a client has.
+///
+/// `total` is all three of the others: what can be withdrawn, what's held
+/// by disputes and deposits that haven't cleared yet (see `ClearingPeriod`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Balance {
+    pub available: i128,
+    pub held: i128,
+    pub total: i128,
+    pub pending: i128,
+}
+
+/// A client and their balances, one per asset they've ever had a
+/// transaction in. The state is for the whole account, not per asset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Client {
-    client_id: u16,
-    pub available: i64,
-    pub held: i64,
-    pub total: i64,
-    pub locked: bool,
+    client_id: ClientId,
+    pub balances: BTreeMap<Asset, Balance>,
+    pub state: AccountState,
+    /// Why the account got into its state, if anyone said.
+    pub reason: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum ClientError {
-    Locked,
+    /// The account's state doesn't allow it.
+    NotAllowed(AccountState),
+    InvalidTransition(AccountState, AccountState),
+    NotEmpty,
     InsufficientFunds,
+    Overflow,
 }
 
 impl std::fmt::Display for ClientError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            ClientError::Locked => write!(f, "Account is locked"),
+            ClientError::NotAllowed(state) => write!(f, "Account is {}", state),
+            ClientError::InvalidTransition(from, to) => {
+                write!(f, "Account can't go from {} to {}", from, to)
+            }
+            ClientError::NotEmpty => write!(f, "Account still has funds"),
             ClientError::InsufficientFunds => write!(f, "Insufficient funds available"),
+            ClientError::Overflow => write!(f, "Balance would overflow"),
         }
     }
 }
 
 impl Client {
-    pub fn new(client_id: u16) -> Self {
+    pub fn new(client_id: ClientId) -> Self {
         Client {
             client_id,
-            available: 0,
-            held: 0,
-            total: 0,
-            locked: false,
+            balances: BTreeMap::new(),
+            state: AccountState::Active,
+            reason: None,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.state == AccountState::Locked
+    }
+
+    /// Locks the account, for a chargeback of `transaction_id`.
+    pub fn lock(&mut self, transaction_id: TransactionId) {
+        self.state = AccountState::Locked;
+        self.reason = Some(format!("chargeback of tx {}", transaction_id));
+    }
+
+    /// Fails with the account's state unless it `allowed` what the client
+    /// is trying to do.
+    fn check(&self, allowed: bool, what: &str) -> Result<(), ClientError> {
+        if !allowed {
+            trace!(
+                "Client {} is {} and cannot {}",
+                self.client_id, self.state, what
+            );
+            return Err(ClientError::NotAllowed(self.state));
+        }
+        Ok(())
+    }
+
+    /// Moves the account to another state, for `reason`. Only the moves
+    /// `AccountState::can_become` allows are, and an account can only be
+    /// closed once it has nothing left in any asset.
+    pub fn transition(
+        &mut self,
+        to: AccountState,
+        reason: Option<String>,
+    ) -> Result<(), ClientError> {
+        if !self.state.can_become(to) {
+            return Err(ClientError::InvalidTransition(self.state, to));
+        }
+        if to == AccountState::Closed
+            && self
+                .balances
+                .values()
+                .any(|balance| *balance != Balance::default())
+        {
+            return Err(ClientError::NotEmpty);
         }
+        trace!(
+            "Client {} went from {} to {}",
+            self.client_id, self.state, to
+        );
+        self.state = to;
+        self.reason = reason;
+        Ok(())
+    }
+
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    /// The balance in `asset`, all zeros if the client never had any.
+    pub fn balance(&self, asset: Asset) -> Balance {
+        self.balances.get(&asset).copied().unwrap_or_default()
     }
 
-    pub fn deposit(&mut self, amount: i64) -> Result<bool, ClientError> {
-        if self.locked {
-            eprintln!("Client {} is locked and cannot deposit", self.client_id);
-            return Err(ClientError::Locked);
+    /// Every balance the client has, or a zero one in the default asset for
+    /// a client that has none, so that every client gets at least one row.
+    pub fn balances(&self) -> Vec<(Asset, Balance)> {
+        if self.balances.is_empty() {
+            return vec![(Asset::DEFAULT, Balance::default())];
         }
+        self.balances
+            .iter()
+            .map(|(asset, balance)| (*asset, *balance))
+            .collect()
+    }
+
+    /// Adds `amount` to the available funds, or to the pending ones if
+    /// it has to clear first.
+    pub fn deposit(
+        &mut self,
+        asset: Asset,
+        amount: i128,
+        pending: bool,
+    ) -> Result<bool, ClientError> {
+        self.check(self.state.allows_deposits(), "deposit")?;
 
-        self.available += amount;
-        self.total += amount;
-        eprintln!(
-            "Client {} deposited {} in tx and now has these balances: available={}, held={}, total={}",
-            self.client_id, amount, self.available, self.held, self.total
+        let mut balance = self.balance(asset);
+        let bucket = if pending {
+            &mut balance.pending
+        } else {
+            &mut balance.available
+        };
+        let (Some(added), Some(total)) = (
+            bucket.checked_add(amount),
+            balance.total.checked_add(amount),
+        ) else {
+            return Err(ClientError::Overflow);
+        };
+        *bucket = added;
+        balance.total = total;
+        self.balances.insert(asset, balance);
+        trace!(
+            "Client {} deposited {} {} and now has these balances: available={}, pending={}, held={}, total={}",
+            self.client_id,
+            amount,
+            asset,
+            balance.available,
+            balance.pending,
+            balance.held,
+            balance.total
         );
         Ok(true)
     }
 
-    pub fn withdraw(&mut self, amount: i64) -> Result<bool, ClientError> {
-        if self.locked {
-            eprintln!("Client {} is locked and cannot withdraw", self.client_id);
-            return Err(ClientError::Locked);
+    /// Moves up to `amount` from pending to available, and returns how much.
+    /// Accounts clear whatever their state, it's not something the client does.
+    pub fn clear(&mut self, asset: Asset, amount: i128) -> i128 {
+        let mut balance = self.balance(asset);
+        let cleared = amount.min(balance.pending).max(0);
+        if cleared == 0 {
+            return 0;
         }
+        // Can't overflow, total is at least available plus pending.
+        balance.pending -= cleared;
+        balance.available += cleared;
+        self.balances.insert(asset, balance);
+        cleared
+    }
+
+    /// Takes `amount` out of the available funds, which may end up as far
+    /// as `overdraft` below zero (see `OverdraftLimits`).
+    pub fn withdraw(
+        &mut self,
+        asset: Asset,
+        amount: i128,
+        overdraft: i128,
+    ) -> Result<bool, ClientError> {
+        self.check(self.state.allows_withdrawals(), "withdraw")?;
 
-        if self.available >= amount {
-            self.available -= amount;
-            self.total -= amount;
-            eprintln!(
-                "Client {} withdrew {} and now has these balances: available={}, held={}, total={}",
-                self.client_id, amount, self.available, self.held, self.total
+        let mut balance = self.balance(asset);
+        let available = balance.available.checked_sub(amount);
+        let total = balance.total.checked_sub(amount);
+        let (Some(available), Some(total)) = (available, total) else {
+            return Err(ClientError::Overflow);
+        };
+        if available >= -overdraft {
+            balance.available = available;
+            balance.total = total;
+            self.balances.insert(asset, balance);
+            trace!(
+                "Client {} withdrew {} {} and now has these balances: available={}, held={}, total={}",
+                self.client_id, amount, asset, balance.available, balance.held, balance.total
             );
         } else {
             // Not yet sure yet how I should deal with this aside from
             // not changing the balance.
-            eprintln!("User is trying to withdraw more than they have.");
+            trace!("User is trying to withdraw more than they have.");
             return Err(ClientError::InsufficientFunds);
         }
         Ok(true)
     }
 
-    pub fn apply_dispute(&mut self, amount: i64) -> Result<bool, ClientError> {
-        if self.locked {
-            eprintln!(
-                "Client {} is locked and cannot apply dispute",
-                self.client_id
-            );
-            return Err(ClientError::Locked);
+    /// Takes a fee of `amount` out of the funds, `from_pending` of it out of
+    /// the pending ones and the rest out of available, which may end up as
+    /// far as `overdraft` below zero. Accounts pay fees whatever their state,
+    /// a chargeback fee comes right after the account got locked.
+    pub fn pay_fee(
+        &mut self,
+        asset: Asset,
+        amount: i128,
+        from_pending: i128,
+        overdraft: i128,
+    ) -> Result<bool, ClientError> {
+        let mut balance = self.balance(asset);
+        let pending = balance.pending.checked_sub(from_pending);
+        let available = balance.available.checked_sub(amount - from_pending);
+        let total = balance.total.checked_sub(amount);
+        let (Some(pending), Some(available), Some(total)) = (pending, available, total) else {
+            return Err(ClientError::Overflow);
+        };
+        if available < -overdraft {
+            trace!("Client {} can't pay a fee of {}", self.client_id, amount);
+            return Err(ClientError::InsufficientFunds);
         }
-
-        self.available -= amount;
-        self.held += amount;
-        eprintln!(
-            "Client {} applied dispute for {} and now has these balances: available={}, held={}, total={}",
-            self.client_id, amount, self.available, self.held, self.total
+        balance.pending = pending;
+        balance.available = available;
+        balance.total = total;
+        self.balances.insert(asset, balance);
+        trace!(
+            "Client {} paid a fee of {} {} and now has these balances: available={}, held={}, total={}",
+            self.client_id, amount, asset, balance.available, balance.held, balance.total
         );
         Ok(true)
     }
 
-    pub fn apply_resolve(&mut self, amount: i64) -> Result<bool, ClientError> {
-        if self.locked {
-            eprintln!(
-                "Client {} is locked and cannot apply resolve",
+    /// Adds a fee of `amount` someone else paid to the available funds,
+    /// whatever the account's state.
+    pub fn collect_fee(&mut self, asset: Asset, amount: i128) -> Result<bool, ClientError> {
+        self.credit(asset, amount)
+    }
+
+    /// Gives the sender of a transfer back the `amount` a chargeback took
+    /// from its recipient, whatever the account's state: the dispute was
+    /// already open.
+    pub fn recover(&mut self, asset: Asset, amount: i128) -> Result<bool, ClientError> {
+        self.credit(asset, amount)
+    }
+
+    fn credit(&mut self, asset: Asset, amount: i128) -> Result<bool, ClientError> {
+        let mut balance = self.balance(asset);
+        let available = balance.available.checked_add(amount);
+        let total = balance.total.checked_add(amount);
+        let (Some(available), Some(total)) = (available, total) else {
+            return Err(ClientError::Overflow);
+        };
+        balance.available = available;
+        balance.total = total;
+        self.balances.insert(asset, balance);
+        Ok(true)
+    }
+
+    /// Adds `amount` to the available funds, or takes it out if it's
+    /// negative, at most `overdraft` below zero. That's a refund or a reversal,
+    /// so it's up to the state whether effects go through, not deposits or
+    /// withdrawals.
+    pub fn adjust(
+        &mut self,
+        asset: Asset,
+        amount: i128,
+        overdraft: i128,
+    ) -> Result<bool, ClientError> {
+        self.check(self.state.allows_effects(), "be refunded")?;
+
+        let mut balance = self.balance(asset);
+        let available = balance.available.checked_add(amount);
+        let total = balance.total.checked_add(amount);
+        let (Some(available), Some(total)) = (available, total) else {
+            return Err(ClientError::Overflow);
+        };
+        if amount < 0 && available < -overdraft {
+            trace!(
+                "Refund would take client {} too far below zero",
                 self.client_id
             );
-            return Err(ClientError::Locked);
+            return Err(ClientError::InsufficientFunds);
         }
-
-        self.available += amount;
-        self.held -= amount;
-        eprintln!(
-            "Client {} resolved dispute for {} and now has these balances: available={}, held={}, total={}",
-            self.client_id, amount, self.available, self.held, self.total
+        balance.available = available;
+        balance.total = total;
+        self.balances.insert(asset, balance);
+        trace!(
+            "Client {} had {} {} refunded and now has these balances: available={}, held={}, total={}",
+            self.client_id, amount, asset, balance.available, balance.held, balance.total
         );
         Ok(true)
     }
 
-    pub fn apply_chargeback(&mut self, amount: i64) -> Result<bool, ClientError> {
-        if self.locked {
-            eprintln!(
-                "Client {} is locked and cannot apply chargeback",
+    /// Moves `amount` to held, `from_pending` of it out of the pending funds
+    /// and the rest out of available. With an `overdraft`, available may end
+    /// up at most that far below zero (see `DisputeOverdraft`).
+    pub fn apply_dispute(
+        &mut self,
+        asset: Asset,
+        amount: i128,
+        from_pending: i128,
+        overdraft: Option<i128>,
+    ) -> Result<bool, ClientError> {
+        self.check(self.state.allows_effects(), "apply dispute")?;
+
+        let mut balance = self.balance(asset);
+        let pending = balance.pending.checked_sub(from_pending);
+        let available = balance.available.checked_sub(amount - from_pending);
+        let held = balance.held.checked_add(amount);
+        let (Some(pending), Some(available), Some(held)) = (pending, available, held) else {
+            return Err(ClientError::Overflow);
+        };
+        if overdraft.is_some_and(|overdraft| available < -overdraft) {
+            trace!(
+                "Dispute would take client {} too far below zero",
                 self.client_id
             );
-            return Err(ClientError::Locked);
+            return Err(ClientError::InsufficientFunds);
         }
+        balance.pending = pending;
+        balance.available = available;
+        balance.held = held;
+        self.balances.insert(asset, balance);
+        trace!(
+            "Client {} applied dispute for {} and now has these balances: available={}, held={}, total={}",
+            self.client_id, amount, balance.available, balance.held, balance.total
+        );
+        Ok(true)
+    }
 
-        self.held -= amount;
-        self.total -= amount;
-        self.locked = true;
-        eprintln!(
-            "Client {} had chargeback for {} and now has these balances: available={}, held={}, total={}, locked={}",
-            self.client_id, amount, self.available, self.held, self.total, self.locked
+    /// Moves `amount` from held back to available, whatever the account's
+    /// state: the dispute was already open.
+    pub fn apply_resolve(&mut self, asset: Asset, amount: i128) -> Result<bool, ClientError> {
+        let mut balance = self.balance(asset);
+        let available = balance.available.checked_add(amount);
+        let held = balance.held.checked_sub(amount);
+        let (Some(available), Some(held)) = (available, held) else {
+            return Err(ClientError::Overflow);
+        };
+        balance.available = available;
+        balance.held = held;
+        self.balances.insert(asset, balance);
+        trace!(
+            "Client {} resolved dispute for {} and now has these balances: available={}, held={}, total={}",
+            self.client_id, amount, balance.available, balance.held, balance.total
         );
         Ok(true)
     }
+
+    /// Takes the held `amount` away for good, whatever the account's state:
+    /// the dispute was already open. Locking the account of whoever charged
+    /// it back is up to the caller, see `lock`.
+    ///
+    /// Returns how much of it the client had already spent, which is
+    /// however far below zero the dispute left their available funds
+    /// (see `FraudAlert`).
+    pub fn apply_chargeback(&mut self, asset: Asset, amount: i128) -> Result<i128, ClientError> {
+        let mut balance = self.balance(asset);
+        let held = balance.held.checked_sub(amount);
+        let total = balance.total.checked_sub(amount);
+        let (Some(held), Some(total)) = (held, total) else {
+            return Err(ClientError::Overflow);
+        };
+        balance.held = held;
+        balance.total = total;
+        self.balances.insert(asset, balance);
+        trace!(
+            "Client {} had chargeback for {} and now has these balances: available={}, held={}, total={}",
+            self.client_id, amount, balance.available, balance.held, balance.total
+        );
+        Ok(balance.available.saturating_neg().clamp(0, amount))
+    }
 }
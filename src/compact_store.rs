@@ -0,0 +1,478 @@
+use crate::asset::Asset;
+use crate::ids::{ClientId, TransactionId};
+use crate::processed_transaction::{DisputeStatus, ProcessedTransaction, ProcessedTransactionType};
+use crate::store::{StoreError, TransactionStore};
+use std::collections::HashMap;
+
+const PRESENT: u8 = 1;
+const WITHDRAWAL: u8 = 1 << 1;
+const STATUS_SHIFT: u8 = 2;
+const STATUS_MASK: u8 = 0b11 << STATUS_SHIFT;
+const HAS_TIMESTAMP: u8 = 1 << 4;
+const HAS_DISPUTED_AT: u8 = 1 << 5;
+const HAS_ASSET: u8 = 1 << 6;
+const WIDE_AMOUNT: u8 = 1 << 7;
+
+/// In `client_ids`, means the client ID didn't fit and is in `wide_client_ids`.
+const WIDE_CLIENT_ID: u16 = u16::MAX;
+
+/// Below this, ids always go in the dense vectors.
+const MIN_DENSE_IDS: usize = 1024;
+/// An id is only stored densely if at most this many slots per stored
+/// transaction would end up empty, otherwise it goes in the sparse map.
+const MAX_SPARSITY: usize = 4;
+
+/// A `ProcessedTransaction` squeezed into an amount, a client ID, one
+/// byte holding the type, the dispute status, whether the slot is used and
+/// which of the optional fields are set, and those fields themselves.
+#[derive(Debug, Clone, Copy)]
+struct PackedTransaction {
+    amount: i128,
+    client_id: ClientId,
+    flags: u8,
+    timestamp: u64,
+    disputed_at: u64,
+    asset: u64,
+}
+
+impl PackedTransaction {
+    fn pack(transaction: &ProcessedTransaction) -> Self {
+        let kind = match transaction.transaction_type {
+            // The recipient of a transfer is kept on the side.
+            ProcessedTransactionType::Deposit | ProcessedTransactionType::Transfer { .. } => 0,
+            ProcessedTransactionType::Withdrawal => WITHDRAWAL,
+        };
+        let status = match transaction.dispute_status {
+            DisputeStatus::Valid => 0,
+            DisputeStatus::Disputed => 1,
+            DisputeStatus::Resolved => 2,
+            DisputeStatus::ChargedBack => 3,
+        };
+
+        let mut flags = PRESENT | kind | (status << STATUS_SHIFT);
+        if transaction.timestamp.is_some() {
+            flags |= HAS_TIMESTAMP;
+        }
+        if transaction.disputed_at.is_some() {
+            flags |= HAS_DISPUTED_AT;
+        }
+        if !transaction.asset.is_default() {
+            flags |= HAS_ASSET;
+        }
+
+        PackedTransaction {
+            amount: transaction.amount,
+            client_id: transaction.client_id,
+            flags,
+            timestamp: transaction.timestamp.unwrap_or(0),
+            disputed_at: transaction.disputed_at.unwrap_or(0),
+            asset: transaction.asset.to_bits(),
+        }
+    }
+
+    fn unpack(self) -> ProcessedTransaction {
+        let transaction_type = if self.flags & WITHDRAWAL != 0 {
+            ProcessedTransactionType::Withdrawal
+        } else {
+            ProcessedTransactionType::Deposit
+        };
+        let dispute_status = match (self.flags & STATUS_MASK) >> STATUS_SHIFT {
+            0 => DisputeStatus::Valid,
+            1 => DisputeStatus::Disputed,
+            2 => DisputeStatus::Resolved,
+            _ => DisputeStatus::ChargedBack,
+        };
+
+        ProcessedTransaction {
+            transaction_type,
+            dispute_status,
+            client_id: self.client_id,
+            asset: Asset::from_bits(self.asset),
+            amount: self.amount,
+            disputed: whole_dispute(dispute_status, self.amount),
+            refunded: 0,
+            charged_back: whole_chargeback(dispute_status, self.amount),
+            pending: 0,
+            timestamp: (self.flags & HAS_TIMESTAMP != 0).then_some(self.timestamp),
+            disputed_at: (self.flags & HAS_DISPUTED_AT != 0).then_some(self.disputed_at),
+            reason_code: None,
+        }
+    }
+}
+
+/// How much of a transaction is under dispute, unless only part of it is.
+fn whole_dispute(dispute_status: DisputeStatus, amount: i128) -> i128 {
+    match dispute_status {
+        DisputeStatus::Disputed => amount,
+        _ => 0,
+    }
+}
+
+/// How much of a transaction was charged back, unless only part of it was.
+fn whole_chargeback(dispute_status: DisputeStatus, amount: i128) -> i128 {
+    match dispute_status {
+        DisputeStatus::ChargedBack => amount,
+        _ => 0,
+    }
+}
+
+/// A `TransactionStore` built for lots of transactions with mostly
+/// sequential ids, which is what a real feed looks like.
+///
+/// Transactions are kept in parallel vectors indexed by transaction ID.
+/// Every one has an amount, a client ID and a flags byte, 11 bytes and no
+/// hashing against roughly 40 for a `HashMap` entry. Timestamps, dispute
+/// times and assets get a vector each, which stays empty until the first
+/// transaction that has one and then costs 8 bytes a slot, so a transaction
+/// with all three takes 35. Ids that would leave the vectors mostly empty
+/// (e.g. a stray `4000000000`) go to a small sparse map instead, at about
+/// the cost of a `HashMap` entry.
+///
+/// Amounts are `i128`s and client IDs `u64`s, but hardly ever need more
+/// than 64 and 16 bits, so the few that do are kept in maps on the side.
+/// So is whatever a flags byte can't say: the disputed and charged back
+/// amounts of transactions that only had part of them disputed or charged
+/// back, refunded amounts, the part of deposits that hasn't cleared yet, the
+/// recipients of transfers and reason codes.
+///
+/// Every transaction is kept, not only the deposits that can still be
+/// disputed: withdrawals can be disputed too, duplicates are caught by ID
+/// and refunds and reversals refer to settled transactions, so dropping
+/// any of them would change what gets rejected.
+#[derive(Debug, Default)]
+pub struct CompactTransactionStore {
+    amounts: Vec<i64>,
+    wide_amounts: HashMap<usize, i128>,
+    client_ids: Vec<u16>,
+    wide_client_ids: HashMap<usize, ClientId>,
+    flags: Vec<u8>,
+    timestamps: Vec<u64>,
+    disputed_ats: Vec<u64>,
+    assets: Vec<u64>,
+    sparse: HashMap<TransactionId, PackedTransaction>,
+    partly_disputed: HashMap<TransactionId, i128>,
+    partly_charged_back: HashMap<TransactionId, i128>,
+    refunded: HashMap<TransactionId, i128>,
+    pending: HashMap<TransactionId, i128>,
+    transfers: HashMap<TransactionId, ClientId>,
+    reason_codes: HashMap<TransactionId, String>,
+    len: usize,
+}
+
+impl CompactTransactionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn dense(&self, index: usize) -> Option<PackedTransaction> {
+        let flags = *self.flags.get(index)?;
+        if flags & PRESENT == 0 {
+            return None;
+        }
+        Some(PackedTransaction {
+            amount: if flags & WIDE_AMOUNT != 0 {
+                self.wide_amounts[&index]
+            } else {
+                i128::from(self.amounts[index])
+            },
+            client_id: match self.client_ids[index] {
+                WIDE_CLIENT_ID => self.wide_client_ids[&index],
+                client_id => ClientId(u64::from(client_id)),
+            },
+            flags,
+            timestamp: if flags & HAS_TIMESTAMP != 0 {
+                self.timestamps[index]
+            } else {
+                0
+            },
+            disputed_at: if flags & HAS_DISPUTED_AT != 0 {
+                self.disputed_ats[index]
+            } else {
+                0
+            },
+            asset: if flags & HAS_ASSET != 0 {
+                self.assets[index]
+            } else {
+                0
+            },
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn unpack(
+        &self,
+        transaction_id: TransactionId,
+        packed: PackedTransaction,
+    ) -> ProcessedTransaction {
+        let mut transaction = packed.unpack();
+        if let Some(disputed) = self.partly_disputed.get(&transaction_id) {
+            transaction.disputed = *disputed;
+        }
+        if let Some(charged_back) = self.partly_charged_back.get(&transaction_id) {
+            transaction.charged_back = *charged_back;
+        }
+        if let Some(refunded) = self.refunded.get(&transaction_id) {
+            transaction.refunded = *refunded;
+        }
+        if let Some(pending) = self.pending.get(&transaction_id) {
+            transaction.pending = *pending;
+        }
+        if let Some(to) = self.transfers.get(&transaction_id) {
+            transaction.transaction_type = ProcessedTransactionType::Transfer { to: *to };
+        }
+        transaction.reason_code = self.reason_codes.get(&transaction_id).cloned();
+        transaction
+    }
+
+    fn fits_dense(&self, index: usize) -> bool {
+        index < self.flags.len() || index < MIN_DENSE_IDS.max((self.len + 1) * MAX_SPARSITY)
+    }
+}
+
+/// Where a transaction ID would go in the dense vectors. IDs past what
+/// a `usize` holds are way too far out to ever be stored densely anyway.
+fn dense_index(transaction_id: TransactionId) -> usize {
+    usize::try_from(transaction_id.0).unwrap_or(usize::MAX)
+}
+
+/// Sets `values[index]`, growing `values` first if it's too short.
+fn set_lazily(values: &mut Vec<u64>, index: usize, value: u64) {
+    if index >= values.len() {
+        values.resize(index + 1, 0);
+    }
+    values[index] = value;
+}
+
+impl TransactionStore for CompactTransactionStore {
+    fn get(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<ProcessedTransaction>, StoreError> {
+        Ok(self
+            .dense(dense_index(transaction_id))
+            .or_else(|| self.sparse.get(&transaction_id).copied())
+            .map(|packed| self.unpack(transaction_id, packed)))
+    }
+
+    fn disputed(&self) -> Result<Vec<(TransactionId, ProcessedTransaction)>, StoreError> {
+        let dense = (0..self.flags.len()).filter_map(|index| {
+            let transaction_id = TransactionId(index as u64);
+            Some((
+                transaction_id,
+                self.unpack(transaction_id, self.dense(index)?),
+            ))
+        });
+        let sparse = self.sparse.iter().map(|(transaction_id, packed)| {
+            (*transaction_id, self.unpack(*transaction_id, *packed))
+        });
+        Ok(dense
+            .chain(sparse)
+            .filter(|(_, transaction)| transaction.dispute_status == DisputeStatus::Disputed)
+            .collect())
+    }
+
+    fn insert(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: ProcessedTransaction,
+    ) -> Result<(), StoreError> {
+        let packed = PackedTransaction::pack(&transaction);
+        let index = dense_index(transaction_id);
+
+        if transaction.disputed == whole_dispute(transaction.dispute_status, transaction.amount) {
+            self.partly_disputed.remove(&transaction_id);
+        } else {
+            self.partly_disputed
+                .insert(transaction_id, transaction.disputed);
+        }
+        if transaction.charged_back
+            == whole_chargeback(transaction.dispute_status, transaction.amount)
+        {
+            self.partly_charged_back.remove(&transaction_id);
+        } else {
+            self.partly_charged_back
+                .insert(transaction_id, transaction.charged_back);
+        }
+        if transaction.refunded == 0 {
+            self.refunded.remove(&transaction_id);
+        } else {
+            self.refunded.insert(transaction_id, transaction.refunded);
+        }
+        if transaction.pending == 0 {
+            self.pending.remove(&transaction_id);
+        } else {
+            self.pending.insert(transaction_id, transaction.pending);
+        }
+        match transaction.transaction_type {
+            ProcessedTransactionType::Transfer { to } => self.transfers.insert(transaction_id, to),
+            _ => self.transfers.remove(&transaction_id),
+        };
+        match &transaction.reason_code {
+            Some(code) => self.reason_codes.insert(transaction_id, code.clone()),
+            None => self.reason_codes.remove(&transaction_id),
+        };
+
+        // Updates stay where the transaction already lives.
+        if let Some(existing) = self.sparse.get_mut(&transaction_id) {
+            *existing = packed;
+            return Ok(());
+        }
+        if self.dense(index).is_none() {
+            self.len += 1;
+        }
+
+        if !self.fits_dense(index) {
+            self.sparse.insert(transaction_id, packed);
+            return Ok(());
+        }
+        if index >= self.flags.len() {
+            self.amounts.resize(index + 1, 0);
+            self.client_ids.resize(index + 1, 0);
+            self.flags.resize(index + 1, 0);
+        }
+        if self.flags[index] & WIDE_AMOUNT != 0 {
+            self.wide_amounts.remove(&index);
+        }
+        if self.client_ids[index] == WIDE_CLIENT_ID {
+            self.wide_client_ids.remove(&index);
+        }
+        self.client_ids[index] = match u16::try_from(packed.client_id.0) {
+            Ok(client_id) if client_id != WIDE_CLIENT_ID => client_id,
+            _ => {
+                self.wide_client_ids.insert(index, packed.client_id);
+                WIDE_CLIENT_ID
+            }
+        };
+        self.flags[index] = packed.flags;
+        match i64::try_from(packed.amount) {
+            Ok(amount) => self.amounts[index] = amount,
+            Err(_) => {
+                self.flags[index] |= WIDE_AMOUNT;
+                self.wide_amounts.insert(index, packed.amount);
+            }
+        }
+        if packed.flags & HAS_TIMESTAMP != 0 {
+            set_lazily(&mut self.timestamps, index, packed.timestamp);
+        }
+        if packed.flags & HAS_DISPUTED_AT != 0 {
+            set_lazily(&mut self.disputed_ats, index, packed.disputed_at);
+        }
+        if packed.flags & HAS_ASSET != 0 {
+            set_lazily(&mut self.assets, index, packed.asset);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::generate::{GeneratorConfig, generate};
+    use crate::history::NoHistory;
+    use crate::process::tests::{Clients, run, run_on};
+
+    #[test]
+    fn test_compact_store_round_trips_dense_and_sparse_ids() {
+        let mut store = CompactTransactionStore::new();
+        let deposit = ProcessedTransaction {
+            timestamp: Some(1727740740),
+            asset: Asset::new("BTC").unwrap(),
+            pending: 12000,
+            ..ProcessedTransaction::new(ClientId(7), 12345, ProcessedTransactionType::Deposit)
+        };
+        let mut withdrawal =
+            ProcessedTransaction::new(ClientId(u64::MAX), -1, ProcessedTransactionType::Withdrawal);
+        withdrawal.dispute_status = DisputeStatus::ChargedBack;
+
+        store.insert(TransactionId(0), deposit.clone()).unwrap();
+        store
+            .insert(TransactionId(u64::MAX), withdrawal.clone())
+            .unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(TransactionId(0)), Ok(Some(deposit.clone())));
+        assert_eq!(store.get(TransactionId(u64::MAX)), Ok(Some(withdrawal)));
+        assert_eq!(store.get(TransactionId(1)), Ok(None));
+        assert_eq!(store.contains(TransactionId(12)), Ok(false));
+        // The stray id must not have blown up the dense vectors
+        assert!(store.flags.len() <= MIN_DENSE_IDS);
+
+        let mut disputed = deposit;
+        disputed.dispute_status = DisputeStatus::Disputed;
+        disputed.disputed_at = Some(1727740800);
+        store.insert(TransactionId(0), disputed.clone()).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(TransactionId(0)), Ok(Some(disputed.clone())));
+        assert_eq!(store.disputed(), Ok(vec![(TransactionId(0), disputed)]));
+
+        // Too big for the dense i64 amounts, so it goes to the side until it's replaced
+        let wide = ProcessedTransaction::new(
+            ClientId(1),
+            -i128::MAX,
+            ProcessedTransactionType::Withdrawal,
+        );
+        store.insert(TransactionId(1), wide.clone()).unwrap();
+        assert_eq!(store.get(TransactionId(1)), Ok(Some(wide)));
+        let narrow = ProcessedTransaction::new(ClientId(1), 1, ProcessedTransactionType::Deposit);
+        store.insert(TransactionId(1), narrow.clone()).unwrap();
+        assert_eq!(store.get(TransactionId(1)), Ok(Some(narrow)));
+        assert!(store.wide_amounts.is_empty());
+    }
+
+    #[test]
+    fn test_compact_store_matches_hashmap() {
+        let config = GeneratorConfig {
+            rows: 20_000,
+            dispute_rate: 0.1,
+            resolve_rate: 0.04,
+            chargeback_rate: 0.01,
+            duplicate_rate: 0.02,
+            ..GeneratorConfig::default()
+        };
+        let mut bytes = Vec::new();
+        generate(&config, &mut bytes).unwrap();
+        let csv = String::from_utf8(bytes).unwrap();
+
+        let (_, hashmap_transactions, hashmap_clients) = run(&csv, &Config::default());
+        let mut compact_transactions = CompactTransactionStore::new();
+        let mut compact_clients = Clients::new();
+        run_on(
+            &csv,
+            &Config::default(),
+            &mut compact_transactions,
+            &mut compact_clients,
+            &mut NoHistory,
+        );
+
+        assert_eq!(compact_transactions.len(), hashmap_transactions.len());
+        for (transaction_id, transaction) in &hashmap_transactions {
+            assert_eq!(
+                compact_transactions.get(*transaction_id),
+                Ok(Some(transaction.clone()))
+            );
+        }
+        for (client_id, client) in &hashmap_clients {
+            let compact_client = &compact_clients[client_id];
+            assert_eq!(
+                compact_client.balance(Asset::DEFAULT).available,
+                client.balance(Asset::DEFAULT).available
+            );
+            assert_eq!(
+                compact_client.balance(Asset::DEFAULT).held,
+                client.balance(Asset::DEFAULT).held
+            );
+            assert_eq!(
+                compact_client.balance(Asset::DEFAULT).total,
+                client.balance(Asset::DEFAULT).total
+            );
+            assert_eq!(compact_client.state, client.state);
+        }
+    }
+}
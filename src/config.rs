@@ -0,0 +1,40 @@
+use crate::asset::AssetRegistry;
+use crate::clearing::ClearingPeriod;
+use crate::convert::Decimal;
+use crate::fees::FeeSchedule;
+use crate::overdraft::{DisputeOverdraft, OverdraftLimits};
+use crate::rules::Rules;
+
+/// Knobs that change how the handlers treat incoming rows.
+///
+/// Everything defaults to the behaviour described in the README so that
+/// `cargo run -- transactions.csv` keeps working without any flags.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Largest amount a single deposit/withdrawal may carry, in whole units
+    /// of whatever asset it is in. `None` means the only limit is what fits
+    /// in an `i128`.
+    pub max_amount: Option<Decimal>,
+    /// How many seconds after a transaction it can still be disputed.
+    /// Only enforced when both the transaction and the dispute have a timestamp.
+    pub dispute_window: Option<u64>,
+    /// How many seconds a dispute may stay open before it is resolved
+    /// automatically, in the client's favour. Time moves forward with the
+    /// timestamps of the incoming rows.
+    pub dispute_deadline: Option<u64>,
+    /// The precision of every asset.
+    pub assets: AssetRegistry,
+    /// How far below zero withdrawals may take each client.
+    pub overdrafts: OverdraftLimits,
+    /// How far below zero disputes may take each client.
+    pub dispute_overdraft: DisputeOverdraft,
+    /// Limits on deposits and withdrawals, checked before the handlers
+    /// get to them.
+    pub rules: Rules,
+    /// How long deposits stay pending before they can be withdrawn.
+    /// `None` makes them available straight away.
+    pub clearing: Option<ClearingPeriod>,
+    /// What the handlers charge for deposits, withdrawals, transfers and
+    /// chargebacks. Nothing by default.
+    pub fees: FeeSchedule,
+}
@@ -0,0 +1,308 @@
+use crate::client::Client;
+use crate::ids::{ClientId, TransactionId};
+use crate::processed_transaction::{DisputeStatus, ProcessedTransaction};
+use crate::store::{AccountStore, BATCH_SIZE, StoreError, TransactionStore};
+use redb::{Builder, Database, ReadableTable, TableDefinition, WriteTransaction};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+macro_rules! store_error_from {
+    ($($error:ty),*) => {
+        $(impl From<$error> for StoreError {
+            fn from(e: $error) -> Self {
+                StoreError(e.to_string())
+            }
+        })*
+    };
+}
+
+store_error_from!(
+    std::io::Error,
+    bincode::Error,
+    redb::DatabaseError,
+    redb::TransactionError,
+    redb::TableError,
+    redb::StorageError,
+    redb::CommitError
+);
+
+/// Both stores are tables of bincode values keyed by ID, in the same file
+/// so that a commit always covers both.
+const TRANSACTIONS: TableDefinition<u64, &[u8]> = TableDefinition::new("transactions");
+const CLIENTS: TableDefinition<u64, &[u8]> = TableDefinition::new("clients");
+
+/// What the state directory holds.
+const STATE_FILE: &str = "state.redb";
+/// What it held before both stores shared `STATE_FILE`.
+const OLD_TRANSACTIONS_FILE: &str = "transactions.redb";
+
+/// How much of the file redb keeps cached in memory.
+const CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Opens (or creates) the on-disk state in the directory at `path` and
+/// returns a transaction store and an account store backed by it.
+///
+/// Pointing the next run at the same directory picks up where this one
+/// left off: duplicates are still caught and disputes can refer to
+/// transactions from earlier files.
+pub fn open_disk_stores(
+    path: impl AsRef<Path>,
+) -> Result<(DiskTransactionStore, DiskAccountStore), StoreError> {
+    let path = path.as_ref();
+    if path.join(OLD_TRANSACTIONS_FILE).exists() {
+        return Err(StoreError(format!(
+            "{} holds state in an older format, start a new one",
+            path.display()
+        )));
+    }
+    fs::create_dir_all(path)?;
+    let file = Rc::new(RefCell::new(KvFile::open(&path.join(STATE_FILE))?));
+    Ok((
+        DiskTransactionStore {
+            file: Rc::clone(&file),
+        },
+        DiskAccountStore { file },
+    ))
+}
+
+/// A redb file with one write transaction always open, so reads see
+/// everything inserted so far whether it's been committed or not.
+struct KvFile {
+    db: Database,
+    txn: Option<WriteTransaction>,
+    pending: usize,
+}
+
+impl KvFile {
+    fn open(path: &Path) -> Result<Self, StoreError> {
+        let db = Builder::new().set_cache_size(CACHE_BYTES).create(path)?;
+        let txn = db.begin_write()?;
+        // Creates the tables, so reading from a fresh file works.
+        txn.open_table(TRANSACTIONS)?;
+        txn.open_table(CLIENTS)?;
+        Ok(KvFile {
+            db,
+            txn: Some(txn),
+            pending: 0,
+        })
+    }
+
+    /// Only missing if a commit failed, in which case the store is unusable.
+    fn txn(&self) -> Result<&WriteTransaction, StoreError> {
+        self.txn
+            .as_ref()
+            .ok_or(StoreError("no open write transaction".to_string()))
+    }
+
+    fn get<V: DeserializeOwned>(
+        &self,
+        table: TableDefinition<u64, &[u8]>,
+        key: u64,
+    ) -> Result<Option<V>, StoreError> {
+        let table = self.txn()?.open_table(table)?;
+        match table.get(key)? {
+            Some(bytes) => Ok(Some(bincode::deserialize(bytes.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn contains(&self, table: TableDefinition<u64, &[u8]>, key: u64) -> Result<bool, StoreError> {
+        let table = self.txn()?.open_table(table)?;
+        Ok(table.get(key)?.is_some())
+    }
+
+    /// Only goes in the open write transaction, see `row_done`.
+    fn insert<V: Serialize>(
+        &mut self,
+        table: TableDefinition<u64, &[u8]>,
+        key: u64,
+        value: &V,
+    ) -> Result<(), StoreError> {
+        let bytes = bincode::serialize(value)?;
+        self.txn()?
+            .open_table(table)?
+            .insert(key, bytes.as_slice())?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Every key and value that `keep` says yes to, sorted by key.
+    fn entries<V: DeserializeOwned>(
+        &self,
+        table: TableDefinition<u64, &[u8]>,
+        keep: impl Fn(&V) -> bool,
+    ) -> Result<Vec<(u64, V)>, StoreError> {
+        let table = self.txn()?.open_table(table)?;
+        let mut entries = Vec::new();
+        for entry in table.iter()? {
+            let (key, bytes) = entry?;
+            let value = bincode::deserialize(bytes.value())?;
+            if keep(&value) {
+                entries.push((key.value(), value));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Commits once `BATCH_SIZE` writes piled up. Only called between rows,
+    /// so a commit never has half of one.
+    fn row_done(&mut self) -> Result<(), StoreError> {
+        if self.pending >= BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        if let Some(txn) = self.txn.take() {
+            txn.commit()?;
+        }
+        self.txn = Some(self.db.begin_write()?);
+        self.pending = 0;
+        Ok(())
+    }
+}
+
+/// A `TransactionStore` kept in an embedded key-value store on disk,
+/// for histories that don't fit in memory.
+///
+/// It shares its file with the `DiskAccountStore` it was opened with, so
+/// flushing either one commits both.
+pub struct DiskTransactionStore {
+    file: Rc<RefCell<KvFile>>,
+}
+
+impl TransactionStore for DiskTransactionStore {
+    fn get(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<ProcessedTransaction>, StoreError> {
+        self.file.borrow().get(TRANSACTIONS, transaction_id.0)
+    }
+
+    fn contains(&self, transaction_id: TransactionId) -> Result<bool, StoreError> {
+        self.file.borrow().contains(TRANSACTIONS, transaction_id.0)
+    }
+
+    /// Has to go through every transaction, there's no index on the status.
+    fn disputed(&self) -> Result<Vec<(TransactionId, ProcessedTransaction)>, StoreError> {
+        let disputed =
+            self.file
+                .borrow()
+                .entries(TRANSACTIONS, |transaction: &ProcessedTransaction| {
+                    transaction.dispute_status == DisputeStatus::Disputed
+                })?;
+        Ok(disputed
+            .into_iter()
+            .map(|(transaction_id, transaction)| (TransactionId(transaction_id), transaction))
+            .collect())
+    }
+
+    fn insert(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: ProcessedTransaction,
+    ) -> Result<(), StoreError> {
+        self.file
+            .borrow_mut()
+            .insert(TRANSACTIONS, transaction_id.0, &transaction)
+    }
+
+    fn row_done(&mut self) -> Result<(), StoreError> {
+        self.file.borrow_mut().row_done()
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        self.file.borrow_mut().flush()
+    }
+}
+
+/// The `AccountStore` counterpart of `DiskTransactionStore`.
+pub struct DiskAccountStore {
+    file: Rc<RefCell<KvFile>>,
+}
+
+impl AccountStore for DiskAccountStore {
+    fn get(&self, client_id: ClientId) -> Result<Option<Client>, StoreError> {
+        self.file.borrow().get(CLIENTS, client_id.0)
+    }
+
+    fn insert(&mut self, client_id: ClientId, client: Client) -> Result<(), StoreError> {
+        self.file.borrow_mut().insert(CLIENTS, client_id.0, &client)
+    }
+
+    fn clients(&self) -> Result<Vec<Client>, StoreError> {
+        let clients = self.file.borrow().entries(CLIENTS, |_: &Client| true)?;
+        Ok(clients.into_iter().map(|(_, client)| client).collect())
+    }
+
+    fn row_done(&mut self) -> Result<(), StoreError> {
+        self.file.borrow_mut().row_done()
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        self.file.borrow_mut().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::Asset;
+    use crate::config::Config;
+    use crate::history::NoHistory;
+    use crate::process::tests::run_on;
+    use crate::rejection::Rejection;
+
+    #[test]
+    fn test_state_is_kept_between_runs() {
+        let path = std::env::temp_dir().join(format!("tx2acc-disk-store-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+
+        let first_run = "type, client, tx, amount\n\
+                         deposit, 1, 1, 10.0\n\
+                         deposit, 2, 2, 5.0\n";
+        {
+            let (mut transactions, mut clients) = open_disk_stores(&path).unwrap();
+            run_on(
+                first_run,
+                &Config::default(),
+                &mut transactions,
+                &mut clients,
+                &mut NoHistory,
+            );
+            transactions.flush().unwrap();
+            clients.flush().unwrap();
+        }
+
+        // Refers to the first run's transactions
+        let second_run = "type, client, tx, amount\n\
+                          deposit, 1, 1, 10.0\n\
+                          dispute, 1, 1,\n\
+                          withdrawal, 2, 3, 1.0\n";
+        let (mut transactions, mut clients) = open_disk_stores(&path).unwrap();
+        let summary = run_on(
+            second_run,
+            &Config::default(),
+            &mut transactions,
+            &mut clients,
+            &mut NoHistory,
+        );
+        assert_eq!(summary.rejections[&Rejection::DuplicateTransaction], 1);
+        assert_eq!(summary.rejected(), 1);
+
+        let clients = clients.clients().unwrap();
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0].client_id(), ClientId(1));
+        assert_eq!(clients[0].balance(Asset::DEFAULT).available, 0);
+        assert_eq!(clients[0].balance(Asset::DEFAULT).held, 100000);
+        assert_eq!(clients[1].client_id(), ClientId(2));
+        assert_eq!(clients[1].balance(Asset::DEFAULT).available, 40000);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+use crate::asset::Asset;
+use crate::config::Config;
+use crate::ids::{ClientId, TransactionId};
+use crate::processed_transaction::DisputeStatus;
+use crate::store::{AccountStore, StoreError, TransactionStore};
+use crate::trace;
+use std::collections::BTreeSet;
+
+/// A transaction whose dispute status changed without a row asking for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisputeTransition {
+    pub transaction_id: TransactionId,
+    pub client_id: ClientId,
+    /// The disputed transaction's.
+    pub asset: Asset,
+    pub from: DisputeStatus,
+    pub to: DisputeStatus,
+    /// The timestamp of the row that moved time past the deadline.
+    pub at: u64,
+}
+
+/// The open disputes that have a deadline (see `Config::dispute_deadline`),
+/// ordered by when they expire.
+///
+/// Entries aren't removed when a dispute gets resolved or charged back,
+/// they are skipped when their deadline comes instead. Partial disputes on
+/// the same transaction all expire together, once the latest one is past
+/// its deadline.
+#[derive(Debug, Default)]
+pub struct OpenDisputes {
+    deadline: Option<u64>,
+    by_expiry: BTreeSet<(u64, TransactionId)>,
+}
+
+impl OpenDisputes {
+    /// Picks up the disputes still open in `transactions`,
+    /// which only has any if the state comes from an earlier run.
+    pub fn load(config: &Config, transactions: &impl TransactionStore) -> Result<Self, StoreError> {
+        let mut open_disputes = OpenDisputes {
+            deadline: config.dispute_deadline,
+            by_expiry: BTreeSet::new(),
+        };
+        if open_disputes.deadline.is_some() {
+            for (transaction_id, transaction) in transactions.disputed()? {
+                open_disputes.track(transaction_id, transaction.disputed_at);
+            }
+        }
+        Ok(open_disputes)
+    }
+
+    /// Starts the clock on a dispute. Disputes without a timestamp never expire.
+    pub fn track(&mut self, transaction_id: TransactionId, disputed_at: Option<u64>) {
+        if let (Some(deadline), Some(disputed_at)) = (self.deadline, disputed_at) {
+            self.by_expiry
+                .insert((disputed_at.saturating_add(deadline), transaction_id));
+        }
+    }
+
+    /// Resolves every dispute whose deadline is before `now`.
+    ///
+    /// A dispute that can't be resolved (releasing it would overflow the
+    /// account's available funds) stays disputed and is dropped from the queue.
+    /// A transaction part of which was charged back ends up charged back
+    /// rather than resolved, see `ProcessedTransaction::settle`.
+    pub fn expire(
+        &mut self,
+        now: u64,
+        transactions: &mut impl TransactionStore,
+        clients: &mut impl AccountStore,
+    ) -> Result<Vec<DisputeTransition>, StoreError> {
+        let mut transitions = Vec::new();
+
+        while let Some(&(expires_at, transaction_id)) = self.by_expiry.first()
+            && expires_at < now
+        {
+            self.by_expiry.pop_first();
+
+            let Some(mut tx) = transactions.get(transaction_id)? else {
+                continue;
+            };
+            if tx.dispute_status != DisputeStatus::Disputed {
+                continue;
+            }
+            // Another dispute on the transaction since, which restarted the clock.
+            if let (Some(deadline), Some(disputed_at)) = (self.deadline, tx.disputed_at)
+                && disputed_at.saturating_add(deadline) >= now
+            {
+                continue;
+            }
+            let Some(mut client) = clients.get(tx.holder())? else {
+                continue;
+            };
+            if let Err(e) = client.apply_resolve(tx.asset, tx.disputed) {
+                trace!(
+                    "Could not resolve expired dispute for transaction {}: {}",
+                    transaction_id, e
+                );
+                continue;
+            }
+
+            trace!(
+                "Dispute for transaction {} expired, resolved automatically.",
+                transaction_id
+            );
+            clients.insert(tx.holder(), client)?;
+            tx.disputed = 0;
+            tx.settle();
+            transactions.insert(transaction_id, tx.clone())?;
+            transitions.push(DisputeTransition {
+                transaction_id,
+                client_id: tx.client_id,
+                asset: tx.asset,
+                from: DisputeStatus::Disputed,
+                to: tx.dispute_status,
+                at: now,
+            });
+        }
+
+        Ok(transitions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::tests::run;
+    use crate::rejection::Rejection;
+
+    #[test]
+    fn test_dispute_window_and_deadline() {
+        let config = Config {
+            dispute_window: Some(100),
+            dispute_deadline: Some(50),
+            ..Config::default()
+        };
+
+        // tx 1 is disputed too late, tx 2 in time and is left open past its
+        // deadline, tx 3 is disputed without a timestamp so it never expires.
+        let csv = "type, client, tx, amount, timestamp\n\
+                   deposit, 1, 1, 10.0, 1000\n\
+                   deposit, 1, 2, 5.0, 1000\n\
+                   deposit, 1, 3, 1.0, 1000\n\
+                   dispute, 1, 1, , 1101\n\
+                   dispute, 1, 2, , 1100\n\
+                   dispute, 1, 3, ,\n\
+                   deposit, 1, 4, 1.0, 1150\n\
+                   deposit, 1, 5, 1.0, 1151\n\
+                   resolve, 1, 2, , 1152\n";
+        let (summary, transactions, clients) = run(csv, &config);
+
+        assert_eq!(summary.rejections[&Rejection::DisputeWindowExpired], 1);
+        assert_eq!(summary.rejections[&Rejection::NotDisputed], 1);
+        assert_eq!(
+            summary.dispute_transitions,
+            vec![DisputeTransition {
+                transaction_id: 2.into(),
+                client_id: 1.into(),
+                asset: Asset::DEFAULT,
+                from: DisputeStatus::Disputed,
+                to: DisputeStatus::Resolved,
+                at: 1151,
+            }]
+        );
+        assert_eq!(
+            transactions[&TransactionId(2)].dispute_status,
+            DisputeStatus::Resolved
+        );
+        assert_eq!(
+            transactions[&TransactionId(3)].dispute_status,
+            DisputeStatus::Disputed
+        );
+        let client = &clients[&ClientId(1)];
+        assert_eq!(client.balance(Asset::DEFAULT).available, 170000);
+        assert_eq!(client.balance(Asset::DEFAULT).held, 10000);
+    }
+}
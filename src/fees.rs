@@ -0,0 +1,306 @@
+use crate::asset::{Asset, AssetRegistry};
+use crate::convert::{Decimal, RawAmount, parse_amount};
+use crate::history::HistoryEvent;
+use crate::ids::{ClientId, TransactionId};
+use crate::ingest::csv_reader;
+use crate::raw_transaction::RawTransactionType;
+use serde::Deserialize;
+use std::error::Error;
+use std::io::Read;
+
+/// Percentages are kept in millionths of the amount, so `1.5` is `15000`.
+const RATE_DECIMALS: u32 = 4;
+const RATE_SCALE: i128 = 100 * 10i128.pow(RATE_DECIMALS);
+
+/// What rows of one type in one asset are charged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fee {
+    /// Deposits, withdrawals, transfers or chargebacks.
+    pub transaction_type: RawTransactionType,
+    pub asset: Asset,
+    /// In units of the asset's smallest fraction, like `min` and `max`.
+    pub flat: i128,
+    /// Of the row's amount, in millionths.
+    pub rate: i128,
+    pub min: i128,
+    pub max: Option<i128>,
+}
+
+impl Fee {
+    /// The fee for a row of `amount`, the percentage rounded down to the
+    /// asset's smallest fraction.
+    pub fn charge(&self, amount: i128) -> i128 {
+        let percentage = match amount.checked_mul(self.rate) {
+            Some(amount) => amount / RATE_SCALE,
+            // Only for huge amounts, which lose nothing worth keeping here.
+            None => (amount / RATE_SCALE).saturating_mul(self.rate),
+        };
+        self.flat
+            .saturating_add(percentage)
+            .max(self.min)
+            .min(self.max.unwrap_or(i128::MAX))
+    }
+}
+
+/// The fees the handlers charge, paid into the house account.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeeSchedule {
+    house: ClientId,
+    fees: Vec<Fee>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FeeRow {
+    #[serde(rename = "type")]
+    transaction_type: String,
+    #[serde(default)]
+    asset: String,
+    #[serde(default)]
+    flat: String,
+    #[serde(default)]
+    percent: String,
+    #[serde(default)]
+    min: String,
+    #[serde(default)]
+    max: String,
+}
+
+impl FeeSchedule {
+    pub fn new(house: ClientId) -> Self {
+        FeeSchedule {
+            house,
+            fees: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, fee: Fee) {
+        self.fees.push(fee);
+    }
+
+    pub fn house(&self) -> ClientId {
+        self.house
+    }
+
+    /// What `client_id` is charged for a row of `amount`, nothing if no fee
+    /// applies or the client is the house itself.
+    pub fn fee(
+        &self,
+        client_id: ClientId,
+        transaction_type: &RawTransactionType,
+        asset: Asset,
+        amount: i128,
+    ) -> i128 {
+        if client_id == self.house {
+            return 0;
+        }
+        self.fees
+            .iter()
+            .find(|fee| fee.transaction_type == *transaction_type && fee.asset == asset)
+            .map_or(0, |fee| fee.charge(amount))
+    }
+
+    /// Reads a CSV with `type`, `asset`, `flat`, `percent`, `min` and `max`
+    /// columns, e.g. `withdrawal,,0.5,1.5,1,25`.
+    ///
+    /// Types are `deposit`, `withdrawal`, `transfer` and `chargeback`, with
+    /// at most one row per type and asset. An empty asset is the default
+    /// asset, amounts are in whole units of it and empty ones are zero,
+    /// except for `max`, which is then unlimited.
+    pub fn from_csv<R: Read>(
+        rdr: R,
+        assets: &AssetRegistry,
+        house: ClientId,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut schedule = FeeSchedule::new(house);
+        for row in csv_reader(rdr).deserialize() {
+            let row: FeeRow = row?;
+            let invalid = |what: &str, value: &str| {
+                format!("{} fee: invalid {} '{}'", row.transaction_type, what, value)
+            };
+
+            let transaction_type = match row.transaction_type.as_str() {
+                "deposit" => RawTransactionType::Deposit,
+                "withdrawal" => RawTransactionType::Withdrawal,
+                "transfer" => RawTransactionType::Transfer,
+                "chargeback" => RawTransactionType::Chargeback,
+                other => return Err(format!("invalid fee type '{}'", other).into()),
+            };
+            let asset = Asset::new(&row.asset).ok_or(invalid("asset", &row.asset))?;
+            let decimals = assets.decimals(asset);
+            let amount = |what: &str, value: &str, decimals: u32| match parse_amount(value) {
+                Some(RawAmount::Decimal(amount)) => amount
+                    .to_units(decimals)
+                    .ok()
+                    .filter(|amount| *amount >= 0)
+                    .ok_or(invalid(what, value)),
+                _ => Err(invalid(what, value)),
+            };
+            let amount_or_zero = |what: &str, value: &str, decimals: u32| match value {
+                "" => Ok(0),
+                value => amount(what, value, decimals),
+            };
+
+            if schedule
+                .fees
+                .iter()
+                .any(|fee| fee.transaction_type == transaction_type && fee.asset == asset)
+            {
+                return Err(format!(
+                    "{} fee: more than one row for asset '{}'",
+                    row.transaction_type, row.asset
+                )
+                .into());
+            }
+            schedule.push(Fee {
+                transaction_type,
+                asset,
+                flat: amount_or_zero("flat", &row.flat, decimals)?,
+                rate: amount_or_zero("percent", &row.percent, RATE_DECIMALS)?,
+                min: amount_or_zero("min", &row.min, decimals)?,
+                max: match row.max.as_str() {
+                    "" => None,
+                    max => Some(amount("max", max, decimals)?),
+                },
+            });
+        }
+        Ok(schedule)
+    }
+}
+
+/// A fee a client paid into the house account.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeeCharge {
+    pub client_id: ClientId,
+    /// The transaction it was for.
+    pub transaction_id: TransactionId,
+    pub house: ClientId,
+    pub asset: Asset,
+    /// In units of the asset's smallest fraction.
+    pub amount: i128,
+}
+
+impl FeeCharge {
+    /// The charge as a `fee` row, its own line in the history.
+    pub fn history_event(&self, timestamp: Option<u64>, assets: &AssetRegistry) -> HistoryEvent {
+        HistoryEvent {
+            transaction_type: RawTransactionType::Fee,
+            client_id: self.client_id,
+            transaction_id: self.transaction_id,
+            amount: Some(Decimal::from_units(
+                self.amount,
+                assets.decimals(self.asset),
+            )),
+            timestamp,
+            asset: self.asset,
+            destination: Some(self.house),
+            reason: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clearing::ClearingPeriod;
+    use crate::client::Client;
+    use crate::config::Config;
+    use crate::history::{HistoryWriter, state_as_of};
+    use crate::ingest::InputFormat;
+    use crate::process::tests::run_with_history;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_fees_are_paid_into_the_house_account() {
+        let assets = AssetRegistry::new();
+        let schedule = "type,asset,flat,percent,min,max\n\
+                        withdrawal,,0.5,1,1,2\n\
+                        chargeback,,15,,,\n";
+        let config = Config {
+            fees: FeeSchedule::from_csv(schedule.as_bytes(), &assets, ClientId(0)).unwrap(),
+            ..Config::default()
+        };
+
+        // 1% of 10 is under the minimum and of 200 over the cap. The last
+        // withdrawal could be paid for, but not with the fee on top.
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 300.0\n\
+                   withdrawal, 1, 2, 10.0\n\
+                   withdrawal, 1, 3, 200.0\n\
+                   withdrawal, 1, 4, 87.0\n\
+                   dispute, 1, 1,\n\
+                   chargeback, 1, 1,\n";
+        let mut history = HistoryWriter::new(Vec::new(), true).unwrap();
+        let (summary, _, clients) = run_with_history(csv, &config, &mut history);
+
+        assert_eq!(summary.rejected(), 1);
+        assert_eq!(
+            summary.fees.get(&(ClientId(1), Asset::DEFAULT)),
+            Some(&180000)
+        );
+        let balance = |clients: &HashMap<ClientId, Client>, client_id| {
+            clients[&ClientId(client_id)]
+                .balance(Asset::DEFAULT)
+                .available
+        };
+        // 300 - 10 - 200 - 300 charged back, less 1 + 2 + 15 in fees
+        assert_eq!(balance(&clients, 1), -2280000);
+        assert_eq!(balance(&clients, 0), 180000);
+
+        // The fees are in the history as rows of their own, so replaying it,
+        // which leaves the schedule out, gets to the same balances.
+        let bytes = history.into_inner();
+        let snapshot = state_as_of(&bytes[..], InputFormat::Csv, u64::MAX, &config).unwrap();
+        assert_eq!(snapshot.rejected, 0);
+        assert_eq!(balance(&snapshot.clients, 1), -2280000);
+        assert_eq!(balance(&snapshot.clients, 0), 180000);
+
+        let fee = |rate, max| Fee {
+            rate,
+            max,
+            ..config.fees.fees[0].clone()
+        };
+        assert_eq!(fee(15000, None).charge(10000000), 155000);
+        // 1% of the largest amount there is, give or take a millionth.
+        let huge = fee(10000, None).charge(i128::MAX);
+        assert!((huge - i128::MAX / 100).abs() < 1000000);
+        assert_eq!(fee(10000, Some(20000)).charge(i128::MAX), 20000);
+    }
+
+    #[test]
+    fn test_deposit_fees_come_out_of_pending_deposits() {
+        let assets = AssetRegistry::new();
+        let schedule = "type,asset,flat,percent,min,max\n\
+                        deposit,,1,,,\n";
+        let config = Config {
+            fees: FeeSchedule::from_csv(schedule.as_bytes(), &assets, ClientId(0)).unwrap(),
+            clearing: ClearingPeriod::parse("1rows"),
+            ..Config::default()
+        };
+
+        // Client 1 has nothing available when either deposit comes in.
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 100.0\n\
+                   deposit, 1, 2, 100.0\n\
+                   deposit, 2, 3, 1.0\n\
+                   deposit, 2, 4, 1.0\n";
+        let mut history = HistoryWriter::new(Vec::new(), true).unwrap();
+        let (summary, _, clients) = run_with_history(csv, &config, &mut history);
+
+        assert_eq!(summary.rejected(), 0);
+        // Only 99 of each of client 1's deposits clears, and nothing of
+        // client 2's, which only just cover their fees.
+        let balances = |clients: &HashMap<ClientId, Client>| {
+            [ClientId(0), ClientId(1), ClientId(2)].map(|client_id| {
+                let balance = clients[&client_id].balance(Asset::DEFAULT);
+                (balance.available, balance.pending, balance.total)
+            })
+        };
+        let expected = [(40000, 0, 40000), (1980000, 0, 1980000), (0, 0, 0)];
+        assert_eq!(balances(&clients), expected);
+
+        let bytes = history.into_inner();
+        let snapshot = state_as_of(&bytes[..], InputFormat::Csv, u64::MAX, &config).unwrap();
+        assert_eq!(snapshot.rejected, 0);
+        assert_eq!(balances(&snapshot.clients), expected);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::asset::Asset;
+use crate::ids::{ClientId, TransactionId};
+
+/// A deposit charged back after some of it was already withdrawn.
+///
+/// That's the pattern the README worries about: deposit, withdraw, then
+/// charge the deposit back. The withdrawn part is gone, and it's what we
+/// stand to lose on the client, their `exposure`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FraudAlert {
+    pub client_id: ClientId,
+    pub transaction_id: TransactionId,
+    pub asset: Asset,
+    /// What the deposit was for, in units of the asset's smallest fraction.
+    pub amount: i128,
+    /// How much of it had been withdrawn, in the same units.
+    pub exposure: i128,
+    /// When the chargeback happened, if the row said.
+    pub at: Option<u64>,
+    /// See `ProcessedTransaction::reason_code`.
+    pub reason_code: Option<String>,
+}
+
+impl FraudAlert {
+    /// How much of the deposit was withdrawn, from 1 to 100 percent.
+    pub fn score(&self) -> u8 {
+        let score = match self.exposure.checked_mul(100) {
+            Some(exposure) => exposure / self.amount,
+            // Only for huge amounts, which a hundredth of is plenty precise.
+            None => self.exposure / (self.amount / 100),
+        };
+        score.clamp(1, 100) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::process::tests::run;
+
+    #[test]
+    fn test_chargebacks_of_withdrawn_deposits_raise_alerts() {
+        // Client 1 withdraws most of tx 1 before charging it back, client 2
+        // deposits more after withdrawing, so nothing they took is at risk.
+        let csv = "type, client, tx, amount, timestamp\n\
+                   deposit, 1, 1, 10.0, 1000\n\
+                   withdrawal, 1, 2, 7.5, 1001\n\
+                   dispute, 1, 1, , 1002\n\
+                   chargeback, 1, 1, , 1003\n\
+                   deposit, 2, 3, 10.0,\n\
+                   withdrawal, 2, 4, 5.0,\n\
+                   deposit, 2, 5, 5.0,\n\
+                   dispute, 2, 3, ,\n\
+                   chargeback, 2, 3, ,\n";
+        let (summary, _, _) = run(csv, &Config::default());
+
+        assert_eq!(
+            summary.fraud_alerts,
+            vec![FraudAlert {
+                client_id: ClientId(1),
+                transaction_id: TransactionId(1),
+                asset: Asset::DEFAULT,
+                amount: 100000,
+                exposure: 75000,
+                at: Some(1003),
+                reason_code: None,
+            }]
+        );
+        assert_eq!(summary.fraud_alerts[0].score(), 75);
+
+        let alert = |amount, exposure| FraudAlert {
+            amount,
+            exposure,
+            ..summary.fraud_alerts[0].clone()
+        };
+        assert_eq!(alert(50, 10).score(), 20);
+        assert_eq!(alert(10000, 1).score(), 1);
+        assert_eq!(alert(i128::MAX, i128::MAX / 2).score(), 50);
+    }
+}
@@ -0,0 +1,436 @@
+use crate::asset::Asset;
+use crate::client::{Balance, Client};
+use crate::convert::{DEFAULT_DECIMALS, format_units};
+use crate::ids::{ClientId, TransactionId};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// The formats the generator can write, matching what `ingest` can read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Jsonl,
+}
+
+/// Shape of the generated stream.
+///
+/// All the `*_rate`s are per-row probabilities, except `withdrawal_rate`
+/// which is the share of deposits/withdrawals that are withdrawals.
+/// Whatever isn't used up by the effect, duplicate and malformed rates
+/// becomes a deposit or a withdrawal.
+#[derive(Debug, Clone)]
+pub struct GeneratorConfig {
+    pub rows: usize,
+    pub clients: u64,
+    pub seed: u64,
+    pub format: OutputFormat,
+    pub withdrawal_rate: f64,
+    pub dispute_rate: f64,
+    pub resolve_rate: f64,
+    pub chargeback_rate: f64,
+    pub duplicate_rate: f64,
+    pub malformed_rate: f64,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        GeneratorConfig {
+            rows: 1_000,
+            clients: 100,
+            seed: 42,
+            format: OutputFormat::Csv,
+            withdrawal_rate: 0.3,
+            dispute_rate: 0.02,
+            resolve_rate: 0.01,
+            chargeback_rate: 0.002,
+            duplicate_rate: 0.001,
+            malformed_rate: 0.001,
+        }
+    }
+}
+
+/// A generated row, kept as text so we can also write rows that
+/// don't parse (e.g. a client ID that isn't a number).
+struct Row {
+    transaction_type: &'static str,
+    client: String,
+    tx: TransactionId,
+    amount: Option<String>,
+}
+
+/// What the generator remembers about a deposit/withdrawal it wrote
+/// and the engine is expected to have applied.
+struct AppliedTransfer {
+    transaction_type: &'static str,
+    client_id: ClientId,
+    amount: i128,
+}
+
+/// Writes `config.rows` rows to `out` and returns the accounts we expect
+/// the engine to end up with after processing them.
+///
+/// The expected accounts come from a small model that lives here rather
+/// than from the handlers, so it can be used to check them. It follows
+/// the rules from the README: rejected rows change nothing, withdrawals
+/// need enough available funds and locked clients can't do anything.
+pub fn generate<W: Write>(
+    config: &GeneratorConfig,
+    out: W,
+) -> io::Result<HashMap<ClientId, Client>> {
+    let mut generator = Generator {
+        config,
+        rng: ChaCha8Rng::seed_from_u64(config.seed),
+        out,
+        clients: HashMap::new(),
+        applied: HashMap::new(),
+        applied_ids: Vec::new(),
+        disputable: Vec::new(),
+        disputed: Vec::new(),
+        next_tx: 1,
+    };
+
+    if config.format == OutputFormat::Csv {
+        writeln!(generator.out, "type, client, tx, amount")?;
+    }
+    for _ in 0..config.rows {
+        generator.next_row()?;
+    }
+    generator.out.flush()?;
+
+    Ok(generator.clients)
+}
+
+struct Generator<'a, W: Write> {
+    config: &'a GeneratorConfig,
+    rng: ChaCha8Rng,
+    out: W,
+    clients: HashMap<ClientId, Client>,
+    applied: HashMap<TransactionId, AppliedTransfer>,
+    /// Same keys as `applied`, so we can pick one at random for duplicates.
+    applied_ids: Vec<TransactionId>,
+    /// Deposits that are still `Valid`.
+    disputable: Vec<TransactionId>,
+    /// Deposits that are currently `Disputed`.
+    disputed: Vec<TransactionId>,
+    next_tx: u64,
+}
+
+impl<W: Write> Generator<'_, W> {
+    fn next_row(&mut self) -> io::Result<()> {
+        let config = self.config;
+        let roll: f64 = self.rng.random();
+        let mut threshold = 0.0;
+        let mut below = |rate: f64| {
+            threshold += rate;
+            roll < threshold
+        };
+
+        let row = if below(config.malformed_rate) {
+            Some(self.malformed())
+        } else if below(config.duplicate_rate) {
+            self.duplicate()
+        } else if below(config.dispute_rate) {
+            self.dispute()
+        } else if below(config.resolve_rate) {
+            self.resolve()
+        } else if below(config.chargeback_rate) {
+            self.chargeback()
+        } else {
+            None
+        };
+
+        // If there is nothing to apply it to (e.g. a resolve without
+        // any open dispute) we fall back to a deposit/withdrawal.
+        let row = match row {
+            Some(row) => row,
+            None => self.transfer(),
+        };
+        self.write(&row)
+    }
+
+    fn fresh_tx(&mut self) -> TransactionId {
+        let tx = TransactionId(self.next_tx);
+        self.next_tx += 1;
+        tx
+    }
+
+    fn random_client(&mut self) -> ClientId {
+        ClientId(self.rng.random_range(1..=self.config.clients.max(1)))
+    }
+
+    fn transfer(&mut self) -> Row {
+        let client_id = self.random_client();
+        let tx = self.fresh_tx();
+        let client = self
+            .clients
+            .entry(client_id)
+            .or_insert(Client::new(client_id));
+        let locked = client.is_locked();
+        let balance = default_balance(client);
+
+        let (transaction_type, amount, applied) =
+            if self.rng.random_bool(self.config.withdrawal_rate) {
+                // Mostly affordable, but every now and then a bit more than
+                // what's available so insufficient funds get exercised too.
+                let ceiling = (balance.available + balance.available / 10).max(10_000);
+                let amount = self.rng.random_range(1..=ceiling);
+                let applied = !locked && balance.available >= amount;
+                if applied {
+                    balance.available -= amount;
+                    balance.total -= amount;
+                }
+                ("withdrawal", amount, applied)
+            } else {
+                let amount = self.rng.random_range(1..=10_000_000);
+                let applied = !locked;
+                if applied {
+                    balance.available += amount;
+                    balance.total += amount;
+                    self.disputable.push(tx);
+                }
+                ("deposit", amount, applied)
+            };
+
+        if applied {
+            self.applied.insert(
+                tx,
+                AppliedTransfer {
+                    transaction_type,
+                    client_id,
+                    amount,
+                },
+            );
+            self.applied_ids.push(tx);
+        }
+
+        Row {
+            transaction_type,
+            client: client_id.to_string(),
+            tx,
+            amount: Some(format_units(amount, DEFAULT_DECIMALS)),
+        }
+    }
+
+    fn duplicate(&mut self) -> Option<Row> {
+        if self.applied_ids.is_empty() {
+            return None;
+        }
+        let tx = self.applied_ids[self.rng.random_range(0..self.applied_ids.len())];
+        let original = &self.applied[&tx];
+
+        Some(Row {
+            transaction_type: original.transaction_type,
+            client: original.client_id.to_string(),
+            tx,
+            amount: Some(format_units(original.amount, DEFAULT_DECIMALS)),
+        })
+    }
+
+    fn dispute(&mut self) -> Option<Row> {
+        let tx = take_random(&mut self.rng, &mut self.disputable)?;
+        let deposit = &self.applied[&tx];
+        let client = self.clients.get_mut(&deposit.client_id)?;
+
+        // Locked clients can't dispute, the deposit just stays valid.
+        if !client.is_locked() {
+            let balance = default_balance(client);
+            balance.available -= deposit.amount;
+            balance.held += deposit.amount;
+            self.disputed.push(tx);
+        }
+        Some(effect_row("dispute", deposit.client_id, tx))
+    }
+
+    fn resolve(&mut self) -> Option<Row> {
+        let tx = take_random(&mut self.rng, &mut self.disputed)?;
+        let deposit = &self.applied[&tx];
+        let client = self.clients.get_mut(&deposit.client_id)?;
+
+        // Open disputes are settled even once the client is locked.
+        let balance = default_balance(client);
+        balance.available += deposit.amount;
+        balance.held -= deposit.amount;
+        Some(effect_row("resolve", deposit.client_id, tx))
+    }
+
+    fn chargeback(&mut self) -> Option<Row> {
+        let tx = take_random(&mut self.rng, &mut self.disputed)?;
+        let deposit = &self.applied[&tx];
+        let client = self.clients.get_mut(&deposit.client_id)?;
+
+        let balance = default_balance(client);
+        balance.held -= deposit.amount;
+        balance.total -= deposit.amount;
+        client.lock(tx);
+        Some(effect_row("chargeback", deposit.client_id, tx))
+    }
+
+    /// Rows the engine must reject or fail to parse. They use a fresh
+    /// transaction ID which is then never reused.
+    fn malformed(&mut self) -> Row {
+        let client = self.random_client().to_string();
+        let tx = self.fresh_tx();
+
+        let (transaction_type, client, amount) = match self.rng.random_range(0..6) {
+            0 => ("deposit", client, None),
+            1 => ("withdrawal", client, Some("-1.0000".to_string())),
+            2 => ("deposit", client, Some("0".to_string())),
+            3 => ("deposit", client, Some("NaN".to_string())),
+            4 => (
+                "deposit",
+                "not-a-client".to_string(),
+                Some("1.0".to_string()),
+            ),
+            _ => ("bogus", client, Some("1.0".to_string())),
+        };
+
+        Row {
+            transaction_type,
+            client,
+            tx,
+            amount,
+        }
+    }
+
+    fn write(&mut self, row: &Row) -> io::Result<()> {
+        match self.config.format {
+            OutputFormat::Csv => writeln!(
+                self.out,
+                "{}, {}, {}, {}",
+                row.transaction_type,
+                row.client,
+                row.tx,
+                row.amount.as_deref().unwrap_or("")
+            ),
+            OutputFormat::Jsonl => {
+                // Numbers are written as-is so the client/amount of a
+                // malformed row end up as strings the engine can't parse.
+                let client = json_value(&row.client);
+                let amount = row.amount.as_deref().map(json_value);
+                match amount {
+                    Some(amount) => writeln!(
+                        self.out,
+                        r#"{{"type":"{}","client":{},"tx":{},"amount":{}}}"#,
+                        row.transaction_type, client, row.tx, amount
+                    ),
+                    None => writeln!(
+                        self.out,
+                        r#"{{"type":"{}","client":{},"tx":{}}}"#,
+                        row.transaction_type, client, row.tx
+                    ),
+                }
+            }
+        }
+    }
+}
+
+fn effect_row(transaction_type: &'static str, client_id: ClientId, tx: TransactionId) -> Row {
+    Row {
+        transaction_type,
+        client: client_id.to_string(),
+        tx,
+        amount: None,
+    }
+}
+
+/// The generator only writes rows in the default asset.
+fn default_balance(client: &mut Client) -> &mut Balance {
+    client.balances.entry(Asset::DEFAULT).or_default()
+}
+
+fn take_random(rng: &mut ChaCha8Rng, ids: &mut Vec<TransactionId>) -> Option<TransactionId> {
+    if ids.is_empty() {
+        return None;
+    }
+    let index = rng.random_range(0..ids.len());
+    Some(ids.swap_remove(index))
+}
+
+fn json_value(value: &str) -> String {
+    if value.parse::<f64>().is_ok_and(f64::is_finite) {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::history::NoHistory;
+    use crate::ingest::InputFormat;
+    use crate::process::process_transactions;
+    use crate::processed_transaction::ProcessedTransaction;
+
+    fn process(bytes: &[u8], format: InputFormat) -> HashMap<ClientId, Client> {
+        let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+        process_transactions(
+            bytes,
+            format,
+            &Config::default(),
+            &mut transactions,
+            &mut clients,
+            &mut NoHistory,
+        )
+        .unwrap();
+        clients
+    }
+
+    fn assert_same_accounts(
+        actual: &HashMap<ClientId, Client>,
+        expected: &HashMap<ClientId, Client>,
+    ) {
+        assert_eq!(actual.len(), expected.len());
+        for (client_id, expected) in expected {
+            let actual = &actual[client_id];
+            assert_eq!(
+                actual.balance(Asset::DEFAULT),
+                expected.balance(Asset::DEFAULT),
+                "client {}",
+                client_id
+            );
+            assert_eq!(actual.state, expected.state, "client {}", client_id);
+            assert_eq!(actual.reason, expected.reason, "client {}", client_id);
+        }
+    }
+
+    #[test]
+    fn test_generated_stream_matches_expected_accounts() {
+        // Cranked up rates so every kind of row shows up plenty of times.
+        let config = GeneratorConfig {
+            rows: 20_000,
+            clients: 50,
+            withdrawal_rate: 0.4,
+            dispute_rate: 0.1,
+            resolve_rate: 0.04,
+            chargeback_rate: 0.01,
+            duplicate_rate: 0.02,
+            malformed_rate: 0.02,
+            ..GeneratorConfig::default()
+        };
+
+        for (format, input_format) in [
+            (OutputFormat::Csv, InputFormat::Csv),
+            (OutputFormat::Jsonl, InputFormat::Jsonl),
+        ] {
+            let config = GeneratorConfig {
+                format,
+                ..config.clone()
+            };
+            let mut bytes = Vec::new();
+            let expected = generate(&config, &mut bytes).unwrap();
+
+            assert!(expected.values().any(|client| client.is_locked()));
+            assert_same_accounts(&process(&bytes, input_format), &expected);
+
+            // Same seed, same stream
+            let mut again = Vec::new();
+            generate(&config, &mut again).unwrap();
+            assert_eq!(bytes, again);
+        }
+    }
+}
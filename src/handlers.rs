@@ -1,674 +1,1109 @@
-use crate::client::Client;
-use crate::convert::convert_fractional_to_number;
+use crate::asset::Asset;
+use crate::client::{Client, ClientError};
+use crate::config::Config;
+use crate::convert::{RawAmount, UnitsError};
+use crate::fees::FeeCharge;
+use crate::fraud::FraudAlert;
+use crate::ids::{ClientId, RawId, TransactionId};
+use crate::lifecycle::AccountState;
 use crate::processed_transaction::{DisputeStatus, ProcessedTransaction, ProcessedTransactionType};
 use crate::raw_transaction::{RawTransaction, RawTransactionType};
-use std::collections::HashMap;
+use crate::rejection::Rejection;
+use crate::store::{AccountStore, StoreError, TransactionStore};
+use crate::trace;
+
+/// Why `handle_transaction` didn't apply a row.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandleError {
+    /// The row was bad or broke a rule. Counted, and the run carries on.
+    Rejected(Rejection),
+    /// One of the stores failed. Nothing sensible to do but stop.
+    Store(StoreError),
+}
 
-/// Takes in a raw transaction that should be a deposit,
-/// a mutable reference to a hashmap of transactions,
-/// and a mutable reference to a hashmap of clients.
-/// Modifies the hash maps to reflect the transaction/effect.
+impl std::fmt::Display for HandleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HandleError::Rejected(rejection) => write!(f, "{}", rejection),
+            HandleError::Store(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for HandleError {}
+
+impl From<Rejection> for HandleError {
+    fn from(rejection: Rejection) -> Self {
+        HandleError::Rejected(rejection)
+    }
+}
+
+impl From<ClientError> for HandleError {
+    fn from(e: ClientError) -> Self {
+        HandleError::Rejected(e.into())
+    }
+}
+
+impl From<StoreError> for HandleError {
+    fn from(e: StoreError) -> Self {
+        HandleError::Store(e)
+    }
+}
+
+/// What applying a row did, besides changing balances and transactions.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Applied {
+    /// See `FraudAlert`, only ever for chargebacks.
+    pub fraud_alert: Option<FraudAlert>,
+    /// What the row's client paid for it, see `FeeSchedule`.
+    pub fee: Option<FeeCharge>,
+    /// Only ever for chargebacks.
+    pub chargeback: Option<Chargeback>,
+}
+
+/// A chargeback that went through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chargeback {
+    /// See `ProcessedTransaction::reason_code`.
+    pub reason_code: Option<String>,
+    pub asset: Asset,
+    /// In units of the asset's smallest fraction.
+    pub amount: i128,
+}
+
+/// Takes in a raw transaction, the engine configuration,
+/// a mutable reference to a store of transactions,
+/// and a mutable reference to a store of clients.
+/// Modifies both to reflect the transaction/effect.
+///
+/// Malformed rows and rows that break a rule are returned as a `Rejection`
+/// and leave both untouched.
+///
+/// A chargeback comes back with what it took away, and with a `FraudAlert`
+/// if it was of a deposit or transfer that was partly or fully withdrawn
+/// already. Rows that were charged a fee come back with what it was.
 pub fn handle_transaction(
     raw_tx: &RawTransaction,
-    transactions: &mut HashMap<u32, ProcessedTransaction>,
-    clients: &mut HashMap<u16, Client>,
-) {
-    match raw_tx.transaction_type {
-        RawTransactionType::Deposit => {
-            handle_deposit(raw_tx, transactions, clients);
-        }
-        RawTransactionType::Withdrawal => {
-            handle_withdrawal(raw_tx, transactions, clients);
-        }
+    config: &Config,
+    transactions: &mut impl TransactionStore,
+    clients: &mut impl AccountStore,
+) -> Result<Applied, HandleError> {
+    let fee = match raw_tx.transaction_type {
+        RawTransactionType::Deposit => handle_deposit(raw_tx, config, transactions, clients)?,
+        RawTransactionType::Withdrawal => handle_withdrawal(raw_tx, config, transactions, clients)?,
         RawTransactionType::Dispute => {
-            handle_dispute(raw_tx, transactions, clients);
+            handle_dispute(raw_tx, config, transactions, clients)?;
+            None
         }
         RawTransactionType::Resolve => {
-            handle_resolve(raw_tx, transactions, clients);
+            handle_resolve(raw_tx, config, transactions, clients)?;
+            None
         }
         RawTransactionType::Chargeback => {
-            handle_chargeback(raw_tx, transactions, clients);
+            return handle_chargeback(raw_tx, config, transactions, clients);
         }
-    }
+        RawTransactionType::Refund => {
+            handle_refund(raw_tx, config, transactions, clients)?;
+            None
+        }
+        RawTransactionType::Reversal => {
+            handle_reversal(raw_tx, transactions, clients)?;
+            None
+        }
+        RawTransactionType::Transfer => handle_transfer(raw_tx, config, transactions, clients)?,
+        RawTransactionType::Fee => Some(handle_fee(raw_tx, config, transactions, clients)?),
+        RawTransactionType::Clear => {
+            handle_clear(raw_tx, config, transactions, clients)?;
+            None
+        }
+        RawTransactionType::Activate => {
+            handle_state_change(raw_tx, AccountState::Active, clients)?;
+            None
+        }
+        RawTransactionType::Freeze => {
+            handle_state_change(raw_tx, AccountState::Frozen, clients)?;
+            None
+        }
+        RawTransactionType::Dormant => {
+            handle_state_change(raw_tx, AccountState::Dormant, clients)?;
+            None
+        }
+        RawTransactionType::Close => {
+            handle_state_change(raw_tx, AccountState::Closed, clients)?;
+            None
+        }
+    };
+    Ok(Applied {
+        fee,
+        ..Applied::default()
+    })
 }
 
-/// Takes in a raw transaction that should be a deposit,
-/// a mutable reference to a hashmap of transactions,
-/// and a mutable reference to a hashmap of clients.
-/// Modifies the hash maps to reflect the deposit.
+/// Deposits, withdrawals and transfers must carry a finite, strictly
+/// positive amount that stays within the configured per-transaction maximum,
+/// with no more decimals than their asset has (see `AssetRegistry`).
 ///
-/// Not the most testable or functional function. I don't love it
-/// but I'd rather move fast and we can test at the "integration" layer.
-fn handle_deposit(
+/// Returns it in units of the asset's smallest fraction.
+pub fn transfer_amount(
     raw_tx: &RawTransaction,
-    transactions: &mut HashMap<u32, ProcessedTransaction>,
-    clients: &mut HashMap<u16, Client>,
-) {
-    if raw_tx.transaction_type != RawTransactionType::Deposit {
-        panic!("You should never pass an invalid transaction type to handle_deposit")
+    config: &Config,
+    asset: Asset,
+) -> Result<i128, Rejection> {
+    let raw_amount = raw_tx.amount.ok_or(Rejection::MissingAmount)?;
+    let units = amount_units(raw_amount, config, asset)?;
+    if let (Some(max_amount), RawAmount::Decimal(amount)) = (config.max_amount, raw_amount)
+        && amount > max_amount
+    {
+        return Err(Rejection::AmountTooLarge);
     }
+    Ok(units)
+}
 
-    eprintln!("Found a deposit with ID {}.", raw_tx.transaction_id);
+/// Effects may carry an amount to only act on part of the transaction,
+/// in its asset. Same rules as for deposits and withdrawals, but the
+/// per-transaction maximum doesn't apply.
+pub fn effect_amount(
+    raw_tx: &RawTransaction,
+    config: &Config,
+    asset: Asset,
+) -> Result<Option<i128>, Rejection> {
+    raw_tx
+        .amount
+        .map(|raw_amount| amount_units(raw_amount, config, asset))
+        .transpose()
+}
 
-    let amount =
-        convert_fractional_to_number(raw_tx.amount.expect("Deposit/Withdrawal must have amount"));
+fn amount_units(raw_amount: RawAmount, config: &Config, asset: Asset) -> Result<i128, Rejection> {
+    let amount = match raw_amount {
+        RawAmount::Decimal(amount) => amount,
+        RawAmount::NonFinite => return Err(Rejection::NonFiniteAmount),
+        RawAmount::TooLarge => return Err(Rejection::Overflow),
+    };
+
+    let decimals = config.assets.decimals(asset);
+    let units = amount.to_units(decimals).map_err(|e| match e {
+        UnitsError::TooPrecise => Rejection::TooPrecise,
+        UnitsError::Overflow => Rejection::Overflow,
+    })?;
+    if units <= 0 {
+        return Err(Rejection::NonPositiveAmount);
+    }
+    Ok(units)
+}
 
-    let client = clients
-        .entry(raw_tx.client_id)
-        .or_insert(Client::new(raw_tx.client_id));
+/// The client and transaction a row is about.
+pub fn row_ids(raw_tx: &RawTransaction) -> Result<(ClientId, TransactionId), Rejection> {
+    match (raw_tx.client_id, raw_tx.transaction_id) {
+        (RawId::Id(client_id), RawId::Id(transaction_id)) => Ok((client_id, transaction_id)),
+        _ => Err(Rejection::IdOutOfRange),
+    }
+}
 
-    let result = client.deposit(amount);
-    if result.is_err() {
-        eprintln!(
-            "Error depositing amount {} for client {}: {}",
-            amount,
-            raw_tx.client_id,
-            result.err().unwrap()
-        );
-        return;
+/// Who a transfer or fee is to.
+fn row_destination(raw_tx: &RawTransaction) -> Result<ClientId, Rejection> {
+    match raw_tx.destination {
+        Some(RawId::Id(to)) => Ok(to),
+        Some(RawId::OutOfRange) => Err(Rejection::IdOutOfRange),
+        None => Err(Rejection::MissingDestination),
     }
+}
 
-    if transactions.contains_key(&raw_tx.transaction_id) {
-        // I want to ignore them because overwriting
-        // would mean we lose any effects we've previously applied.
-        eprintln!(
-            "Ignoring duplicate transaction ID {}",
-            raw_tx.transaction_id
-        );
-    } else {
-        let transaction = ProcessedTransaction::new(
-            raw_tx.transaction_id,
-            raw_tx.client_id,
-            amount,
-            ProcessedTransactionType::Deposit,
-        );
+/// The asset a row is in, the default one if it doesn't say.
+pub fn row_asset(raw_tx: &RawTransaction) -> Result<Asset, Rejection> {
+    match raw_tx.asset.as_deref() {
+        Some(code) => Asset::new(code).ok_or(Rejection::InvalidAsset),
+        None => Ok(Asset::DEFAULT),
+    }
+}
+
+/// The client a deposit, withdrawal or transfer is from, created if we've
+/// never seen it.
+///
+/// A new client is stored straight away, so like before it shows up in
+/// the output even if the row that introduced it gets rejected.
+fn client_or_insert(
+    clients: &mut impl AccountStore,
+    client_id: ClientId,
+) -> Result<Client, StoreError> {
+    if let Some(client) = clients.get(client_id)? {
+        return Ok(client);
+    }
+    let client = Client::new(client_id);
+    clients.insert(client_id, client.clone())?;
+    Ok(client)
+}
+
+/// Has `payer` pay the fee for a row of `amount`, if there is one (see
+/// `FeeSchedule`), as much of it as it can out of the row's `pending` funds
+/// and going at most `overdraft` below zero for the rest.
+///
+/// Only the payer's copy changes, so a fee they can't pay rejects the row
+/// along with it. The house gets it once the row's clients are stored,
+/// see `collect_fee`.
+fn pay_fee(
+    raw_tx: &RawTransaction,
+    config: &Config,
+    payer: &mut Client,
+    asset: Asset,
+    amount: i128,
+    pending: i128,
+    overdraft: i128,
+) -> Result<Option<FeeCharge>, HandleError> {
+    let (_, transaction_id) = row_ids(raw_tx)?;
+    let fee = config
+        .fees
+        .fee(payer.client_id(), &raw_tx.transaction_type, asset, amount);
+    if fee == 0 {
+        return Ok(None);
+    }
+    payer.pay_fee(asset, fee, fee.min(pending), overdraft)?;
+    Ok(Some(FeeCharge {
+        client_id: payer.client_id(),
+        transaction_id,
+        house: config.fees.house(),
+        asset,
+        amount: fee,
+    }))
+}
 
-        transactions.insert(raw_tx.transaction_id, transaction);
+/// Pays a fee into the house account. Only once every other client the row
+/// changed is stored, since the house may well be one of them.
+fn collect_fee(
+    clients: &mut impl AccountStore,
+    fee: Option<&FeeCharge>,
+) -> Result<(), HandleError> {
+    if let Some(fee) = fee {
+        let mut house = clients
+            .get(fee.house)?
+            .unwrap_or_else(|| Client::new(fee.house));
+        house.collect_fee(fee.asset, fee.amount)?;
+        clients.insert(fee.house, house)?;
     }
+    Ok(())
+}
+
+/// Takes in a raw transaction that should be a deposit,
+/// a mutable reference to a store of transactions,
+/// and a mutable reference to a store of clients.
+/// Modifies both to reflect the deposit.
+///
+/// Not the most testable or functional function. I don't love it
+/// but I'd rather move fast and we can test at the "integration" layer.
+fn handle_deposit(
+    raw_tx: &RawTransaction,
+    config: &Config,
+    transactions: &mut impl TransactionStore,
+    clients: &mut impl AccountStore,
+) -> Result<Option<FeeCharge>, HandleError> {
+    let (client_id, transaction_id) = row_ids(raw_tx)?;
+    trace!("Found a deposit with ID {}.", transaction_id);
+
+    let asset = row_asset(raw_tx)?;
+    let amount = transfer_amount(raw_tx, config, asset)?;
+
+    // I want to ignore duplicates because overwriting
+    // would mean we lose any effects we've previously applied.
+    if transactions.contains(transaction_id)? {
+        return Err(Rejection::DuplicateTransaction.into());
+    }
+
+    let mut client = client_or_insert(clients, client_id)?;
+    client.deposit(asset, amount, config.clearing.is_some())?;
+    // A deposit that has to clear pays its fee out of itself, since the
+    // client may well not have anything available yet.
+    let mut pending = if config.clearing.is_some() { amount } else { 0 };
+    let overdraft = config.overdrafts.limit(client_id, asset);
+    let fee = pay_fee(
+        raw_tx,
+        config,
+        &mut client,
+        asset,
+        amount,
+        pending,
+        overdraft,
+    )?;
+    pending -= fee.as_ref().map_or(0, |fee| fee.amount.min(pending));
+    clients.insert(client_id, client)?;
+    collect_fee(clients, fee.as_ref())?;
+
+    transactions.insert(
+        transaction_id,
+        ProcessedTransaction {
+            timestamp: raw_tx.timestamp,
+            asset,
+            pending,
+            ..ProcessedTransaction::new(client_id, amount, ProcessedTransactionType::Deposit)
+        },
+    )?;
+    Ok(fee)
 }
 
 /// Takes in a raw transaction that should be a withdrawal,
-/// a mutable reference to a hashmap of transactions,
-/// and a mutable reference to a hashmap of clients.
-/// Modifies the hash maps to reflect the deposit.
+/// a mutable reference to a store of transactions,
+/// and a mutable reference to a store of clients.
+/// Modifies both to reflect the withdrawal.
 ///
 /// Not the most testable or functional function. I don't love it
 /// but I'd rather move fast and we can test at the "integration" layer.
 fn handle_withdrawal(
     raw_tx: &RawTransaction,
-    transactions: &mut HashMap<u32, ProcessedTransaction>,
-    clients: &mut HashMap<u16, Client>,
-) {
-    if raw_tx.transaction_type != RawTransactionType::Withdrawal {
-        panic!("You should never pass an invalid transaction type to handle_withdrawal")
+    config: &Config,
+    transactions: &mut impl TransactionStore,
+    clients: &mut impl AccountStore,
+) -> Result<Option<FeeCharge>, HandleError> {
+    let (client_id, transaction_id) = row_ids(raw_tx)?;
+    trace!("Found a withdrawal with ID {}.", transaction_id);
+
+    let asset = row_asset(raw_tx)?;
+    let amount = transfer_amount(raw_tx, config, asset)?;
+
+    // Same as for deposits, a duplicate must not overwrite the original.
+    if transactions.contains(transaction_id)? {
+        return Err(Rejection::DuplicateTransaction.into());
     }
 
-    eprintln!("Found a withdrawal with ID {}.", raw_tx.transaction_id);
+    let mut client = client_or_insert(clients, client_id)?;
+    let overdraft = config.overdrafts.limit(client_id, asset);
+    client.withdraw(asset, amount, overdraft)?;
+    let fee = pay_fee(raw_tx, config, &mut client, asset, amount, 0, overdraft)?;
+    clients.insert(client_id, client)?;
+    collect_fee(clients, fee.as_ref())?;
+
+    transactions.insert(
+        transaction_id,
+        ProcessedTransaction {
+            timestamp: raw_tx.timestamp,
+            asset,
+            ..ProcessedTransaction::new(client_id, amount, ProcessedTransactionType::Withdrawal)
+        },
+    )?;
+    Ok(fee)
+}
 
-    let amount =
-        convert_fractional_to_number(raw_tx.amount.expect("Deposit/Withdrawal must have amount"));
+/// Takes in a raw transaction that should be a transfer,
+/// a mutable reference to a store of transactions,
+/// and a mutable reference to a store of clients.
+/// Moves the amount from the row's client to the one in `to`.
+///
+/// Both sides go through or neither does: the sender is held to the same
+/// limits as for a withdrawal, and the transfer is rejected unless the
+/// sender's account allows withdrawals and the recipient's deposits (see
+/// `AccountState`). The funds are the sender's to dispute, as with a withdrawal,
+/// but it's the recipient's account that a dispute holds them in.
+fn handle_transfer(
+    raw_tx: &RawTransaction,
+    config: &Config,
+    transactions: &mut impl TransactionStore,
+    clients: &mut impl AccountStore,
+) -> Result<Option<FeeCharge>, HandleError> {
+    let (client_id, transaction_id) = row_ids(raw_tx)?;
+    trace!("Found a transfer with ID {}.", transaction_id);
+
+    let to = row_destination(raw_tx)?;
+    if to == client_id {
+        return Err(Rejection::SelfTransfer.into());
+    }
 
-    let client = clients
-        .entry(raw_tx.client_id)
-        .or_insert(Client::new(raw_tx.client_id));
+    let asset = row_asset(raw_tx)?;
+    let amount = transfer_amount(raw_tx, config, asset)?;
 
-    let result = client.withdraw(amount);
-    if result.is_err() {
-        eprintln!(
-            "Error withdrawing from client {} with amount {}: {}",
-            raw_tx.client_id,
-            amount,
-            result.err().unwrap()
-        );
-        return;
+    // Same as for deposits, a duplicate must not overwrite the original.
+    if transactions.contains(transaction_id)? {
+        return Err(Rejection::DuplicateTransaction.into());
     }
 
-    if transactions.contains_key(&raw_tx.transaction_id) {
-        // I want to ignore them because overwriting
-        // would mean we lose any effects we've previously applied.
-        eprintln!(
-            "Ignoring duplicate transaction ID {}",
-            raw_tx.transaction_id
-        );
+    let mut sender = client_or_insert(clients, client_id)?;
+    // Unlike the sender, a recipient we've never seen only gets stored
+    // if the transfer goes through.
+    let mut recipient = clients.get(to)?.unwrap_or_else(|| Client::new(to));
+    let overdraft = config.overdrafts.limit(client_id, asset);
+    sender.withdraw(asset, amount, overdraft)?;
+    let fee = pay_fee(raw_tx, config, &mut sender, asset, amount, 0, overdraft)?;
+    // They're already cleared, so they don't go into pending.
+    recipient.deposit(asset, amount, false)?;
+    clients.insert(client_id, sender)?;
+    clients.insert(to, recipient)?;
+    collect_fee(clients, fee.as_ref())?;
+
+    transactions.insert(
+        transaction_id,
+        ProcessedTransaction {
+            timestamp: raw_tx.timestamp,
+            asset,
+            ..ProcessedTransaction::new(
+                client_id,
+                amount,
+                ProcessedTransactionType::Transfer { to },
+            )
+        },
+    )?;
+    Ok(fee)
+}
+
+/// A fee of `amount` paid into the account in `to`, for the transaction
+/// in `tx`. That's how the fees the handlers charge show up in the history,
+/// but it may just as well be in the input, for a fee that isn't part of
+/// the schedule.
+///
+/// A fee was already agreed on, so it always goes through: accounts pay it
+/// whatever their state and it may take the client as far below zero as it
+/// needs to.
+fn handle_fee(
+    raw_tx: &RawTransaction,
+    config: &Config,
+    transactions: &mut impl TransactionStore,
+    clients: &mut impl AccountStore,
+) -> Result<FeeCharge, HandleError> {
+    let (client_id, transaction_id) = row_ids(raw_tx)?;
+    trace!("Found a fee for transaction with ID {}.", transaction_id);
+
+    let house = row_destination(raw_tx)?;
+    if house == client_id {
+        return Err(Rejection::SelfTransfer.into());
+    }
+    let asset = row_asset(raw_tx)?;
+    let amount = effect_amount(raw_tx, config, asset)?.ok_or(Rejection::MissingAmount)?;
+    let mut tx = transactions
+        .get(transaction_id)?
+        .ok_or(Rejection::UnknownTransaction)?;
+
+    let mut client = clients.get(client_id)?.ok_or(Rejection::UnknownClient)?;
+    // Like a scheduled deposit fee, out of the deposit if it hasn't cleared.
+    let from_pending = if tx.client_id == client_id && tx.asset == asset {
+        amount
+            .min(tx.pending)
+            .min(client.balance(asset).pending)
+            .max(0)
     } else {
-        let transaction = ProcessedTransaction::new(
-            raw_tx.transaction_id,
-            raw_tx.client_id,
-            amount,
-            ProcessedTransactionType::Withdrawal,
-        );
-        transactions.insert(raw_tx.transaction_id, transaction);
+        0
+    };
+    client.pay_fee(asset, amount, from_pending, i128::MAX)?;
+    clients.insert(client_id, client)?;
+    if from_pending > 0 {
+        tx.pending -= from_pending;
+        transactions.insert(transaction_id, tx)?;
     }
+    let fee = FeeCharge {
+        client_id,
+        transaction_id,
+        house,
+        asset,
+        amount,
+    };
+    collect_fee(clients, Some(&fee))?;
+    Ok(fee)
 }
 
-fn handle_dispute(
+/// Makes available at most `amount` of the client's pending funds in the
+/// row's asset. That's how clearing (see `Config::clearing`) shows up in
+/// the history, but it may just as well be in the input, to clear funds
+/// early.
+///
+/// Like clearing, it goes through whatever the account's state. The row's
+/// `tx` is the deposit's if it's known, which then has that much less left
+/// to clear. It's not checked otherwise, nor stored.
+fn handle_clear(
     raw_tx: &RawTransaction,
-    transactions: &mut HashMap<u32, ProcessedTransaction>,
-    clients: &mut HashMap<u16, Client>,
-) {
-    if raw_tx.transaction_type != RawTransactionType::Dispute {
-        panic!("You should never pass an invalid transaction type to handle_dispute")
+    config: &Config,
+    transactions: &mut impl TransactionStore,
+    clients: &mut impl AccountStore,
+) -> Result<(), HandleError> {
+    let (client_id, transaction_id) = row_ids(raw_tx)?;
+    trace!("Found a clear for transaction with ID {}.", transaction_id);
+
+    let asset = row_asset(raw_tx)?;
+    let amount = effect_amount(raw_tx, config, asset)?.ok_or(Rejection::MissingAmount)?;
+    let mut client = clients.get(client_id)?.ok_or(Rejection::UnknownClient)?;
+    let cleared = client.clear(asset, amount);
+    clients.insert(client_id, client)?;
+    if let Some(mut tx) = transactions.get(transaction_id)?
+        && tx.client_id == client_id
+        && tx.asset == asset
+        && tx.pending > 0
+    {
+        tx.pending -= cleared.min(tx.pending);
+        transactions.insert(transaction_id, tx)?;
     }
+    Ok(())
+}
 
-    eprintln!(
-        "Found a dispute for transaction with ID {}.",
-        raw_tx.transaction_id
-    );
-
-    if !clients.contains_key(&raw_tx.client_id) {
-        // This is an easy skip, if the client doesn't exist it means a transaction
-        // doesn't exist so the effect cannot be applied.
-        // This is safe because the transactions are fed to the system chronologically
-        // so a client should exist if they had a transaction before.
-        eprintln!(
-            "Client with ID {} not found while handling effect for tx {}.",
-            raw_tx.client_id, raw_tx.transaction_id
-        );
-        return;
+/// Finds the transaction an effect (dispute/resolve/chargeback/refund/reversal) refers to
+/// along with the client holding its funds (see `ProcessedTransaction::holder`).
+///
+/// Effects act on the referenced transaction in its asset, on as much of
+/// it as they can unless they carry an amount (see `effect_amount`). An
+/// effect may name the asset, but it has to be that one. Both are copies,
+/// callers have to put them back in their stores once they've changed them.
+fn effect_target(
+    raw_tx: &RawTransaction,
+    transactions: &impl TransactionStore,
+    clients: &impl AccountStore,
+) -> Result<(ProcessedTransaction, Client), HandleError> {
+    let (client_id, transaction_id) = row_ids(raw_tx)?;
+
+    // This is an easy skip, if the client doesn't exist it means a transaction
+    // doesn't exist so the effect cannot be applied.
+    // This is safe because the transactions are fed to the system chronologically
+    // so a client should exist if they had a transaction before.
+    let client = clients.get(client_id)?.ok_or(Rejection::UnknownClient)?;
+
+    // This is also an easy skip, if the transaction doesn't exist it means a transaction
+    // doesn't exist so the effect cannot be applied.
+    // This is safe because the transactions are fed to the system chronologically
+    // so the transaction should exist if an effect came in from the CSV.
+    let tx = transactions
+        .get(transaction_id)?
+        .ok_or(Rejection::UnknownTransaction)?;
+
+    if client_id != tx.client_id {
+        return Err(Rejection::ClientMismatch.into());
+    }
+    if raw_tx.asset.is_some() && row_asset(raw_tx)? != tx.asset {
+        return Err(Rejection::AssetMismatch.into());
     }
 
-    if !transactions.contains_key(&raw_tx.transaction_id) {
-        // This is also an easy skip, if the transaction doesn't exist it means a transaction
-        // doesn't exist so the effect cannot be applied.
-        // This is safe because the transactions are fed to the system chronologically
-        // so the transaction should exist if an effect came in from the CSV.
-        eprintln!(
-            "Transaction with ID {} not found while handling effect for tx {}.",
-            raw_tx.transaction_id, raw_tx.transaction_id
-        );
-        return;
+    if tx.holder() != client_id {
+        let holder = clients.get(tx.holder())?.ok_or(Rejection::UnknownClient)?;
+        return Ok((tx, holder));
     }
+    Ok((tx, client))
+}
 
-    // I know unwrap is discouraged cause it can panic, but we
-    // just checked that the transaction exists
-    let tx = transactions.get_mut(&raw_tx.transaction_id).unwrap();
+/// Disputes are only accepted within `config.dispute_window` of the
+/// transaction, when both have a timestamp to tell, and only if they
+/// don't take the client further below zero than `config.dispute_overdraft`
+/// allows.
+///
+/// A dispute without an amount is for whatever of the transaction isn't
+/// disputed yet. One with an amount may be joined by more later on, as long
+/// as they don't add up to more than the transaction. Whatever of it wasn't
+/// charged back or refunded stays disputable, whether an earlier dispute
+/// was resolved or not (see `ProcessedTransaction::undisputed`).
+fn handle_dispute(
+    raw_tx: &RawTransaction,
+    config: &Config,
+    transactions: &mut impl TransactionStore,
+    clients: &mut impl AccountStore,
+) -> Result<(), HandleError> {
+    let (_, transaction_id) = row_ids(raw_tx)?;
+    trace!(
+        "Found a dispute for transaction with ID {}.",
+        transaction_id
+    );
 
-    if raw_tx.client_id != tx.client_id {
-        eprintln!(
-            "Client {} cannot dispute transaction {} which belongs to client {}",
-            raw_tx.client_id, raw_tx.transaction_id, tx.client_id
-        );
-        return;
-    }
+    let (mut tx, mut client) = effect_target(raw_tx, transactions, clients)?;
 
-    if tx.dispute_status != DisputeStatus::Valid {
-        eprintln!(
-            "Failed to dispute transaction with ID {} because it is not valid.",
-            raw_tx.transaction_id
-        );
-        return;
+    let undisputed = tx.undisputed();
+    if undisputed == 0 {
+        return Err(Rejection::NotDisputable.into());
     }
-
-    // I know unwrap is discouraged cause it can panic, but we
-    // just checked that the client exists
-    let client = clients.get_mut(&tx.client_id).unwrap();
-    let result = client.apply_dispute(tx.amount);
-    if result.is_err() {
-        eprintln!(
-            "Failed to dispute transaction with ID {}: {}",
-            raw_tx.transaction_id,
-            result.err().unwrap()
-        );
-        return;
+    if let (Some(window), Some(disputed_at), Some(happened_at)) =
+        (config.dispute_window, raw_tx.timestamp, tx.timestamp)
+        && disputed_at.saturating_sub(happened_at) > window
+    {
+        return Err(Rejection::DisputeWindowExpired.into());
+    }
+    let amount = effect_amount(raw_tx, config, tx.asset)?.unwrap_or(undisputed);
+    if amount > undisputed {
+        return Err(Rejection::ExceedsUndisputed.into());
     }
 
+    // Funds that haven't cleared yet are held out of pending, so they
+    // don't clear while under dispute and aren't taken for spent ones by a
+    // chargeback. Pending funds carried over from an earlier run may well
+    // have cleared since, whatever the deposit says.
+    let from_pending = amount
+        .min(tx.pending)
+        .min(client.balance(tx.asset).pending)
+        .max(0);
+    let overdraft = config
+        .dispute_overdraft
+        .limit(&config.overdrafts, tx.holder(), tx.asset);
+    client.apply_dispute(tx.asset, amount, from_pending, overdraft)?;
+    clients.insert(tx.holder(), client)?;
+    tx.pending -= from_pending;
     tx.dispute_status = DisputeStatus::Disputed;
+    tx.disputed += amount;
+    tx.disputed_at = raw_tx.timestamp.or(tx.disputed_at);
+    record_reason_code(raw_tx, &mut tx);
+    transactions.insert(transaction_id, tx)?;
+    Ok(())
 }
 
-fn handle_resolve(
+/// Disputes, resolves and chargebacks may come with a reason code, which
+/// the transaction keeps until the next one that has one.
+fn record_reason_code(raw_tx: &RawTransaction, tx: &mut ProcessedTransaction) {
+    if raw_tx.reason.is_some() {
+        tx.reason_code = raw_tx.reason.clone();
+    }
+}
+
+/// What a resolve or chargeback acts on: everything under dispute, or
+/// the part of it the row asks for.
+fn disputed_amount(
     raw_tx: &RawTransaction,
-    transactions: &mut HashMap<u32, ProcessedTransaction>,
-    clients: &mut HashMap<u16, Client>,
-) {
-    if raw_tx.transaction_type != RawTransactionType::Resolve {
-        panic!("You should never pass an invalid transaction type to handle_resolve")
+    config: &Config,
+    tx: &ProcessedTransaction,
+) -> Result<i128, Rejection> {
+    if tx.dispute_status != DisputeStatus::Disputed {
+        return Err(Rejection::NotDisputed);
+    }
+    let amount = effect_amount(raw_tx, config, tx.asset)?.unwrap_or(tx.disputed);
+    if amount > tx.disputed {
+        return Err(Rejection::ExceedsDisputed);
     }
+    Ok(amount)
+}
 
-    eprintln!(
+/// Releases what's under dispute, or only `amount` of it. The transaction
+/// is resolved once nothing is left under dispute (see
+/// `ProcessedTransaction::settle`).
+///
+/// Settling a dispute that's already open isn't up to the client, so it
+/// goes through whatever the account's state, a lock by the chargeback of
+/// another part of the transaction included.
+fn handle_resolve(
+    raw_tx: &RawTransaction,
+    config: &Config,
+    transactions: &mut impl TransactionStore,
+    clients: &mut impl AccountStore,
+) -> Result<(), HandleError> {
+    let (_, transaction_id) = row_ids(raw_tx)?;
+    trace!(
         "Found a resolve for transaction with ID {}.",
-        raw_tx.transaction_id
+        transaction_id
     );
 
-    if !clients.contains_key(&raw_tx.client_id) {
-        // This is an easy skip, if the client doesn't exist it means a transaction
-        // doesn't exist so the effect cannot be applied.
-        // This is safe because the transactions are fed to the system chronologically
-        // so a client should exist if they had a transaction before.
-        eprintln!(
-            "Client with ID {} not found while handling effect for tx {}.",
-            raw_tx.client_id, raw_tx.transaction_id
-        );
-        return;
-    }
+    let (mut tx, mut client) = effect_target(raw_tx, transactions, clients)?;
 
-    if !transactions.contains_key(&raw_tx.transaction_id) {
-        // This is also an easy skip, if the transaction doesn't exist it means a transaction
-        // doesn't exist so the effect cannot be applied.
-        // This is safe because the transactions are fed to the system chronologically
-        // so the transaction should exist if an effect came in from the CSV.
-        eprintln!(
-            "Transaction with ID {} not found while handling effect for tx {}.",
-            raw_tx.transaction_id, raw_tx.transaction_id
-        );
-        return;
-    }
+    let amount = disputed_amount(raw_tx, config, &tx)?;
 
-    // I know unwrap is discouraged cause it can panic, but we
-    // just checked that the transaction exists
-    let tx = transactions.get_mut(&raw_tx.transaction_id).unwrap();
+    client.apply_resolve(tx.asset, amount)?;
+    clients.insert(tx.holder(), client)?;
+    tx.disputed -= amount;
+    tx.settle();
+    record_reason_code(raw_tx, &mut tx);
+    transactions.insert(transaction_id, tx)?;
+    Ok(())
+}
 
-    if raw_tx.client_id != tx.client_id {
-        eprintln!(
-            "Client {} cannot resolve transaction {} which belongs to client {}",
-            raw_tx.client_id, raw_tx.transaction_id, tx.client_id
-        );
-        return;
-    }
+/// Like a resolve, except the funds are taken away and the account locked.
+/// The rest of the transaction stays disputable, and any other part of it
+/// under dispute can still be resolved or charged back.
+///
+/// For a transfer the funds are taken from the recipient's account and go
+/// back to the sender. It's the sender that gets locked though: they're the
+/// one who charged it back, like the client of a deposit is, while the
+/// recipient did nothing wrong but receive the funds.
+///
+/// Whoever gets locked pays the chargeback fee, however far below zero
+/// that takes them.
+fn handle_chargeback(
+    raw_tx: &RawTransaction,
+    config: &Config,
+    transactions: &mut impl TransactionStore,
+    clients: &mut impl AccountStore,
+) -> Result<Applied, HandleError> {
+    let (client_id, transaction_id) = row_ids(raw_tx)?;
+    trace!(
+        "Found a chargeback for transaction with ID {}.",
+        transaction_id
+    );
 
-    if tx.dispute_status != DisputeStatus::Disputed {
-        eprintln!(
-            "Failed to resolve transaction with ID {} because it is not disputed.",
-            raw_tx.transaction_id
-        );
-        return;
-    }
+    let (mut tx, mut client) = effect_target(raw_tx, transactions, clients)?;
 
-    // I know unwrap is discouraged cause it can panic, but we
-    // just checked that the client exists
-    let client = clients.get_mut(&tx.client_id).unwrap();
-    let result = client.apply_resolve(tx.amount);
-    if result.is_err() {
-        eprintln!(
-            "Error resolving transaction with ID {} for client {}: {}",
-            raw_tx.transaction_id,
-            raw_tx.client_id,
-            result.err().unwrap()
-        );
-        return;
+    let amount = disputed_amount(raw_tx, config, &tx)?;
+
+    let exposure = client.apply_chargeback(tx.asset, amount)?;
+    let mut sender = None;
+    if tx.holder() != client_id {
+        let mut recovering = clients.get(client_id)?.ok_or(Rejection::UnknownClient)?;
+        recovering.recover(tx.asset, amount)?;
+        sender = Some(recovering);
+    }
+    let charged = sender.as_mut().unwrap_or(&mut client);
+    charged.lock(transaction_id);
+    let fee = pay_fee(raw_tx, config, charged, tx.asset, amount, 0, i128::MAX)?;
+    clients.insert(tx.holder(), client)?;
+    if let Some(sender) = sender {
+        clients.insert(client_id, sender)?;
     }
+    collect_fee(clients, fee.as_ref())?;
+    tx.disputed -= amount;
+    tx.charged_back += amount;
+    tx.settle();
+    record_reason_code(raw_tx, &mut tx);
+    transactions.insert(transaction_id, tx.clone())?;
+
+    let chargeback = Some(Chargeback {
+        reason_code: tx.reason_code.clone(),
+        asset: tx.asset,
+        amount,
+    });
+    if tx.transaction_type == ProcessedTransactionType::Withdrawal || exposure == 0 {
+        return Ok(Applied {
+            fee,
+            chargeback,
+            ..Applied::default()
+        });
+    }
+    trace!(
+        "Client {} had {} of transaction {} charged back after withdrawing it",
+        tx.holder(),
+        exposure,
+        transaction_id
+    );
+    Ok(Applied {
+        fraud_alert: Some(FraudAlert {
+            client_id: tx.holder(),
+            transaction_id,
+            asset: tx.asset,
+            amount,
+            exposure,
+            at: raw_tx.timestamp,
+            reason_code: tx.reason_code,
+        }),
+        fee,
+        chargeback,
+    })
+}
 
-    tx.dispute_status = DisputeStatus::Resolved;
+/// Gives `amount` of a transaction back: a deposit is taken out of the
+/// client's available funds, at most `overdraft` below zero, a withdrawal
+/// is put back in. Transfers can't be given back, that takes two clients,
+/// so they're rejected as `NotRefundable`.
+///
+/// It's an effect, so it's up to the account's state whether effects go
+/// through, not deposits or withdrawals.
+fn give_back(
+    client: &mut Client,
+    tx: &ProcessedTransaction,
+    amount: i128,
+    overdraft: i128,
+) -> Result<bool, HandleError> {
+    Ok(match tx.transaction_type {
+        ProcessedTransactionType::Deposit => client.adjust(tx.asset, -amount, overdraft)?,
+        ProcessedTransactionType::Withdrawal => client.adjust(tx.asset, amount, overdraft)?,
+        ProcessedTransactionType::Transfer { .. } => {
+            return Err(Rejection::NotRefundable.into());
+        }
+    })
 }
 
-fn handle_chargeback(
+/// A merchant giving back (part of) a deposit or withdrawal. Without an
+/// amount it's for whatever is left to refund.
+///
+/// Only what isn't disputed or charged back can be refunded, and refunding
+/// a deposit is held to the same overdraft limit as a withdrawal would be.
+/// Transfers are between clients, not with a merchant, so they can't be refunded.
+fn handle_refund(
     raw_tx: &RawTransaction,
-    transactions: &mut HashMap<u32, ProcessedTransaction>,
-    clients: &mut HashMap<u16, Client>,
-) {
-    if raw_tx.transaction_type != RawTransactionType::Chargeback {
-        panic!("You should never pass an invalid transaction type to handle_chargeback")
+    config: &Config,
+    transactions: &mut impl TransactionStore,
+    clients: &mut impl AccountStore,
+) -> Result<(), HandleError> {
+    let (client_id, transaction_id) = row_ids(raw_tx)?;
+    trace!("Found a refund for transaction with ID {}.", transaction_id);
+
+    let (mut tx, mut client) = effect_target(raw_tx, transactions, clients)?;
+
+    let refundable = tx.undisputed();
+    if matches!(
+        tx.transaction_type,
+        ProcessedTransactionType::Transfer { .. }
+    ) || refundable == 0
+    {
+        return Err(Rejection::NotRefundable.into());
     }
-
-    eprintln!(
-        "Found a chargeback for transaction with ID {}.",
-        raw_tx.transaction_id
-    );
-
-    if !clients.contains_key(&raw_tx.client_id) {
-        // This is an easy skip, if the client doesn't exist it means a transaction
-        // doesn't exist so the effect cannot be applied.
-        // This is safe because the transactions are fed to the system chronologically
-        // so a client should exist if they had a transaction before.
-        eprintln!(
-            "Client with ID {} not found while handling effect for tx {}.",
-            raw_tx.client_id, raw_tx.transaction_id
-        );
-        return;
+    let amount = effect_amount(raw_tx, config, tx.asset)?.unwrap_or(refundable);
+    if amount > refundable {
+        return Err(Rejection::ExceedsRefundable.into());
     }
 
-    if !transactions.contains_key(&raw_tx.transaction_id) {
-        // This is also an easy skip, if the transaction doesn't exist it means a transaction
-        // doesn't exist so the effect cannot be applied.
-        // This is safe because the transactions are fed to the system chronologically
-        // so the transaction should exist if an effect came in from the CSV.
-        eprintln!(
-            "Transaction with ID {} not found while handling effect for tx {}.",
-            raw_tx.transaction_id, raw_tx.transaction_id
-        );
-        return;
-    }
+    give_back(
+        &mut client,
+        &tx,
+        amount,
+        config.overdrafts.limit(client_id, tx.asset),
+    )?;
+    clients.insert(client_id, client)?;
+    tx.refunded += amount;
+    transactions.insert(transaction_id, tx)?;
+    Ok(())
+}
 
-    // I know unwrap is discouraged cause it can panic, but we
-    // just checked that the transaction exists
-    let tx = transactions.get_mut(&raw_tx.transaction_id).unwrap();
+/// An operator undoing a transaction that should never have been posted,
+/// whatever wasn't refunded or charged back of it already.
+///
+/// Only deposits and withdrawals that aren't under dispute can be reversed.
+/// A correction has to go through, so reversing a deposit may take the
+/// client as far below zero as it needs to.
+fn handle_reversal(
+    raw_tx: &RawTransaction,
+    transactions: &mut impl TransactionStore,
+    clients: &mut impl AccountStore,
+) -> Result<(), HandleError> {
+    let (client_id, transaction_id) = row_ids(raw_tx)?;
+    trace!(
+        "Found a reversal for transaction with ID {}.",
+        transaction_id
+    );
 
-    if raw_tx.client_id != tx.client_id {
-        eprintln!(
-            "Client {} cannot chargeback transaction {} which belongs to client {}",
-            raw_tx.client_id, raw_tx.transaction_id, tx.client_id
-        );
-        return;
+    if raw_tx.amount.is_some() {
+        return Err(Rejection::UnexpectedAmount.into());
     }
-
-    if tx.dispute_status != DisputeStatus::Disputed {
-        eprintln!(
-            "Failed to chargeback transaction with ID {} because it is not disputed.",
-            raw_tx.transaction_id
-        );
-        return;
+    let (mut tx, mut client) = effect_target(raw_tx, transactions, clients)?;
+
+    let remaining = tx.amount - tx.refunded - tx.charged_back;
+    if tx.disputed > 0
+        || matches!(
+            tx.transaction_type,
+            ProcessedTransactionType::Transfer { .. }
+        )
+        || remaining == 0
+    {
+        return Err(Rejection::NotReversible.into());
     }
 
-    // I know unwrap is discouraged cause it can panic, but we
-    // just checked that the client exists
-    let client = clients.get_mut(&tx.client_id).unwrap();
-    let result = client.apply_chargeback(tx.amount);
-    if result.is_err() {
-        eprintln!(
-            "Error charging back transaction with ID {} for client {}: {}",
-            raw_tx.transaction_id,
-            raw_tx.client_id,
-            result.err().unwrap()
-        );
-        return;
-    }
+    give_back(&mut client, &tx, remaining, i128::MAX)?;
+    clients.insert(client_id, client)?;
+    tx.refunded += remaining;
+    transactions.insert(transaction_id, tx)?;
+    Ok(())
+}
 
-    tx.dispute_status = DisputeStatus::ChargedBack;
+/// An operator moving a client's account to another state `to`, for the
+/// row's reason if it has one. See `Client::transition` for which moves
+/// are allowed.
+///
+/// The row's `tx` is only there for the history, it's neither checked
+/// nor stored.
+fn handle_state_change(
+    raw_tx: &RawTransaction,
+    to: AccountState,
+    clients: &mut impl AccountStore,
+) -> Result<(), HandleError> {
+    let (client_id, _) = row_ids(raw_tx)?;
+    trace!("Found a move to {} for client {}.", to, client_id);
+
+    if raw_tx.amount.is_some() {
+        return Err(Rejection::UnexpectedAmount.into());
+    }
+    let mut client = clients.get(client_id)?.ok_or(Rejection::UnknownClient)?;
+    client.transition(to, raw_tx.reason.clone())?;
+    clients.insert(client_id, client)?;
+    Ok(())
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
+    use crate::asset::AssetRegistry;
     use crate::client::Client;
+    use crate::convert::Decimal;
+    use crate::ingest::{InputFormat, csv_reader, read_transactions};
+    use crate::overdraft::{DisputeOverdraft, OverdraftLimits};
     use crate::processed_transaction::ProcessedTransaction;
     use crate::raw_transaction::{RawTransaction, RawTransactionType};
     use std::collections::HashMap;
 
+    /// A row with only the columns of the exercise, none of the optional ones.
+    fn row(
+        transaction_type: RawTransactionType,
+        client_id: u64,
+        transaction_id: u64,
+        amount: Option<f64>,
+    ) -> RawTransaction {
+        RawTransaction {
+            transaction_type,
+            client_id: client_id.into(),
+            transaction_id: transaction_id.into(),
+            amount: amount.map(RawAmount::from),
+            timestamp: None,
+            asset: None,
+            destination: None,
+            reason: None,
+        }
+    }
+
+    /// Applies every row of `csv` to the stores, and returns how each went.
+    fn apply(
+        csv: &str,
+        config: &Config,
+        transactions: &mut impl TransactionStore,
+        clients: &mut impl AccountStore,
+    ) -> Vec<Result<Applied, HandleError>> {
+        csv_reader(csv.as_bytes())
+            .deserialize::<RawTransaction>()
+            .map(|row| handle_transaction(&row.unwrap(), config, transactions, clients))
+            .collect()
+    }
+
     #[test]
     fn test_handle_transaction_with_simple_data() {
-        let mut transactions: HashMap<u32, ProcessedTransaction> = HashMap::new();
-        let mut clients: HashMap<u16, Client> = HashMap::new();
+        let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
 
         // These are the same as in `data/tx/sample_1.csv`
         let sample_transactions = vec![
-            RawTransaction {
-                transaction_type: RawTransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(1.0),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Deposit,
-                client_id: 2,
-                transaction_id: 2,
-                amount: Some(5.0),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 3,
-                amount: Some(2.0),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Withdrawal,
-                client_id: 1,
-                transaction_id: 4,
-                amount: Some(1.5),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Withdrawal,
-                client_id: 2,
-                transaction_id: 5,
-                amount: Some(3.0),
-            },
+            row(RawTransactionType::Deposit, 1, 1, Some(1.0)),
+            row(RawTransactionType::Deposit, 2, 2, Some(5.0)),
+            row(RawTransactionType::Deposit, 1, 3, Some(2.0)),
+            row(RawTransactionType::Withdrawal, 1, 4, Some(1.5)),
+            row(RawTransactionType::Withdrawal, 2, 5, Some(3.0)),
         ];
 
         for raw_tx in &sample_transactions {
-            handle_transaction(raw_tx, &mut transactions, &mut clients);
+            handle_transaction(raw_tx, &Config::default(), &mut transactions, &mut clients)
+                .unwrap();
         }
 
         assert_eq!(clients.len(), 2);
-        assert!(clients.contains_key(&1));
-        assert!(clients.contains_key(&2));
+        assert!(clients.contains_key(&ClientId(1)));
+        assert!(clients.contains_key(&ClientId(2)));
 
-        let client1 = clients.get(&1).unwrap();
-        assert_eq!(client1.available, 15000); // 1.5 * 10000
-        assert_eq!(client1.held, 0);
-        assert_eq!(client1.total, 15000); // 1.5 * 10000
-        assert_eq!(client1.locked, false);
+        let client1 = clients.get(&ClientId(1)).unwrap();
+        assert_eq!(client1.balance(Asset::DEFAULT).available, 15000); // 1.5 * 10000
+        assert_eq!(client1.balance(Asset::DEFAULT).held, 0);
+        assert_eq!(client1.balance(Asset::DEFAULT).total, 15000); // 1.5 * 10000
+        assert_eq!(client1.is_locked(), false);
 
-        let client2 = clients.get(&2).unwrap();
-        assert_eq!(client2.available, 20000); // 2.0 * 10000
-        assert_eq!(client2.held, 0);
-        assert_eq!(client2.total, 20000); // 2.0 * 10000
-        assert_eq!(client2.locked, false);
+        let client2 = clients.get(&ClientId(2)).unwrap();
+        assert_eq!(client2.balance(Asset::DEFAULT).available, 20000); // 2.0 * 10000
+        assert_eq!(client2.balance(Asset::DEFAULT).held, 0);
+        assert_eq!(client2.balance(Asset::DEFAULT).total, 20000); // 2.0 * 10000
+        assert_eq!(client2.is_locked(), false);
 
         assert_eq!(transactions.len(), 5);
-        assert!(transactions.contains_key(&1));
-        assert!(transactions.contains_key(&2));
-        assert!(transactions.contains_key(&3));
-        assert!(transactions.contains_key(&4));
-        assert!(transactions.contains_key(&5));
+        assert!(transactions.contains_key(&TransactionId(1)));
+        assert!(transactions.contains_key(&TransactionId(2)));
+        assert!(transactions.contains_key(&TransactionId(3)));
+        assert!(transactions.contains_key(&TransactionId(4)));
+        assert!(transactions.contains_key(&TransactionId(5)));
     }
 
     #[test]
     fn test_handle_transaction_complex_data() {
-        let mut transactions: HashMap<u32, ProcessedTransaction> = HashMap::new();
-        let mut clients: HashMap<u16, Client> = HashMap::new();
+        let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
 
         let complex_transactions = vec![
-            RawTransaction {
-                transaction_type: RawTransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(1000.0),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Deposit,
-                client_id: 2,
-                transaction_id: 4,
-                amount: Some(800.0),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Deposit,
-                client_id: 3,
-                transaction_id: 7,
-                amount: Some(600.0),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 2,
-                amount: Some(500.0),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Deposit,
-                client_id: 2,
-                transaction_id: 5,
-                amount: Some(400.0),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Deposit,
-                client_id: 3,
-                transaction_id: 8,
-                amount: Some(300.0),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Withdrawal,
-                client_id: 1,
-                transaction_id: 3,
-                amount: Some(200.0),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Withdrawal,
-                client_id: 2,
-                transaction_id: 6,
-                amount: Some(100.0),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Withdrawal,
-                client_id: 3,
-                transaction_id: 9,
-                amount: Some(150.0),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Dispute,
-                client_id: 1,
-                transaction_id: 1,
-                amount: None,
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Dispute,
-                client_id: 2,
-                transaction_id: 4,
-                amount: None,
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Dispute,
-                client_id: 3,
-                transaction_id: 7,
-                amount: None,
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Dispute,
-                client_id: 1,
-                transaction_id: 2,
-                amount: None,
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Dispute,
-                client_id: 3,
-                transaction_id: 8,
-                amount: None,
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Resolve,
-                client_id: 1,
-                transaction_id: 2,
-                amount: None,
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Resolve,
-                client_id: 3,
-                transaction_id: 7,
-                amount: None,
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Chargeback,
-                client_id: 2,
-                transaction_id: 4,
-                amount: None,
-            },
+            row(RawTransactionType::Deposit, 1, 1, Some(1000.0)),
+            row(RawTransactionType::Deposit, 2, 4, Some(800.0)),
+            row(RawTransactionType::Deposit, 3, 7, Some(600.0)),
+            row(RawTransactionType::Deposit, 1, 2, Some(500.0)),
+            row(RawTransactionType::Deposit, 2, 5, Some(400.0)),
+            row(RawTransactionType::Deposit, 3, 8, Some(300.0)),
+            row(RawTransactionType::Withdrawal, 1, 3, Some(200.0)),
+            row(RawTransactionType::Withdrawal, 2, 6, Some(100.0)),
+            row(RawTransactionType::Withdrawal, 3, 9, Some(150.0)),
+            row(RawTransactionType::Dispute, 1, 1, None),
+            row(RawTransactionType::Dispute, 2, 4, None),
+            row(RawTransactionType::Dispute, 3, 7, None),
+            row(RawTransactionType::Dispute, 1, 2, None),
+            row(RawTransactionType::Dispute, 3, 8, None),
+            row(RawTransactionType::Resolve, 1, 2, None),
+            row(RawTransactionType::Resolve, 3, 7, None),
+            row(RawTransactionType::Chargeback, 2, 4, None),
         ];
 
         for raw_tx in &complex_transactions {
-            handle_transaction(raw_tx, &mut transactions, &mut clients);
+            handle_transaction(raw_tx, &Config::default(), &mut transactions, &mut clients)
+                .unwrap();
         }
 
         assert_eq!(clients.len(), 3);
-        assert!(clients.contains_key(&1));
-        assert!(clients.contains_key(&2));
-        assert!(clients.contains_key(&3));
+        assert!(clients.contains_key(&ClientId(1)));
+        assert!(clients.contains_key(&ClientId(2)));
+        assert!(clients.contains_key(&ClientId(3)));
 
         // Client 1 has one unresolved dispute (tx1 = 1000 held), tx2 was resolved
         // We expect: available=300, held=1000, total=1300, locked=false
-        let client1 = clients.get(&1).unwrap();
-        assert_eq!(client1.available, 3000000); // 300.0 * 10000
-        assert_eq!(client1.held, 10000000); // 1000.0 * 10000
-        assert_eq!(client1.total, 13000000); // 1300.0 * 10000
-        assert_eq!(client1.locked, false);
+        let client1 = clients.get(&ClientId(1)).unwrap();
+        assert_eq!(client1.balance(Asset::DEFAULT).available, 3000000); // 300.0 * 10000
+        assert_eq!(client1.balance(Asset::DEFAULT).held, 10000000); // 1000.0 * 10000
+        assert_eq!(client1.balance(Asset::DEFAULT).total, 13000000); // 1300.0 * 10000
+        assert_eq!(client1.is_locked(), false);
 
         // Client 2 has chargeback on tx4 so account should be locked
         // We expect available=300, held=0, total=300, locked=true
-        let client2 = clients.get(&2).unwrap();
-        assert_eq!(client2.available, 3000000); // 300.0 * 10000
-        assert_eq!(client2.held, 0);
-        assert_eq!(client2.total, 3000000); // 300.0 * 10000
-        assert_eq!(client2.locked, true);
+        let client2 = clients.get(&ClientId(2)).unwrap();
+        assert_eq!(client2.balance(Asset::DEFAULT).available, 3000000); // 300.0 * 10000
+        assert_eq!(client2.balance(Asset::DEFAULT).held, 0);
+        assert_eq!(client2.balance(Asset::DEFAULT).total, 3000000); // 300.0 * 10000
+        assert_eq!(client2.is_locked(), true);
 
         // Client 3 has one unresolved dispute (tx8 = 300 held), tx7 was resolved
         // Expected: available=450, held=300, total=750, locked=false
-        let client3 = clients.get(&3).unwrap();
-        assert_eq!(client3.available, 4500000); // 450.0 * 10000
-        assert_eq!(client3.held, 3000000); // 300.0 * 10000
-        assert_eq!(client3.total, 7500000); // 750.0 * 10000
-        assert_eq!(client3.locked, false);
+        let client3 = clients.get(&ClientId(3)).unwrap();
+        assert_eq!(client3.balance(Asset::DEFAULT).available, 4500000); // 450.0 * 10000
+        assert_eq!(client3.balance(Asset::DEFAULT).held, 3000000); // 300.0 * 10000
+        assert_eq!(client3.balance(Asset::DEFAULT).total, 7500000); // 750.0 * 10000
+        assert_eq!(client3.is_locked(), false);
 
         assert_eq!(transactions.len(), 9);
     }
 
     #[test]
     fn test_locked_account_rejects_operations() {
-        let mut transactions: HashMap<u32, ProcessedTransaction> = HashMap::new();
-        let mut clients: HashMap<u16, Client> = HashMap::new();
+        let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
 
         let initial_transactions = vec![
-            RawTransaction {
-                transaction_type: RawTransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(1000.0),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Dispute,
-                client_id: 1,
-                transaction_id: 1,
-                amount: None,
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Chargeback,
-                client_id: 1,
-                transaction_id: 1,
-                amount: None,
-            },
+            row(RawTransactionType::Deposit, 1, 1, Some(1000.0)),
+            row(RawTransactionType::Dispute, 1, 1, None),
+            row(RawTransactionType::Chargeback, 1, 1, None),
         ];
 
         for raw_tx in &initial_transactions {
-            handle_transaction(raw_tx, &mut transactions, &mut clients);
+            handle_transaction(raw_tx, &Config::default(), &mut transactions, &mut clients)
+                .unwrap();
         }
 
-        let client = clients.get(&1).unwrap();
-        assert_eq!(client.locked, true);
+        let client = clients.get(&ClientId(1)).unwrap();
+        assert_eq!(client.is_locked(), true);
 
-        let available_before = client.available;
-        let held_before = client.held;
-        let total_before = client.total;
+        let available_before = client.balance(Asset::DEFAULT).available;
+        let held_before = client.balance(Asset::DEFAULT).held;
+        let total_before = client.balance(Asset::DEFAULT).total;
         let tx_count_before = transactions.len();
 
         let forbidden_transactions = vec![
-            RawTransaction {
-                transaction_type: RawTransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 2,
-                amount: Some(500.0),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Withdrawal,
-                client_id: 1,
-                transaction_id: 3,
-                amount: Some(100.0),
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Dispute,
-                client_id: 1,
-                transaction_id: 1,
-                amount: None,
-            },
-            RawTransaction {
-                transaction_type: RawTransactionType::Resolve,
-                client_id: 1,
-                transaction_id: 1,
-                amount: None,
-            },
+            row(RawTransactionType::Deposit, 1, 2, Some(500.0)),
+            row(RawTransactionType::Withdrawal, 1, 3, Some(100.0)),
+            row(RawTransactionType::Dispute, 1, 1, None),
+            row(RawTransactionType::Resolve, 1, 1, None),
         ];
 
         for raw_tx in &forbidden_transactions {
-            handle_transaction(raw_tx, &mut transactions, &mut clients);
+            assert!(
+                handle_transaction(raw_tx, &Config::default(), &mut transactions, &mut clients)
+                    .is_err()
+            );
         }
 
-        let client_after = clients.get(&1).unwrap();
+        let client_after = clients.get(&ClientId(1)).unwrap();
         assert_eq!(
-            client_after.available, available_before,
+            client_after.balance(Asset::DEFAULT).available,
+            available_before,
             "Available balance changed on locked account"
         );
         assert_eq!(
-            client_after.held, held_before,
+            client_after.balance(Asset::DEFAULT).held,
+            held_before,
             "Held balance changed on locked account"
         );
         assert_eq!(
-            client_after.total, total_before,
+            client_after.balance(Asset::DEFAULT).total,
+            total_before,
             "Total balance changed on locked account"
         );
-        assert_eq!(client_after.locked, true, "Account should still be locked");
+        assert_eq!(
+            client_after.is_locked(),
+            true,
+            "Account should still be locked"
+        );
 
         assert_eq!(
             transactions.len(),
@@ -679,46 +1114,837 @@ mod tests {
 
     #[test]
     fn test_cross_client_effects() {
-        let mut transactions: HashMap<u32, ProcessedTransaction> = HashMap::new();
-        let mut clients: HashMap<u16, Client> = HashMap::new();
-
-        let deposit_tx = RawTransaction {
-            transaction_type: RawTransactionType::Deposit,
-            client_id: 1,
-            transaction_id: 1,
-            amount: Some(100.0),
+        let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+
+        let deposit_tx = row(RawTransactionType::Deposit, 1, 1, Some(100.0));
+        handle_transaction(
+            &deposit_tx,
+            &Config::default(),
+            &mut transactions,
+            &mut clients,
+        )
+        .unwrap();
+
+        let deposit_tx2 = row(RawTransactionType::Deposit, 2, 2, Some(50.0));
+        handle_transaction(
+            &deposit_tx2,
+            &Config::default(),
+            &mut transactions,
+            &mut clients,
+        )
+        .unwrap();
+
+        let client1_initial_available = clients
+            .get(&ClientId(1))
+            .unwrap()
+            .balance(Asset::DEFAULT)
+            .available;
+        let client1_initial_held = clients
+            .get(&ClientId(1))
+            .unwrap()
+            .balance(Asset::DEFAULT)
+            .held;
+        let client2_initial_available = clients
+            .get(&ClientId(2))
+            .unwrap()
+            .balance(Asset::DEFAULT)
+            .available;
+        let client2_initial_held = clients
+            .get(&ClientId(2))
+            .unwrap()
+            .balance(Asset::DEFAULT)
+            .held;
+
+        // This is client 1's transaction
+        let dispute_tx = row(RawTransactionType::Dispute, 2, 1, None);
+        assert_eq!(
+            handle_transaction(
+                &dispute_tx,
+                &Config::default(),
+                &mut transactions,
+                &mut clients
+            ),
+            Err(Rejection::ClientMismatch.into())
+        );
+
+        let client1_after = clients.get(&ClientId(1)).unwrap();
+        let client2_after = clients.get(&ClientId(2)).unwrap();
+
+        // Both should be unchanged since cross-client effects shouldn't apply
+        assert_eq!(
+            client1_after.balance(Asset::DEFAULT).available,
+            client1_initial_available
+        );
+        assert_eq!(
+            client1_after.balance(Asset::DEFAULT).held,
+            client1_initial_held
+        );
+        assert_eq!(
+            client1_after.balance(Asset::DEFAULT).total,
+            client1_initial_available
+        );
+        assert_eq!(
+            client2_after.balance(Asset::DEFAULT).available,
+            client2_initial_available
+        );
+        assert_eq!(
+            client2_after.balance(Asset::DEFAULT).held,
+            client2_initial_held
+        );
+    }
+
+    #[test]
+    fn test_malformed_rows_are_rejected() {
+        let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+
+        let deposit_tx = row(RawTransactionType::Deposit, 1, 1, Some(100.0));
+        handle_transaction(
+            &deposit_tx,
+            &Config::default(),
+            &mut transactions,
+            &mut clients,
+        )
+        .unwrap();
+
+        let malformed = vec![
+            (
+                RawTransactionType::Deposit,
+                2,
+                None,
+                Rejection::MissingAmount,
+            ),
+            (
+                RawTransactionType::Withdrawal,
+                3,
+                None,
+                Rejection::MissingAmount,
+            ),
+            (
+                RawTransactionType::Deposit,
+                4,
+                Some(0.0),
+                Rejection::NonPositiveAmount,
+            ),
+            (
+                RawTransactionType::Deposit,
+                5,
+                Some(-5.0),
+                Rejection::NonPositiveAmount,
+            ),
+            (
+                RawTransactionType::Withdrawal,
+                6,
+                Some(-5.0),
+                Rejection::NonPositiveAmount,
+            ),
+            // More decimals than the default 4
+            (
+                RawTransactionType::Deposit,
+                7,
+                Some(0.00001),
+                Rejection::TooPrecise,
+            ),
+            (
+                RawTransactionType::Deposit,
+                8,
+                Some(f64::NAN),
+                Rejection::NonFiniteAmount,
+            ),
+            (
+                RawTransactionType::Deposit,
+                9,
+                Some(f64::INFINITY),
+                Rejection::NonFiniteAmount,
+            ),
+            (
+                RawTransactionType::Withdrawal,
+                10,
+                Some(f64::NEG_INFINITY),
+                Rejection::NonFiniteAmount,
+            ),
+            // Effects may be for part of the transaction, but no more than it
+            (
+                RawTransactionType::Dispute,
+                1,
+                Some(150.0),
+                Rejection::ExceedsUndisputed,
+            ),
+            (
+                RawTransactionType::Dispute,
+                1,
+                Some(-5.0),
+                Rejection::NonPositiveAmount,
+            ),
+            (
+                RawTransactionType::Resolve,
+                1,
+                Some(50.0),
+                Rejection::NotDisputed,
+            ),
+        ];
+
+        for (transaction_type, transaction_id, amount, expected) in malformed {
+            let raw_tx = row(transaction_type, 1, transaction_id, amount);
+            assert_eq!(
+                handle_transaction(&raw_tx, &Config::default(), &mut transactions, &mut clients),
+                Err(expected.into()),
+                "Unexpected result for {:?}",
+                raw_tx
+            );
+        }
+
+        let client = clients.get(&ClientId(1)).unwrap();
+        assert_eq!(client.balance(Asset::DEFAULT).available, 1000000);
+        assert_eq!(client.balance(Asset::DEFAULT).held, 0);
+        assert_eq!(client.balance(Asset::DEFAULT).total, 1000000);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(
+            transactions.get(&TransactionId(1)).unwrap().dispute_status,
+            DisputeStatus::Valid
+        );
+    }
+
+    #[test]
+    fn test_duplicate_transaction_is_not_applied() {
+        let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+
+        let deposit_tx = row(RawTransactionType::Deposit, 1, 1, Some(100.0));
+        handle_transaction(
+            &deposit_tx,
+            &Config::default(),
+            &mut transactions,
+            &mut clients,
+        )
+        .unwrap();
+        assert_eq!(
+            handle_transaction(
+                &deposit_tx,
+                &Config::default(),
+                &mut transactions,
+                &mut clients
+            ),
+            Err(Rejection::DuplicateTransaction.into())
+        );
+
+        let withdrawal_tx = row(RawTransactionType::Withdrawal, 1, 1, Some(10.0));
+        assert_eq!(
+            handle_transaction(
+                &withdrawal_tx,
+                &Config::default(),
+                &mut transactions,
+                &mut clients
+            ),
+            Err(Rejection::DuplicateTransaction.into())
+        );
+
+        let client = clients.get(&ClientId(1)).unwrap();
+        assert_eq!(client.balance(Asset::DEFAULT).available, 1000000);
+        assert_eq!(client.balance(Asset::DEFAULT).total, 1000000);
+        assert_eq!(transactions.len(), 1);
+    }
+
+    #[test]
+    fn test_malformed_csv_rows_parse_into_rejections() {
+        let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 10.0\n\
+                   deposit, 1, 2,\n\
+                   deposit, 1, 3, NaN\n\
+                   withdrawal, 1, 4, inf\n\
+                   dispute, 1, 1, 10.0\n";
+
+        let results = apply(csv, &Config::default(), &mut transactions, &mut clients);
+
+        assert_eq!(
+            results,
+            vec![
+                Ok(Applied::default()),
+                Err(Rejection::MissingAmount.into()),
+                Err(Rejection::NonFiniteAmount.into()),
+                Err(Rejection::NonFiniteAmount.into()),
+                Ok(Applied::default()),
+            ]
+        );
+        assert_eq!(
+            clients
+                .get(&ClientId(1))
+                .unwrap()
+                .balance(Asset::DEFAULT)
+                .total,
+            100000
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_ids_are_rejected() {
+        let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+
+        let csv = "type, client, tx, amount\n\
+                   deposit, 70000, 5000000000, 10.0\n\
+                   deposit, 18446744073709551616, 1, 10.0\n\
+                   deposit, 1, -1, 10.0\n\
+                   dispute, 70000, 5000000000,\n";
+        let jsonl = r#"{"type":"deposit","client":1,"tx":18446744073709551616,"amount":1}"#;
+
+        let rows = read_transactions(csv.as_bytes(), InputFormat::Csv)
+            .chain(read_transactions(jsonl.as_bytes(), InputFormat::Jsonl));
+        let results: Vec<Result<Applied, HandleError>> = rows
+            .map(|row| {
+                handle_transaction(
+                    &row.unwrap(),
+                    &Config::default(),
+                    &mut transactions,
+                    &mut clients,
+                )
+            })
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                Ok(Applied::default()),
+                Err(Rejection::IdOutOfRange.into()),
+                Err(Rejection::IdOutOfRange.into()),
+                Ok(Applied::default()),
+                Err(Rejection::IdOutOfRange.into()),
+            ]
+        );
+
+        let client = &clients[&ClientId(70000)];
+        assert_eq!(client.balance(Asset::DEFAULT).held, 100000);
+        assert_eq!(
+            transactions[&TransactionId(5_000_000_000)].dispute_status,
+            DisputeStatus::Disputed
+        );
+        assert_eq!(clients.len(), 1);
+    }
+
+    #[test]
+    fn test_overflowing_amounts_are_rejected() {
+        let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+
+        // Doesn't fit in an i128
+        let huge_tx = row(RawTransactionType::Deposit, 1, 1, Some(1e300));
+        assert_eq!(
+            handle_transaction(
+                &huge_tx,
+                &Config::default(),
+                &mut transactions,
+                &mut clients
+            ),
+            Err(Rejection::Overflow.into())
+        );
+
+        // Each of these fits, but not both of them together
+        let big_tx = row(RawTransactionType::Deposit, 1, 2, Some(1e34));
+        handle_transaction(&big_tx, &Config::default(), &mut transactions, &mut clients).unwrap();
+        let another_big_tx = RawTransaction {
+            transaction_id: 3.into(),
+            ..big_tx.clone()
         };
-        handle_transaction(&deposit_tx, &mut transactions, &mut clients);
+        assert_eq!(
+            handle_transaction(
+                &another_big_tx,
+                &Config::default(),
+                &mut transactions,
+                &mut clients
+            ),
+            Err(Rejection::Overflow.into())
+        );
 
-        let deposit_tx2 = RawTransaction {
-            transaction_type: RawTransactionType::Deposit,
-            client_id: 2,
-            transaction_id: 2,
-            amount: Some(50.0),
+        let client = clients.get(&ClientId(1)).unwrap();
+        assert_eq!(client.balance(Asset::DEFAULT).available, 10i128.pow(38));
+        assert_eq!(client.balance(Asset::DEFAULT).total, 10i128.pow(38));
+        assert_eq!(transactions.len(), 1);
+    }
+
+    #[test]
+    fn test_max_amount_is_enforced() {
+        let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+        let config = Config {
+            max_amount: Some(Decimal::from_units(100, 0)),
+            ..Config::default()
         };
-        handle_transaction(&deposit_tx2, &mut transactions, &mut clients);
-
-        let client1_initial_available = clients.get(&1).unwrap().available;
-        let client1_initial_held = clients.get(&1).unwrap().held;
-        let client2_initial_available = clients.get(&2).unwrap().available;
-        let client2_initial_held = clients.get(&2).unwrap().held;
-
-        let dispute_tx = RawTransaction {
-            transaction_type: RawTransactionType::Dispute,
-            client_id: 2,
-            transaction_id: 1, // This is client 1's transaction
-            amount: None,
+
+        let deposit_tx = row(RawTransactionType::Deposit, 1, 1, Some(100.0));
+        handle_transaction(&deposit_tx, &config, &mut transactions, &mut clients).unwrap();
+
+        let too_large_deposit = RawTransaction {
+            transaction_id: 2.into(),
+            amount: Some(100.0001.into()),
+            ..deposit_tx.clone()
         };
-        handle_transaction(&dispute_tx, &mut transactions, &mut clients);
+        assert_eq!(
+            handle_transaction(&too_large_deposit, &config, &mut transactions, &mut clients),
+            Err(Rejection::AmountTooLarge.into())
+        );
 
-        let client1_after = clients.get(&1).unwrap();
-        let client2_after = clients.get(&2).unwrap();
+        let too_large_withdrawal = RawTransaction {
+            transaction_type: RawTransactionType::Withdrawal,
+            transaction_id: 3.into(),
+            amount: Some(150.0.into()),
+            ..deposit_tx.clone()
+        };
+        assert_eq!(
+            handle_transaction(
+                &too_large_withdrawal,
+                &config,
+                &mut transactions,
+                &mut clients
+            ),
+            Err(Rejection::AmountTooLarge.into())
+        );
 
-        // Both should be unchanged since cross-client effects shouldn't apply
-        assert_eq!(client1_after.available, client1_initial_available);
-        assert_eq!(client1_after.held, client1_initial_held);
-        assert_eq!(client1_after.total, client1_initial_available);
-        assert_eq!(client2_after.available, client2_initial_available);
-        assert_eq!(client2_after.held, client2_initial_held);
+        assert_eq!(
+            clients
+                .get(&ClientId(1))
+                .unwrap()
+                .balance(Asset::DEFAULT)
+                .total,
+            1_000_000
+        );
+        assert_eq!(transactions.len(), 1);
+    }
+
+    #[test]
+    fn test_assets_are_kept_apart() {
+        let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+
+        let csv = "type, client, tx, amount, asset\n\
+                   deposit, 1, 1, 10.0, usd\n\
+                   deposit, 1, 2, 0.5, BTC\n\
+                   deposit, 1, 3, 2.0,\n\
+                   withdrawal, 1, 4, 1.0, BTC\n\
+                   deposit, 1, 5, 1.0, not-an-asset\n\
+                   dispute, 1, 2, , USD\n\
+                   dispute, 1, 2, ,\n\
+                   chargeback, 1, 2, , BTC\n";
+
+        let results = apply(csv, &Config::default(), &mut transactions, &mut clients);
+        assert_eq!(
+            results,
+            vec![
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Err(ClientError::InsufficientFunds.into()),
+                Err(Rejection::InvalidAsset.into()),
+                Err(Rejection::AssetMismatch.into()),
+                Ok(Applied::default()),
+                Ok(Applied {
+                    chargeback: Some(Chargeback {
+                        reason_code: None,
+                        asset: Asset::new("BTC").unwrap(),
+                        amount: 5000,
+                    }),
+                    ..Applied::default()
+                }),
+            ]
+        );
+
+        let usd = Asset::new("USD").unwrap();
+        let btc = Asset::new("BTC").unwrap();
+        assert_eq!(transactions[&TransactionId(2)].asset, btc);
+        let client = &clients[&ClientId(1)];
+        assert_eq!(client.balance(usd).total, 100000);
+        assert_eq!(client.balance(Asset::DEFAULT).total, 20000);
+        assert_eq!(client.balance(btc).total, 0);
+        assert_eq!(client.balances.len(), 3);
+        assert!(client.is_locked());
+    }
+
+    #[test]
+    fn test_precision_is_per_asset() {
+        let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+        let mut assets = AssetRegistry::new();
+        assets.insert(Asset::new("USD").unwrap(), 2).unwrap();
+        assets.insert(Asset::new("ETH").unwrap(), 18).unwrap();
+        let config = Config {
+            assets,
+            ..Config::default()
+        };
+
+        let csv = "type, client, tx, amount, asset\n\
+                   deposit, 1, 1, 10.25, USD\n\
+                   deposit, 1, 2, 10.255, USD\n\
+                   deposit, 1, 3, 1.000000000000000001, ETH\n\
+                   deposit, 1, 4, 0.0000000000000000001, ETH\n\
+                   deposit, 1, 5, 0.00001,\n";
+
+        let results = apply(csv, &config, &mut transactions, &mut clients);
+        assert_eq!(
+            results,
+            vec![
+                Ok(Applied::default()),
+                Err(Rejection::TooPrecise.into()),
+                Ok(Applied::default()),
+                Err(Rejection::TooPrecise.into()),
+                Err(Rejection::TooPrecise.into()),
+            ]
+        );
+
+        let client = &clients[&ClientId(1)];
+        assert_eq!(client.balance(Asset::new("USD").unwrap()).total, 1025);
+        assert_eq!(
+            client.balance(Asset::new("ETH").unwrap()).total,
+            1_000_000_000_000_000_001
+        );
+    }
+
+    #[test]
+    fn test_overdrafts() {
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 10.0\n\
+                   withdrawal, 1, 2, 15.0\n\
+                   withdrawal, 1, 3, 10.0\n\
+                   deposit, 2, 4, 10.0\n\
+                   withdrawal, 2, 5, 8.0\n\
+                   dispute, 2, 4,\n";
+
+        let mut overdrafts = OverdraftLimits::new();
+        overdrafts.insert(Some(ClientId(1)), Asset::DEFAULT, 100000);
+        overdrafts.insert(None, Asset::DEFAULT, 50000);
+        let run = |dispute_overdraft| {
+            let config = Config {
+                overdrafts: overdrafts.clone(),
+                dispute_overdraft,
+                ..Config::default()
+            };
+            let mut transactions: HashMap<TransactionId, ProcessedTransaction> = HashMap::new();
+            let mut clients: HashMap<ClientId, Client> = HashMap::new();
+            let results = apply(csv, &config, &mut transactions, &mut clients);
+            (results, clients)
+        };
+
+        // Client 1 may go 10 below zero, client 2 only 5
+        let (results, clients) = run(DisputeOverdraft::Allow);
+        assert_eq!(results[1], Ok(Applied::default()));
+        assert_eq!(results[2], Err(ClientError::InsufficientFunds.into()));
+        assert_eq!(results[5], Ok(Applied::default()));
+        assert_eq!(
+            clients[&ClientId(1)].balance(Asset::DEFAULT).available,
+            -50000
+        );
+        assert_eq!(
+            clients[&ClientId(2)].balance(Asset::DEFAULT).available,
+            -80000
+        );
+
+        let (results, _) = run(DisputeOverdraft::WithinLimit);
+        assert_eq!(results[5], Err(ClientError::InsufficientFunds.into()));
+        let (results, _) = run(DisputeOverdraft::Never);
+        assert_eq!(results[5], Err(ClientError::InsufficientFunds.into()));
+    }
+
+    #[test]
+    fn test_partial_disputes() {
+        // The compact store keeps partly disputed amounts on the side
+        let mut transactions = crate::compact_store::CompactTransactionStore::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 10.0\n\
+                   dispute, 1, 1, 3.0\n\
+                   dispute, 1, 1, 4.0\n\
+                   dispute, 1, 1, 4.0\n\
+                   resolve, 1, 1, 2.0\n\
+                   chargeback, 1, 1, 6.0\n\
+                   chargeback, 1, 1, 1.0\n\
+                   deposit, 2, 2, 10.0\n\
+                   dispute, 2, 2, 2.5\n\
+                   dispute, 2, 2,\n\
+                   resolve, 2, 2,\n\
+                   dispute, 2, 2, 1.0\n";
+
+        let results = apply(csv, &Config::default(), &mut transactions, &mut clients);
+        assert_eq!(
+            results,
+            vec![
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Err(Rejection::ExceedsUndisputed.into()),
+                Ok(Applied::default()),
+                Err(Rejection::ExceedsDisputed.into()),
+                Ok(Applied {
+                    chargeback: Some(Chargeback {
+                        reason_code: None,
+                        asset: Asset::DEFAULT,
+                        amount: 10000,
+                    }),
+                    ..Applied::default()
+                }),
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                // What was resolved can be disputed again
+                Ok(Applied::default()),
+            ]
+        );
+
+        // 4 of tx 1 is still under dispute, the account being locked by
+        // the chargeback of 1 of it.
+        let tx = transactions.get(TransactionId(1)).unwrap().unwrap();
+        assert_eq!(
+            (tx.dispute_status, tx.disputed),
+            (DisputeStatus::Disputed, 40000)
+        );
+        let balance = clients[&ClientId(1)].balance(Asset::DEFAULT);
+        assert_eq!(
+            (balance.available, balance.held, balance.total),
+            (50000, 40000, 90000)
+        );
+
+        let tx = transactions.get(TransactionId(2)).unwrap().unwrap();
+        assert_eq!(
+            (tx.dispute_status, tx.disputed),
+            (DisputeStatus::Disputed, 10000)
+        );
+        assert_eq!(
+            clients[&ClientId(2)].balance(Asset::DEFAULT).available,
+            90000
+        );
+    }
+
+    #[test]
+    fn test_partly_settled_transactions_stay_disputable() {
+        let mut transactions = crate::compact_store::CompactTransactionStore::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+
+        // Client 1 disputes part of tx 1 again once the first part is
+        // resolved. Client 2 has part of tx 2 charged back, which locks the
+        // account, and the other part still gets resolved.
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 100.0\n\
+                   dispute, 1, 1, 40.0\n\
+                   resolve, 1, 1,\n\
+                   dispute, 1, 1, 30.0\n\
+                   deposit, 2, 2, 100.0\n\
+                   dispute, 2, 2, 40.0\n\
+                   dispute, 2, 2, 60.0\n\
+                   chargeback, 2, 2, 40.0\n\
+                   resolve, 2, 2,\n\
+                   dispute, 2, 2,\n";
+
+        let results = apply(csv, &Config::default(), &mut transactions, &mut clients);
+        assert_eq!(
+            results,
+            vec![
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Ok(Applied {
+                    chargeback: Some(Chargeback {
+                        reason_code: None,
+                        asset: Asset::DEFAULT,
+                        amount: 400000,
+                    }),
+                    ..Applied::default()
+                }),
+                Ok(Applied::default()),
+                // The rest is disputable, but the account is locked
+                Err(Rejection::Client(ClientError::NotAllowed(AccountState::Locked)).into()),
+            ]
+        );
+
+        let tx = transactions.get(TransactionId(1)).unwrap().unwrap();
+        assert_eq!(
+            (tx.dispute_status, tx.disputed, tx.undisputed()),
+            (DisputeStatus::Disputed, 300000, 700000)
+        );
+        let balance = clients[&ClientId(1)].balance(Asset::DEFAULT);
+        assert_eq!((balance.available, balance.held), (700000, 300000));
+
+        let tx = transactions.get(TransactionId(2)).unwrap().unwrap();
+        assert_eq!(
+            (tx.dispute_status, tx.disputed, tx.charged_back),
+            (DisputeStatus::ChargedBack, 0, 400000)
+        );
+        let balance = clients[&ClientId(2)].balance(Asset::DEFAULT);
+        assert_eq!(
+            (balance.available, balance.held, balance.total),
+            (600000, 0, 600000)
+        );
+        assert_eq!(clients[&ClientId(2)].is_locked(), true);
+    }
+
+    #[test]
+    fn test_refunds_and_reversals() {
+        let mut transactions = crate::compact_store::CompactTransactionStore::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 10.0\n\
+                   withdrawal, 1, 2, 4.0\n\
+                   refund, 1, 2, 1.5\n\
+                   refund, 1, 1, 5.0\n\
+                   dispute, 1, 1, 6.0\n\
+                   dispute, 1, 1,\n\
+                   refund, 1, 1,\n\
+                   reversal, 1, 1,\n\
+                   resolve, 1, 1,\n\
+                   reversal, 1, 1, 1.0\n\
+                   reversal, 1, 1,\n\
+                   reversal, 1, 2,\n\
+                   reversal, 1, 2,\n\
+                   refund, 1, 2,\n";
+
+        let results = apply(csv, &Config::default(), &mut transactions, &mut clients);
+        assert_eq!(
+            results,
+            vec![
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                // Only the 5 that weren't refunded can be disputed
+                Err(Rejection::ExceedsUndisputed.into()),
+                Ok(Applied::default()),
+                Err(Rejection::NotRefundable.into()),
+                Err(Rejection::NotReversible.into()),
+                Ok(Applied::default()),
+                Err(Rejection::UnexpectedAmount.into()),
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Err(Rejection::NotReversible.into()),
+                Err(Rejection::NotRefundable.into()),
+            ]
+        );
+
+        for (transaction_id, refunded) in [(1, 100000), (2, 40000)] {
+            let tx = transactions
+                .get(TransactionId(transaction_id))
+                .unwrap()
+                .unwrap();
+            assert_eq!(tx.refunded, refunded);
+        }
+        let balance = clients[&ClientId(1)].balance(Asset::DEFAULT);
+        assert_eq!((balance.available, balance.held, balance.total), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_reversals_leave_what_was_charged_back() {
+        let mut transactions = crate::compact_store::CompactTransactionStore::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+
+        // 40 of tx 1 is charged back, so reversing it only takes the other 60.
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 100.0\n\
+                   deposit, 1, 2, 500.0\n\
+                   dispute, 1, 1, 40.0\n\
+                   dispute, 1, 1, 60.0\n\
+                   chargeback, 1, 1, 40.0\n\
+                   activate, 1, 3,\n\
+                   resolve, 1, 1,\n\
+                   reversal, 1, 1,\n\
+                   refund, 1, 1,\n\
+                   reversal, 1, 1,\n";
+
+        let results = apply(csv, &Config::default(), &mut transactions, &mut clients);
+        assert_eq!(results[7], Ok(Applied::default()));
+        assert_eq!(results[8], Err(Rejection::NotRefundable.into()));
+        assert_eq!(results[9], Err(Rejection::NotReversible.into()));
+
+        let tx = transactions.get(TransactionId(1)).unwrap().unwrap();
+        assert_eq!((tx.refunded, tx.charged_back), (600000, 400000));
+        let balance = clients[&ClientId(1)].balance(Asset::DEFAULT);
+        assert_eq!(
+            (balance.available, balance.held, balance.total),
+            (5000000, 0, 5000000)
+        );
+    }
+
+    #[test]
+    fn test_transfers() {
+        let mut transactions = crate::compact_store::CompactTransactionStore::new();
+        let mut clients: HashMap<ClientId, Client> = HashMap::new();
+
+        let csv = "type, client, tx, amount, to\n\
+                   deposit, 1, 1, 10.0,\n\
+                   transfer, 1, 2, 4.0, 2\n\
+                   transfer, 1, 3, 1.0,\n\
+                   transfer, 1, 3, 1.0, 1\n\
+                   transfer, 1, 3, 20.0, 2\n\
+                   dispute, 2, 2, ,\n\
+                   dispute, 1, 2, 1.0,\n\
+                   reversal, 1, 2, ,\n\
+                   withdrawal, 2, 4, 2.5,\n\
+                   dispute, 1, 2, ,\n\
+                   chargeback, 1, 2, ,\n\
+                   transfer, 2, 5, 0.1, 1\n\
+                   transfer, 1, 5, 0.1, 2\n";
+
+        let results = apply(csv, &Config::default(), &mut transactions, &mut clients);
+        let locked =
+            || Err(Rejection::Client(ClientError::NotAllowed(AccountState::Locked)).into());
+        assert_eq!(
+            results,
+            vec![
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                Err(Rejection::MissingDestination.into()),
+                Err(Rejection::SelfTransfer.into()),
+                Err(Rejection::Client(ClientError::InsufficientFunds).into()),
+                // Only the sender disputes
+                Err(Rejection::ClientMismatch.into()),
+                Ok(Applied::default()),
+                Err(Rejection::NotReversible.into()),
+                Ok(Applied::default()),
+                Ok(Applied::default()),
+                // Client 2 had spent 2.5 of it already
+                Ok(Applied {
+                    fraud_alert: Some(FraudAlert {
+                        client_id: ClientId(2),
+                        transaction_id: TransactionId(2),
+                        asset: Asset::DEFAULT,
+                        amount: 40000,
+                        exposure: 25000,
+                        at: None,
+                        reason_code: None,
+                    }),
+                    fee: None,
+                    chargeback: Some(Chargeback {
+                        reason_code: None,
+                        asset: Asset::DEFAULT,
+                        amount: 40000,
+                    }),
+                }),
+                // Client 1 charged it back, so it's locked, not client 2
+                Err(Rejection::Client(ClientError::InsufficientFunds).into()),
+                locked(),
+            ]
+        );
+
+        assert_eq!(
+            transactions
+                .get(TransactionId(2))
+                .unwrap()
+                .unwrap()
+                .transaction_type,
+            ProcessedTransactionType::Transfer { to: ClientId(2) }
+        );
+        let balance = clients[&ClientId(1)].balance(Asset::DEFAULT);
+        assert_eq!((balance.available, balance.total), (100000, 100000));
+        let balance = clients[&ClientId(2)].balance(Asset::DEFAULT);
+        assert_eq!(
+            (balance.available, balance.held, balance.total),
+            (-25000, 0, -25000)
+        );
+        assert!(clients[&ClientId(1)].is_locked());
+        assert!(!clients[&ClientId(2)].is_locked());
     }
 }
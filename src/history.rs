@@ -0,0 +1,297 @@
+use crate::asset::Asset;
+use crate::client::Client;
+use crate::config::Config;
+use crate::convert::Decimal;
+use crate::handlers::{HandleError, handle_transaction};
+use crate::ids::{ClientId, TransactionId};
+use crate::ingest::{InputFormat, read_transactions};
+use crate::output::csv_field;
+use crate::overdraft::OverdraftLimits;
+use crate::processed_transaction::ProcessedTransaction;
+use crate::raw_transaction::RawTransactionType;
+use crate::store::StoreError;
+use crate::trace;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::io::{Read, Write};
+
+/// Something that happened to an account: an accepted row, or a dispute
+/// that resolved itself past its deadline (see `expiry.rs`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEvent {
+    pub transaction_type: RawTransactionType,
+    pub client_id: ClientId,
+    pub transaction_id: TransactionId,
+    /// As it was written. Always set for deposits and withdrawals, only
+    /// for effects that were for part of the transaction.
+    pub amount: Option<Decimal>,
+    pub timestamp: Option<u64>,
+    pub asset: Asset,
+    /// Who a transfer went to.
+    pub destination: Option<ClientId>,
+    /// Why an operator moved the account to another state.
+    pub reason: Option<String>,
+}
+
+/// Where `process_transactions` records every event it applied.
+pub trait History {
+    fn record(&mut self, event: HistoryEvent) -> Result<(), StoreError>;
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// For when nobody is going to ask about the past.
+pub struct NoHistory;
+
+impl History for NoHistory {
+    fn record(&mut self, _event: HistoryEvent) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+impl History for Vec<HistoryEvent> {
+    fn record(&mut self, event: HistoryEvent) -> Result<(), StoreError> {
+        self.push(event);
+        Ok(())
+    }
+}
+
+/// The header `HistoryWriter` writes. A history written with another one
+/// can't be appended to.
+pub const HISTORY_HEADER: &str = "type,client,tx,amount,timestamp,asset,to,reason";
+
+/// Writes the history as CSV in the input format, with the timestamp,
+/// asset, recipient and reason columns.
+///
+/// Only applied events make it in, so replaying the file through the engine
+/// rebuilds the exact same state, which is what `state_as_of` does.
+pub struct HistoryWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> HistoryWriter<W> {
+    /// Set `write_header` to false when appending to an existing history.
+    pub fn new(mut out: W, write_header: bool) -> Result<Self, StoreError> {
+        if write_header {
+            writeln!(out, "{}", HISTORY_HEADER)?;
+        }
+        Ok(HistoryWriter { out })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> History for HistoryWriter<W> {
+    fn record(&mut self, event: HistoryEvent) -> Result<(), StoreError> {
+        let amount = event
+            .amount
+            .map(|amount| amount.to_string())
+            .unwrap_or_default();
+        let timestamp = event
+            .timestamp
+            .map(|timestamp| timestamp.to_string())
+            .unwrap_or_default();
+        let destination = event
+            .destination
+            .map(|destination| destination.to_string())
+            .unwrap_or_default();
+        let reason = event.reason.as_deref().map(csv_field).unwrap_or_default();
+        writeln!(
+            self.out,
+            "{},{},{},{},{},{},{},{}",
+            event.transaction_type,
+            event.client_id,
+            event.transaction_id,
+            amount,
+            timestamp,
+            event.asset,
+            destination,
+            reason
+        )?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Every transaction and client as they were at a point in time.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    pub transactions: HashMap<TransactionId, ProcessedTransaction>,
+    pub clients: HashMap<ClientId, Client>,
+    /// Rows of the history that went through again, per type.
+    pub applied: BTreeMap<RawTransactionType, usize>,
+    /// Rows of the history that didn't go through again. There shouldn't
+    /// be any unless it was written with another config than the one it's
+    /// replayed with.
+    pub rejected: usize,
+}
+
+/// Rebuilds the state as of `at` (seconds since the epoch, inclusive)
+/// by replaying a history written by `HistoryWriter`.
+///
+/// `config` has to be the one the history was written with, as far as its
+/// assets and clearing go. Everything else the config did is in the history
+/// already: fees, expired disputes and cleared funds are rows of their own.
+///
+/// Events without a timestamp are taken to have happened at the same time
+/// as the last one before them that had one.
+pub fn state_as_of<R: Read>(
+    history: R,
+    format: InputFormat,
+    at: u64,
+    config: &Config,
+) -> Result<Snapshot, Box<dyn Error>> {
+    let mut snapshot = Snapshot::default();
+    // Everything in there was accepted once, whatever the limits and rules
+    // were then. Deposits go into pending if they did back then, and `clear`
+    // rows make them available again.
+    let config = Config {
+        assets: config.assets.clone(),
+        clearing: config.clearing,
+        overdrafts: OverdraftLimits::unlimited(),
+        ..Config::default()
+    };
+    let mut now = 0;
+
+    for event in read_transactions(history, format) {
+        let event = event?;
+        now = event.timestamp.unwrap_or(now);
+        if now > at {
+            continue;
+        }
+        match handle_transaction(
+            &event,
+            &config,
+            &mut snapshot.transactions,
+            &mut snapshot.clients,
+        ) {
+            Ok(_) => *snapshot.applied.entry(event.transaction_type).or_insert(0) += 1,
+            Err(HandleError::Rejected(rejection)) => {
+                trace!("Rejected history row {:?}: {}", event, rejection);
+                snapshot.rejected += 1;
+            }
+            Err(HandleError::Store(e)) => return Err(e.into()),
+        }
+    }
+
+    Ok(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clearing::ClearingPeriod;
+    use crate::process::tests::run_with_history;
+    use crate::processed_transaction::DisputeStatus;
+
+    #[test]
+    fn test_state_as_of_replays_history() {
+        let config = Config {
+            dispute_deadline: Some(100),
+            ..Config::default()
+        };
+
+        // The rejected withdrawal must not show up in the history, and the
+        // dispute resolving itself at 1300 must.
+        let csv = "type, client, tx, amount, timestamp\n\
+                   deposit, 1, 1, 10.0, 1000\n\
+                   withdrawal, 1, 2, 50.0, 1050\n\
+                   deposit, 2, 3, 2.5,\n\
+                   dispute, 1, 1, , 1100\n\
+                   deposit, 2, 4, 1.0, 1300\n";
+        let mut history = HistoryWriter::new(Vec::new(), true).unwrap();
+        run_with_history(csv, &config, &mut history);
+        let bytes = history.into_inner();
+
+        let as_of = |at| state_as_of(&bytes[..], InputFormat::Csv, at, &Config::default()).unwrap();
+
+        let snapshot = as_of(999);
+        assert!(snapshot.clients.is_empty());
+
+        // tx 3 has no timestamp of its own, it happened "at" 1050
+        let snapshot = as_of(1050);
+        assert_eq!(
+            snapshot.clients[&ClientId(1)]
+                .balance(Asset::DEFAULT)
+                .available,
+            100000
+        );
+        assert_eq!(
+            snapshot.clients[&ClientId(2)]
+                .balance(Asset::DEFAULT)
+                .available,
+            25000
+        );
+
+        let snapshot = as_of(1200);
+        assert_eq!(
+            snapshot.clients[&ClientId(1)].balance(Asset::DEFAULT).held,
+            100000
+        );
+        assert_eq!(
+            snapshot.transactions[&TransactionId(1)].dispute_status,
+            DisputeStatus::Disputed
+        );
+
+        let snapshot = as_of(u64::MAX);
+        assert_eq!(
+            snapshot.clients[&ClientId(1)]
+                .balance(Asset::DEFAULT)
+                .available,
+            100000
+        );
+        assert_eq!(
+            snapshot.clients[&ClientId(1)].balance(Asset::DEFAULT).held,
+            0
+        );
+        assert_eq!(
+            snapshot.clients[&ClientId(2)]
+                .balance(Asset::DEFAULT)
+                .available,
+            35000
+        );
+        assert_eq!(
+            snapshot.transactions[&TransactionId(1)].dispute_status,
+            DisputeStatus::Resolved
+        );
+        assert_eq!(snapshot.transactions.len(), 3);
+    }
+
+    #[test]
+    fn test_state_as_of_replays_clearing() {
+        let config = Config {
+            clearing: ClearingPeriod::parse("1m"),
+            ..Config::default()
+        };
+
+        // tx 1 clears right before the withdrawal, tx 2 never does.
+        let csv = "type, client, tx, amount, timestamp\n\
+                   deposit, 1, 1, 10.0, 1000\n\
+                   deposit, 2, 2, 1.0, 1030\n\
+                   withdrawal, 1, 3, 5.0, 1060\n";
+        let mut history = HistoryWriter::new(Vec::new(), true).unwrap();
+        run_with_history(csv, &config, &mut history);
+        let bytes = history.into_inner();
+
+        let as_of = |at| state_as_of(&bytes[..], InputFormat::Csv, at, &config).unwrap();
+        let balance = |snapshot: &Snapshot, client_id| {
+            let balance = snapshot.clients[&ClientId(client_id)].balance(Asset::DEFAULT);
+            (balance.available, balance.pending)
+        };
+
+        let snapshot = as_of(1001);
+        assert_eq!(balance(&snapshot, 1), (0, 100000));
+        let snapshot = as_of(1060);
+        assert_eq!(snapshot.rejected, 0);
+        assert_eq!(balance(&snapshot, 1), (50000, 0));
+        assert_eq!(balance(&snapshot, 2), (0, 10000));
+    }
+}
@@ -0,0 +1,134 @@
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::marker::PhantomData;
+
+/// Who a transaction belongs to.
+///
+/// Client and transaction IDs used to be a `u16` and a `u32`, which ran out
+/// of clients long before anything else. Both are `u64`s now, behind their
+/// own types so they can't be mixed up with each other or with amounts.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct ClientId(pub u64);
+
+/// A deposit or withdrawal, which effects refer to. Unique across clients.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct TransactionId(pub u64);
+
+impl From<u64> for ClientId {
+    fn from(id: u64) -> Self {
+        ClientId(id)
+    }
+}
+
+impl From<u64> for TransactionId {
+    fn from(id: u64) -> Self {
+        TransactionId(id)
+    }
+}
+
+impl std::fmt::Display for ClientId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::fmt::Display for TransactionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// What was in an ID column.
+///
+/// An ID that doesn't fit (too many digits, or negative) still makes a row,
+/// so that it can be rejected like any other bad row instead of showing up
+/// as an unreadable one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawId<T> {
+    Id(T),
+    OutOfRange,
+}
+
+impl<T> RawId<T> {
+    pub fn get(self) -> Option<T> {
+        match self {
+            RawId::Id(id) => Some(id),
+            RawId::OutOfRange => None,
+        }
+    }
+}
+
+/// For tests and the fuzzer, which build rows by hand.
+impl<T: From<u64>> From<u64> for RawId<T> {
+    fn from(id: u64) -> Self {
+        RawId::Id(T::from(id))
+    }
+}
+
+/// Always asks for a string, like amounts do (see `convert.rs`): the CSV
+/// reader would otherwise give us an `f64` for anything past `u64::MAX`.
+impl<'de, T: From<u64>> Deserialize<'de> for RawId<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(IdVisitor(PhantomData))
+    }
+}
+
+struct IdVisitor<T>(PhantomData<T>);
+
+impl<'de, T: From<u64>> Visitor<'de> for IdVisitor<T> {
+    type Value = RawId<T>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "an ID")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        let value = value.trim();
+        let digits = value.strip_prefix('-').unwrap_or(value);
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(E::custom(format!("invalid ID '{}'", value)));
+        }
+        if value.starts_with('-') {
+            return Ok(RawId::OutOfRange);
+        }
+        Ok(digits
+            .parse()
+            .map(|id: u64| RawId::Id(T::from(id)))
+            .unwrap_or(RawId::OutOfRange))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::csv_reader;
+
+    #[test]
+    fn test_ids_that_dont_fit_are_kept_apart() {
+        let csv = "client\n1\n18446744073709551615\n18446744073709551616\n-1\n1.5\nabc\n";
+        #[derive(Debug, Deserialize)]
+        struct Row {
+            client: RawId<ClientId>,
+        }
+
+        let rows: Vec<Option<RawId<ClientId>>> = csv_reader(csv.as_bytes())
+            .deserialize::<Row>()
+            .map(|row| row.ok().map(|row| row.client))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                Some(RawId::Id(ClientId(1))),
+                Some(RawId::Id(ClientId(u64::MAX))),
+                Some(RawId::OutOfRange),
+                Some(RawId::OutOfRange),
+                None,
+                None,
+            ]
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use crate::raw_transaction::RawTransaction;
+use serde_json::value::RawValue;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+/// The transaction sources we know how to read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Csv,
+    /// One JSON object per line, with the same field names as the CSV header.
+    Jsonl,
+}
+
+impl InputFormat {
+    /// Anything that isn't explicitly `.jsonl` is treated as CSV,
+    /// which is what the exercise feeds us.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl") => InputFormat::Jsonl,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+/// Builds the CSV reader we use for every transaction source.
+///
+/// The input may contain whitespace around values and headers
+/// (e.g. `deposit, 1, 1, 1.0`), so we trim everything.
+/// Shared between the binary and the fuzz targets so both
+/// exercise exactly the same parsing rules.
+pub fn csv_reader<R: Read>(rdr: R) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(rdr)
+}
+
+/// Streams `RawTransaction`s out of `rdr` one row at a time.
+///
+/// A row that can't be parsed yields an `Err` and the stream carries on
+/// with the next one, it's up to the caller to decide what to do with it.
+/// Blank lines in JSONL input are skipped.
+pub fn read_transactions<'a, R: Read + 'a>(
+    rdr: R,
+    format: InputFormat,
+) -> Box<dyn Iterator<Item = Result<RawTransaction, Box<dyn Error>>> + 'a> {
+    match format {
+        InputFormat::Csv => Box::new(
+            csv_reader(rdr)
+                .into_deserialize()
+                .map(|row| row.map_err(|e| e.into())),
+        ),
+        InputFormat::Jsonl => Box::new(
+            BufReader::new(rdr)
+                .lines()
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|line| parse_json_line(&line?)),
+        ),
+    }
+}
+
+/// serde_json reads every number as an `f64`, which would quietly round
+/// amounts with more digits than that holds (see `convert.rs`), and can't
+/// tell us about an ID that's too big (see `RawId`).
+/// So numbers in those fields are handed over as the text they were
+/// written as, the same way they come out of a CSV.
+fn parse_json_line(line: &str) -> Result<RawTransaction, Box<dyn Error>> {
+    let mut fields: HashMap<String, Box<RawValue>> = serde_json::from_str(line)?;
+    for name in ["amount", "client", "tx", "to"] {
+        if let Some(value) = fields.get_mut(name)
+            && value
+                .get()
+                .starts_with(|c: char| c == '-' || c.is_ascii_digit())
+        {
+            *value = RawValue::from_string(format!("\"{}\"", value.get()))?;
+        }
+    }
+    Ok(serde_json::from_str(&serde_json::to_string(&fields)?)?)
+}
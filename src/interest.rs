@@ -0,0 +1,193 @@
+use crate::asset::Asset;
+use crate::client::Client;
+use crate::convert::{RawAmount, parse_amount};
+use crate::ids::ClientId;
+use crate::ingest::csv_reader;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Read;
+
+/// Rates are yearly percentages with up to this many decimals, kept in
+/// millionths, so `2.5` is `25000`.
+const RATE_DECIMALS: u32 = 4;
+const RATE_SCALE: i128 = 100 * 10i128.pow(RATE_DECIMALS);
+/// Interest accrues per day, a year always being this many of them.
+const DAYS_PER_YEAR: i128 = 365;
+
+/// The yearly interest rate of every asset that earns any.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InterestRates {
+    rates: BTreeMap<Asset, i128>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RateRow {
+    #[serde(default)]
+    asset: String,
+    rate: String,
+}
+
+impl InterestRates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the rate of `asset`, in millionths per year.
+    pub fn insert(&mut self, asset: Asset, rate: i128) {
+        self.rates.insert(asset, rate);
+    }
+
+    /// Reads a CSV with `asset` and `rate` columns, rates being yearly
+    /// percentages (`USD,2.5`). An empty asset is the default asset.
+    pub fn from_csv<R: Read>(rdr: R) -> Result<Self, Box<dyn Error>> {
+        let mut rates = InterestRates::new();
+        for row in csv_reader(rdr).deserialize() {
+            let row: RateRow = row?;
+            let asset =
+                Asset::new(&row.asset).ok_or(format!("invalid asset code '{}'", row.asset))?;
+            let rate = match parse_amount(&row.rate) {
+                Some(RawAmount::Decimal(rate)) => rate.to_units(RATE_DECIMALS).ok(),
+                _ => None,
+            }
+            .filter(|rate| *rate >= 0)
+            .ok_or(format!("invalid rate '{}'", row.rate))?;
+            rates.insert(asset, rate);
+        }
+        Ok(rates)
+    }
+}
+
+/// Interest a client earned on one of their balances.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accrual {
+    pub client_id: ClientId,
+    pub asset: Asset,
+    /// In units of the asset's smallest fraction.
+    pub amount: i128,
+}
+
+/// What every client earns on their available funds over `days`, at the
+/// yearly `rates`.
+///
+/// That's `available * rate * days / 365` for every balance above zero in
+/// an asset with a rate, worked out exactly and only then rounded to the
+/// asset's smallest fraction, half to even. Balances below zero aren't
+/// charged anything, and accounts whose state doesn't allow deposits (see
+/// `AccountState`) earn nothing. Accruals that round to zero are left out.
+pub fn accrue(clients: &[Client], rates: &InterestRates, days: u64) -> Vec<Accrual> {
+    let mut accruals = Vec::new();
+    for client in clients
+        .iter()
+        .filter(|client| client.state.allows_deposits())
+    {
+        for (asset, balance) in client.balances() {
+            let Some(rate) = rates.rates.get(&asset) else {
+                continue;
+            };
+            let amount = interest(balance.available, *rate, days);
+            if amount > 0 {
+                accruals.push(Accrual {
+                    client_id: client.client_id(),
+                    asset,
+                    amount,
+                });
+            }
+        }
+    }
+    accruals
+}
+
+/// `available * rate * days / (RATE_SCALE * DAYS_PER_YEAR)`, rounded half
+/// to even. Only the part of `available` that doesn't divide evenly has to
+/// be rounded, which keeps the products small enough not to overflow for
+/// any sensible rate and period.
+fn interest(available: i128, rate: i128, days: u64) -> i128 {
+    if available <= 0 {
+        return 0;
+    }
+    let divisor = RATE_SCALE * DAYS_PER_YEAR;
+    let factor = rate.saturating_mul(i128::from(days));
+    let whole = (available / divisor).saturating_mul(factor);
+    let rest = (available % divisor).saturating_mul(factor);
+
+    let (quotient, remainder) = (rest / divisor, rest % divisor);
+    let rounded = match (remainder * 2).cmp(&divisor) {
+        std::cmp::Ordering::Less => quotient,
+        std::cmp::Ordering::Equal => quotient + quotient % 2,
+        std::cmp::Ordering::Greater => quotient + 1,
+    };
+    whole.saturating_add(rounded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::AssetRegistry;
+    use crate::config::Config;
+    use crate::history::NoHistory;
+    use crate::ids::TransactionId;
+    use crate::output::write_accruals;
+    use crate::process::tests::{Clients, Transactions, run_on};
+    use crate::store::AccountStore;
+
+    #[test]
+    fn test_accruals_round_half_to_even_and_can_be_disputed() {
+        // Both runs go through the same stores.
+        let mut transactions = Transactions::new();
+        let mut clients = Clients::new();
+        let mut run = |csv: &str, clients: &mut Clients| {
+            run_on(
+                csv,
+                &Config::default(),
+                &mut transactions,
+                clients,
+                &mut NoHistory,
+            )
+        };
+
+        // 5% over 73 days is exactly 1%: 1 for client 1, and half a unit
+        // for clients 2 and 3, which goes to the even side. Client 4 has
+        // nothing left and client 5 is locked.
+        run(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 100.0\n\
+             deposit, 2, 2, 0.005\n\
+             deposit, 3, 3, 0.015\n\
+             deposit, 4, 4, 10.0\n\
+             withdrawal, 4, 5, 10.0\n\
+             deposit, 5, 6, 10.0\n\
+             deposit, 5, 7, 5.0\n\
+             dispute, 5, 7,\n\
+             chargeback, 5, 7,\n",
+            &mut clients,
+        );
+        let rates = InterestRates::from_csv("asset,rate\n,5\n".as_bytes()).unwrap();
+        let accruals = accrue(&clients.clients().unwrap(), &rates, 73);
+        assert_eq!(
+            accruals
+                .iter()
+                .map(|accrual| (accrual.client_id.0, accrual.amount))
+                .collect::<Vec<_>>(),
+            vec![(1, 10000), (3, 2)]
+        );
+        assert_eq!(interest(i128::MAX, 50000, 73), i128::MAX / 100);
+
+        // They're deposits like any other, so they can be disputed.
+        let mut csv = Vec::new();
+        write_accruals(
+            &mut csv,
+            &accruals,
+            TransactionId(100),
+            1727740740,
+            &AssetRegistry::new(),
+        )
+        .unwrap();
+        let csv = String::from_utf8(csv).unwrap() + "dispute,1,100,,,\n";
+        let summary = run(&csv, &mut clients);
+        assert_eq!(summary.rejected(), 0);
+        let balance = clients[&ClientId(1)].balance(Asset::DEFAULT);
+        assert_eq!((balance.available, balance.held), (1000000, 10000));
+        assert_eq!(clients[&ClientId(3)].balance(Asset::DEFAULT).available, 152);
+    }
+}
@@ -0,0 +1,29 @@
+pub mod asset;
+pub mod clearing;
+pub mod client;
+pub mod compact_store;
+pub mod config;
+pub mod convert;
+pub mod disk_store;
+pub mod expiry;
+pub mod fees;
+pub mod fraud;
+pub mod generate;
+pub mod handlers;
+pub mod history;
+pub mod ids;
+pub mod ingest;
+pub mod interest;
+pub mod lifecycle;
+pub mod log;
+pub mod output;
+pub mod overdraft;
+pub mod process;
+pub mod processed_transaction;
+pub mod raw_transaction;
+pub mod rejection;
+pub mod report;
+pub mod rules;
+pub mod sqlite_store;
+pub mod store;
+pub mod timestamp;
@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+
+/// Where an account is in its life, which decides what it can still do.
+///
+/// Effects are disputes, resolves, chargebacks, refunds and reversals.
+/// Clearing and fees aren't something the client does, so they happen
+/// whatever the state. Neither is settling a dispute that's already open,
+/// so resolves and chargebacks go through whatever the state too.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub enum AccountState {
+    /// Everything goes.
+    #[default]
+    Active,
+    /// Put on hold by an operator, only effects go through.
+    Frozen,
+    /// A chargeback happened, nothing goes through but settling the
+    /// disputes already open.
+    Locked,
+    /// Marked as unused by an operator, it only takes deposits and
+    /// transfers in.
+    Dormant,
+    /// Nothing goes through, for good.
+    Closed,
+}
+
+impl std::fmt::Display for AccountState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AccountState::Active => write!(f, "active"),
+            AccountState::Frozen => write!(f, "frozen"),
+            AccountState::Locked => write!(f, "locked"),
+            AccountState::Dormant => write!(f, "dormant"),
+            AccountState::Closed => write!(f, "closed"),
+        }
+    }
+}
+
+impl AccountState {
+    pub fn allows_deposits(self) -> bool {
+        matches!(self, AccountState::Active | AccountState::Dormant)
+    }
+
+    /// Withdrawals and transfers out.
+    pub fn allows_withdrawals(self) -> bool {
+        self == AccountState::Active
+    }
+
+    pub fn allows_effects(self) -> bool {
+        matches!(self, AccountState::Active | AccountState::Frozen)
+    }
+
+    /// Whether an account can go from this state to `to`.
+    ///
+    /// Only a chargeback locks an account, no row moves one there.
+    /// Anything but a closed account can be closed (as long as it's empty,
+    /// see `Client::transition`) and anything but an active one reactivated.
+    /// Only active accounts go dormant, so it's no way out of being frozen
+    /// or locked, but dormant ones can still be frozen.
+    pub fn can_become(self, to: AccountState) -> bool {
+        use AccountState::*;
+        match (self, to) {
+            (Closed, _) => false,
+            (_, Closed) => true,
+            (Active, Active) => false,
+            (_, Active) => true,
+            (Active | Frozen, Locked) => true,
+            (Active | Dormant, Frozen) => true,
+            (Active, Dormant) => true,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::AssetRegistry;
+    use crate::client::ClientError;
+    use crate::config::Config;
+    use crate::history::{HistoryWriter, state_as_of};
+    use crate::ingest::InputFormat;
+    use crate::output::{AccountColumns, write_accounts};
+    use crate::process::tests::run_with_history;
+    use crate::raw_transaction::RawTransactionType;
+    use crate::rejection::Rejection;
+    use crate::store::AccountStore;
+
+    #[test]
+    fn test_admin_rows_move_accounts_between_states() {
+        let csv = "type,client,tx,amount,reason\n\
+                   deposit,1,1,10.0,\n\
+                   deposit,2,2,5.0,\n\
+                   deposit,3,3,1.0,\n\
+                   freeze,1,100,,\"KYC review, pending\"\n\
+                   withdrawal,1,4,1.0,\n\
+                   dispute,1,1,,\n\
+                   resolve,1,1,,\n\
+                   dormant,1,101,,\n\
+                   activate,1,102,,review done\n\
+                   withdrawal,1,5,1.0,\n\
+                   dormant,2,103,,\n\
+                   deposit,2,6,1.0,\n\
+                   withdrawal,2,7,1.0,\n\
+                   close,2,104,,\n\
+                   close,9,105,,\n\
+                   withdrawal,3,8,1.0,\n\
+                   close,3,106,,moved away\n\
+                   deposit,3,9,1.0,\n\
+                   activate,3,107,,\n\
+                   freeze,1,108,1.0,\n";
+        let mut history = HistoryWriter::new(Vec::new(), true).unwrap();
+        let (summary, _, clients) = run_with_history(csv, &Config::default(), &mut history);
+
+        use AccountState::*;
+        let not_allowed = |state| Rejection::Client(ClientError::NotAllowed(state));
+        let invalid = |from, to| Rejection::Client(ClientError::InvalidTransition(from, to));
+        let mut rejections: Vec<_> = summary.rejections.into_iter().collect();
+        rejections.sort();
+        assert_eq!(
+            rejections,
+            vec![
+                (Rejection::UnexpectedAmount, 1),
+                (Rejection::UnknownClient, 1),
+                (not_allowed(Frozen), 1),
+                (not_allowed(Dormant), 1),
+                (not_allowed(Closed), 1),
+                (invalid(Frozen, Dormant), 1),
+                (invalid(Closed, Active), 1),
+                (Rejection::Client(ClientError::NotEmpty), 1),
+            ]
+        );
+
+        let mut out = Vec::new();
+        let accounts = clients.clients().unwrap();
+        let lifecycle = summary
+            .applied
+            .keys()
+            .any(RawTransactionType::is_state_change);
+        let columns = AccountColumns::new(&Config::default(), &accounts, lifecycle);
+        write_accounts(&mut out, &accounts, &AssetRegistry::new(), columns).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked,state,reason\n\
+             1,9.0000,0.0000,9.0000,false,active,review done\n\
+             2,6.0000,0.0000,6.0000,false,dormant,\n\
+             3,0.0000,0.0000,0.0000,false,closed,moved away\n"
+        );
+
+        // Replaying the history gets to the same states, reasons and all.
+        let bytes = history.into_inner();
+        let snapshot =
+            state_as_of(&bytes[..], InputFormat::Csv, u64::MAX, &Config::default()).unwrap();
+        for client in accounts {
+            let replayed = &snapshot.clients[&client.client_id()];
+            assert_eq!(
+                (replayed.state, &replayed.reason),
+                (client.state, &client.reason)
+            );
+        }
+        assert!(
+            String::from_utf8(bytes)
+                .unwrap()
+                .contains("freeze,1,100,,,,,\"KYC review, pending\"\n")
+        );
+    }
+}
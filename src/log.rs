@@ -0,0 +1,25 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static VERBOSE: AtomicBool = AtomicBool::new(true);
+
+/// Turns the per-row diagnostics on stderr on or off.
+///
+/// They're handy when looking at a handful of rows but they dominate
+/// the runtime (and the benchmarks) on large inputs.
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+/// `eprintln!` that stays quiet unless verbose output is on.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::log::is_verbose() {
+            eprintln!($($arg)*);
+        }
+    };
+}
@@ -1,22 +1,146 @@
-mod client;
-mod convert;
-mod handlers;
-mod processed_transaction;
-mod raw_transaction;
-
-use client::Client;
-use convert::convert_number_to_fractional;
-use handlers::handle_transaction;
-use processed_transaction::ProcessedTransaction;
-use raw_transaction::RawTransaction;
+mod cli;
+
+use cli::{Command, Outputs, StoreKind};
 use std::collections::HashMap;
 use std::env;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
 use std::path::Path;
+use std::time::Instant;
+use tx2acc::asset::AssetRegistry;
+use tx2acc::client::Client;
+use tx2acc::compact_store::CompactTransactionStore;
+use tx2acc::config::Config;
+use tx2acc::disk_store::open_disk_stores;
+use tx2acc::generate::{GeneratorConfig, generate};
+use tx2acc::history::{HISTORY_HEADER, HistoryWriter, NoHistory, Snapshot, state_as_of};
+use tx2acc::ids::{ClientId, TransactionId};
+use tx2acc::ingest::InputFormat;
+use tx2acc::interest::{InterestRates, accrue};
+use tx2acc::output::{
+    AccountColumns, write_accounts, write_accruals, write_chargebacks, write_dispute_statuses,
+    write_dispute_transitions, write_fees, write_fraud_alerts, write_overdrafts, write_report,
+};
+use tx2acc::process::process_transactions;
+use tx2acc::processed_transaction::{DisputeStatus, ProcessedTransaction};
+use tx2acc::raw_transaction::RawTransactionType;
+use tx2acc::report::RunReport;
+use tx2acc::sqlite_store::open_sqlite_stores;
+use tx2acc::store::{AccountStore, TransactionStore};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
-    let input_file = if args.len() > 1 { &args[1] } else { "unknown" };
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match cli::parse_args(&args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return Ok(());
+        }
+    };
+
+    match command {
+        Command::Process {
+            input_file,
+            config,
+            store,
+            outputs,
+            quiet,
+        } => {
+            tx2acc::log::set_verbose(!quiet);
+            let mut clients: HashMap<ClientId, Client> = HashMap::new();
+            match store {
+                StoreKind::HashMap => process(
+                    &input_file,
+                    &config,
+                    &outputs,
+                    &mut HashMap::new(),
+                    &mut clients,
+                    false,
+                ),
+                StoreKind::Compact => process(
+                    &input_file,
+                    &config,
+                    &outputs,
+                    &mut CompactTransactionStore::new(),
+                    &mut clients,
+                    false,
+                ),
+                StoreKind::Disk(state) => {
+                    let (mut transactions, mut clients) = open_disk_stores(state)?;
+                    process(
+                        &input_file,
+                        &config,
+                        &outputs,
+                        &mut transactions,
+                        &mut clients,
+                        true,
+                    )
+                }
+                StoreKind::Sqlite(state) => {
+                    let (mut transactions, mut clients) =
+                        open_sqlite_stores(state, &config.assets)?;
+                    process(
+                        &input_file,
+                        &config,
+                        &outputs,
+                        &mut transactions,
+                        &mut clients,
+                        true,
+                    )
+                }
+            }
+        }
+        Command::AsOf {
+            at,
+            history_file,
+            config,
+            client,
+            disputes,
+        } => {
+            // Replaying is an implementation detail, nobody wants to see it row by row
+            tx2acc::log::set_verbose(false);
+            print_as_of(at, &history_file, &config, client, disputes.as_deref())
+        }
+        Command::Accrue {
+            at,
+            history_file,
+            rates,
+            days,
+            first_tx,
+            config,
+            output,
+        } => {
+            tx2acc::log::set_verbose(false);
+            write_interest(
+                at,
+                &history_file,
+                &rates,
+                days,
+                first_tx,
+                &config,
+                output.as_deref(),
+            )
+        }
+        Command::Generate {
+            config,
+            output,
+            expected,
+        } => generate_transactions(&config, output.as_deref(), expected.as_deref()),
+    }
+}
+
+/// Runs the input through the stores and writes the accounts and reports.
+///
+/// `resumed` is whether the stores carry on from earlier runs (`--state`),
+/// whose rows may have moved accounts to another state.
+fn process(
+    input_file: &str,
+    config: &Config,
+    outputs: &Outputs,
+    transactions: &mut impl TransactionStore,
+    clients: &mut impl AccountStore,
+    resumed: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
     eprintln!("input = {}", input_file);
 
     if !Path::new(input_file).exists() {
@@ -24,41 +148,223 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
-    let mut transactions: HashMap<u32, ProcessedTransaction> = HashMap::new();
-    let mut clients: HashMap<u16, Client> = HashMap::new();
-
+    let started = Instant::now();
     let file = File::open(input_file)?;
-    let mut csv_reader = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .from_reader(file);
-
-    let mut row = 0;
-    for result in csv_reader.deserialize() {
-        row += 1;
-        let raw_tx: RawTransaction = match result {
-            Ok(tx) => tx,
-            Err(e) => {
-                eprintln!("Error parsing row: {}", e);
-                continue;
-            }
-        };
+    let format = InputFormat::from_path(Path::new(input_file));
+    let summary = match &outputs.history {
+        Some(path) => {
+            let mut history = open_history(path)?;
+            process_transactions(file, format, config, transactions, clients, &mut history)?
+        }
+        None => process_transactions(file, format, config, transactions, clients, &mut NoHistory)?,
+    };
+    transactions.flush()?;
+    clients.flush()?;
+    let elapsed = started.elapsed();
 
-        eprintln!("CSV Row {}, {:?}", row, raw_tx);
+    let accounts = clients.clients()?;
+    let lifecycle = resumed
+        || summary
+            .applied
+            .keys()
+            .any(RawTransactionType::is_state_change);
+    write_accounts(
+        io::stdout().lock(),
+        &accounts,
+        &config.assets,
+        AccountColumns::new(config, &accounts, lifecycle),
+    )?;
+    if let Some(path) = &outputs.dispute_report {
+        write_dispute_transitions(
+            BufWriter::new(File::create(path)?),
+            &summary.dispute_transitions,
+        )?;
+    }
+    if let Some(path) = &outputs.overdraft_report {
+        write_overdrafts(
+            BufWriter::new(File::create(path)?),
+            &accounts,
+            &config.assets,
+            &config.overdrafts,
+        )?;
+    }
+
+    if let Some(path) = &outputs.fraud_report {
+        write_fraud_alerts(
+            BufWriter::new(File::create(path)?),
+            &summary.fraud_alerts,
+            &config.assets,
+        )?;
+    }
+    if let Some(path) = &outputs.fee_report {
+        write_fees(
+            BufWriter::new(File::create(path)?),
+            &summary.fees,
+            &config.assets,
+        )?;
+    }
+    if let Some(path) = &outputs.chargeback_report {
+        write_chargebacks(
+            BufWriter::new(File::create(path)?),
+            &summary.chargebacks,
+            &config.assets,
+        )?;
+    }
 
-        handle_transaction(&raw_tx, &mut transactions, &mut clients);
+    let report = RunReport {
+        summary: &summary,
+        accounts: &accounts,
+        open_disputes: transactions.disputed()?.len(),
+        elapsed,
+    };
+    match &outputs.summary {
+        Some(path) => write_report(
+            BufWriter::new(File::create(path)?),
+            &report,
+            outputs.summary_format,
+            &config.assets,
+        )?,
+        None => write_report(
+            io::stderr().lock(),
+            &report,
+            outputs.summary_format,
+            &config.assets,
+        )?,
     }
 
-    println!("client,available,held,total,locked");
-    for (client_id, client) in clients.iter() {
-        let available = convert_number_to_fractional(client.available);
-        let held = convert_number_to_fractional(client.held);
-        let total = convert_number_to_fractional(client.total);
+    Ok(())
+}
 
-        println!(
-            "{},{:.4},{:.4},{:.4},{}",
-            client_id, available, held, total, client.locked
+/// Opens the history for appending, so it carries on across runs sharing `--state`.
+///
+/// A history from before the `to` or `reason` columns were added has a
+/// different header, and rows with more columns wouldn't read back.
+fn open_history(path: &str) -> Result<HistoryWriter<BufWriter<File>>, Box<dyn std::error::Error>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?;
+    let is_new = file.metadata()?.len() == 0;
+    if !is_new {
+        let mut header = String::new();
+        BufReader::new(&file).read_line(&mut header)?;
+        if header.trim_end() != HISTORY_HEADER {
+            return Err(
+                format!("History file {} has an older format, start a new one", path).into(),
+            );
+        }
+    }
+    Ok(HistoryWriter::new(BufWriter::new(file), is_new)?)
+}
+
+/// Replays the history up to `at` and prints the accounts as they were then.
+fn print_as_of(
+    at: u64,
+    history_file: &str,
+    config: &Config,
+    client: Option<ClientId>,
+    disputes: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let format = InputFormat::from_path(Path::new(history_file));
+    let snapshot = state_as_of(File::open(history_file)?, format, at, config)?;
+    warn_rejected_replays(&snapshot);
+
+    let all_clients = snapshot.clients.clients()?;
+    let lifecycle = snapshot
+        .applied
+        .keys()
+        .any(RawTransactionType::is_state_change);
+    let columns = AccountColumns::new(config, &all_clients, lifecycle);
+    let clients: Vec<Client> = all_clients
+        .into_iter()
+        .filter(|c| client.is_none_or(|client_id| c.client_id() == client_id))
+        .collect();
+    write_accounts(io::stdout().lock(), &clients, &config.assets, columns)?;
+
+    if let Some(path) = disputes {
+        let mut transactions: Vec<(TransactionId, ProcessedTransaction)> = snapshot
+            .transactions
+            .into_iter()
+            .filter(|(_, tx)| tx.dispute_status != DisputeStatus::Valid)
+            .filter(|(_, tx)| client.is_none_or(|client_id| tx.client_id == client_id))
+            .collect();
+        transactions.sort_by_key(|(transaction_id, _)| *transaction_id);
+        write_dispute_statuses(BufWriter::new(File::create(path)?), &transactions)?;
+    }
+    Ok(())
+}
+
+/// Replays the history up to `at` and writes the interest every client
+/// earned over `days` as deposits to `output` (stdout if not given).
+///
+/// They are numbered from `first_tx` on, or from right after the highest
+/// transaction ID in the history.
+fn write_interest(
+    at: u64,
+    history_file: &str,
+    rates: &InterestRates,
+    days: u64,
+    first_tx: Option<TransactionId>,
+    config: &Config,
+    output: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let format = InputFormat::from_path(Path::new(history_file));
+    let snapshot = state_as_of(File::open(history_file)?, format, at, config)?;
+    warn_rejected_replays(&snapshot);
+
+    let accruals = accrue(&snapshot.clients.clients()?, rates, days);
+    let first_tx = first_tx.unwrap_or_else(|| {
+        let last = snapshot
+            .transactions
+            .keys()
+            .max()
+            .copied()
+            .unwrap_or_default();
+        TransactionId(last.0.saturating_add(1))
+    });
+
+    let out: Box<dyn Write> = match output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+    write_accruals(BufWriter::new(out), &accruals, first_tx, at, &config.assets)?;
+    eprintln!("{} balances earned interest", accruals.len());
+    Ok(())
+}
+
+/// A history only has rows that went through, so any that don't on the way
+/// back mean it was written with other `--assets` or `--clearing`, or edited.
+fn warn_rejected_replays(snapshot: &Snapshot) {
+    if snapshot.rejected > 0 {
+        eprintln!(
+            "Warning: {} rows of the history were rejected on replay, balances may be off",
+            snapshot.rejected
         );
     }
+}
 
+/// Writes a synthetic stream to `output` (stdout if not given) and,
+/// if asked to, the accounts we expect the engine to produce from it.
+fn generate_transactions(
+    config: &GeneratorConfig,
+    output: Option<&str>,
+    expected: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let out: Box<dyn Write> = match output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+    let clients = generate(config, BufWriter::new(out))?;
+
+    if let Some(path) = expected {
+        let clients = clients.clients()?;
+        write_accounts(
+            BufWriter::new(File::create(path)?),
+            &clients,
+            &AssetRegistry::new(),
+            AccountColumns::new(&Config::default(), &clients, false),
+        )?;
+    }
     Ok(())
 }
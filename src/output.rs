@@ -0,0 +1,430 @@
+use crate::asset::{Asset, AssetRegistry};
+use crate::client::Client;
+use crate::config::Config;
+use crate::convert::format_units;
+use crate::expiry::DisputeTransition;
+use crate::fraud::FraudAlert;
+use crate::ids::{ClientId, TransactionId};
+use crate::interest::Accrual;
+use crate::overdraft::OverdraftLimits;
+use crate::process::ChargebackTotal;
+use crate::processed_transaction::ProcessedTransaction;
+use crate::report::{ReportFormat, RunReport};
+use crate::timestamp::format_timestamp;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// `value` as a CSV field, quoted if it has a comma, quote or line break in it.
+pub fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// The columns of the accounts table that the exercise doesn't have, so
+/// that simple runs keep its exact format.
+///
+/// They go by how the run was set up rather than by the balances, so runs
+/// set up the same way always write the same header.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AccountColumns {
+    /// `asset`, right after `client`.
+    pub asset: bool,
+    /// `pending`, right after `available`.
+    pub pending: bool,
+    /// `state` and `reason`, at the end.
+    pub state: bool,
+}
+
+impl AccountColumns {
+    /// The columns for accounts that went through rows under `config`.
+    ///
+    /// `asset` is there with `--assets`, and otherwise only once some
+    /// client holds something other than the default asset, since the
+    /// table has a row per client and asset and they couldn't be told apart
+    /// without it. `pending` is there with `--clearing` (see `ClearingPeriod`),
+    /// and `state` with `lifecycle`, which callers set when rows may have
+    /// moved an account to another state (see `AccountState`).
+    pub fn new(config: &Config, clients: &[Client], lifecycle: bool) -> Self {
+        AccountColumns {
+            asset: !config.assets.is_empty()
+                || clients
+                    .iter()
+                    .any(|client| client.balances.keys().any(|asset| !asset.is_default())),
+            pending: config.clearing.is_some(),
+            state: lifecycle,
+        }
+    }
+}
+
+/// Writes the accounts table in the format described in the README,
+/// one row per client and asset, each with as many decimals as `assets`
+/// says the asset has, and the optional `columns`. `locked` is whether
+/// the account is locked.
+///
+/// Ordering isn't important for the exercise, but outputs are only diffable
+/// (e.g. against a generator's expected output) if `clients` is sorted by
+/// client ID, which is what `AccountStore::clients` hands out.
+pub fn write_accounts<W: Write>(
+    mut w: W,
+    clients: &[Client],
+    assets: &AssetRegistry,
+    columns: AccountColumns,
+) -> io::Result<()> {
+    let AccountColumns {
+        asset: with_asset,
+        pending: with_pending,
+        state: with_state,
+    } = columns;
+
+    write!(w, "client,")?;
+    if with_asset {
+        write!(w, "asset,")?;
+    }
+    write!(w, "available,")?;
+    if with_pending {
+        write!(w, "pending,")?;
+    }
+    write!(w, "held,total,locked")?;
+    if with_state {
+        write!(w, ",state,reason")?;
+    }
+    writeln!(w)?;
+    for client in clients {
+        for (asset, balance) in client.balances() {
+            let decimals = assets.decimals(asset);
+            let available = format_units(balance.available, decimals);
+            let held = format_units(balance.held, decimals);
+            let total = format_units(balance.total, decimals);
+
+            write!(w, "{},", client.client_id())?;
+            if with_asset {
+                write!(w, "{},", asset)?;
+            }
+            write!(w, "{},", available)?;
+            if with_pending {
+                write!(w, "{},", format_units(balance.pending, decimals))?;
+            }
+            write!(w, "{},{},{}", held, total, client.is_locked())?;
+            if with_state {
+                let reason = client.reason.as_deref().map(csv_field).unwrap_or_default();
+                write!(w, ",{},{}", client.state, reason)?;
+            }
+            writeln!(w)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes every balance that is below zero, along with how far below
+/// zero the client may go in that asset.
+pub fn write_overdrafts<W: Write>(
+    mut w: W,
+    clients: &[Client],
+    assets: &AssetRegistry,
+    overdrafts: &OverdraftLimits,
+) -> io::Result<()> {
+    writeln!(w, "client,asset,available,limit")?;
+    for client in clients {
+        for (asset, balance) in client.balances() {
+            if balance.available >= 0 {
+                continue;
+            }
+            let decimals = assets.decimals(asset);
+            writeln!(
+                w,
+                "{},{},{},{}",
+                client.client_id(),
+                asset,
+                format_units(balance.available, decimals),
+                format_units(overdrafts.limit(client.client_id(), asset), decimals)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the disputes whose status changed on their own, in the order it happened.
+pub fn write_dispute_transitions<W: Write>(
+    mut w: W,
+    transitions: &[DisputeTransition],
+) -> io::Result<()> {
+    writeln!(w, "tx,client,from,to,at")?;
+    for transition in transitions {
+        writeln!(
+            w,
+            "{},{},{},{},{}",
+            transition.transaction_id,
+            transition.client_id,
+            transition.from,
+            transition.to,
+            format_timestamp(transition.at)
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes every chargeback of a deposit that had already been withdrawn,
+/// in the order they happened. `exposure` is how much of the deposit was
+/// withdrawn, `score` the same as a percentage of it.
+pub fn write_fraud_alerts<W: Write>(
+    mut w: W,
+    alerts: &[FraudAlert],
+    assets: &AssetRegistry,
+) -> io::Result<()> {
+    writeln!(w, "client,tx,asset,amount,exposure,score,at,reason")?;
+    for alert in alerts {
+        let decimals = assets.decimals(alert.asset);
+        writeln!(
+            w,
+            "{},{},{},{},{},{},{},{}",
+            alert.client_id,
+            alert.transaction_id,
+            alert.asset,
+            format_units(alert.amount, decimals),
+            format_units(alert.exposure, decimals),
+            alert.score(),
+            alert.at.map(format_timestamp).unwrap_or_default(),
+            alert
+                .reason_code
+                .as_deref()
+                .map(csv_field)
+                .unwrap_or_default()
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes what each client paid in fees, per asset (see `FeeSchedule`).
+pub fn write_fees<W: Write>(
+    mut w: W,
+    fees: &BTreeMap<(ClientId, Asset), i128>,
+    assets: &AssetRegistry,
+) -> io::Result<()> {
+    writeln!(w, "client,asset,fees")?;
+    for ((client_id, asset), amount) in fees {
+        writeln!(
+            w,
+            "{},{},{}",
+            client_id,
+            asset,
+            format_units(*amount, assets.decimals(*asset))
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes how many chargebacks there were for each reason code and asset,
+/// and what they took away. Chargebacks without a code have an empty `reason`.
+pub fn write_chargebacks<W: Write>(
+    mut w: W,
+    chargebacks: &BTreeMap<(Option<String>, Asset), ChargebackTotal>,
+    assets: &AssetRegistry,
+) -> io::Result<()> {
+    writeln!(w, "reason,asset,chargebacks,amount")?;
+    for ((reason_code, asset), total) in chargebacks {
+        writeln!(
+            w,
+            "{},{},{},{}",
+            reason_code.as_deref().map(csv_field).unwrap_or_default(),
+            asset,
+            total.count,
+            format_units(total.amount, assets.decimals(*asset))
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes accruals as deposits in the input format, numbered from
+/// `first_tx` on and all happening `at`, to be fed to the engine.
+pub fn write_accruals<W: Write>(
+    mut w: W,
+    accruals: &[Accrual],
+    first_tx: TransactionId,
+    at: u64,
+    assets: &AssetRegistry,
+) -> io::Result<()> {
+    writeln!(w, "type,client,tx,amount,timestamp,asset")?;
+    for (transaction_id, accrual) in (first_tx.0..).zip(accruals) {
+        writeln!(
+            w,
+            "deposit,{},{},{},{},{}",
+            accrual.client_id,
+            transaction_id,
+            format_units(accrual.amount, assets.decimals(accrual.asset)),
+            at,
+            accrual.asset
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes where each transaction stands with regards to disputes, and the
+/// reason code it got along the way, if any.
+pub fn write_dispute_statuses<W: Write>(
+    mut w: W,
+    transactions: &[(TransactionId, ProcessedTransaction)],
+) -> io::Result<()> {
+    writeln!(w, "tx,client,status,reason")?;
+    for (transaction_id, transaction) in transactions {
+        writeln!(
+            w,
+            "{},{},{},{}",
+            transaction_id,
+            transaction.client_id,
+            transaction.dispute_status,
+            transaction
+                .reason_code
+                .as_deref()
+                .map(csv_field)
+                .unwrap_or_default()
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes what happened during a run, see `RunReport`.
+pub fn write_report<W: Write>(
+    w: W,
+    report: &RunReport,
+    format: ReportFormat,
+    assets: &AssetRegistry,
+) -> io::Result<()> {
+    match format {
+        ReportFormat::Human => write_human_report(w, report, assets),
+        ReportFormat::Json => write_json_report(w, report, assets),
+    }
+}
+
+fn write_human_report<W: Write>(
+    mut w: W,
+    report: &RunReport,
+    assets: &AssetRegistry,
+) -> io::Result<()> {
+    let summary = report.summary;
+    writeln!(
+        w,
+        "Processed {} rows: {} could not be parsed, {} were rejected",
+        summary.rows,
+        summary.parse_failures,
+        summary.rejected()
+    )?;
+    for (rejection, count) in &summary.rejections {
+        writeln!(w, "  {}: {}", rejection, count)?;
+    }
+    writeln!(
+        w,
+        "Took {:.3}s, {:.0} rows per second",
+        report.elapsed.as_secs_f64(),
+        report.throughput()
+    )?;
+    if !summary.applied.is_empty() {
+        writeln!(w, "Applied:")?;
+        for (transaction_type, count) in &summary.applied {
+            writeln!(w, "  {}: {}", transaction_type, count)?;
+        }
+    }
+    for (asset, totals) in report.totals() {
+        let decimals = assets.decimals(asset);
+        writeln!(
+            w,
+            "{}: {} deposited, {} withdrawn, {} held, {} charged back",
+            if asset.is_default() {
+                "Default asset".to_string()
+            } else {
+                asset.to_string()
+            },
+            format_units(totals.deposited, decimals),
+            format_units(totals.withdrawn, decimals),
+            format_units(totals.held, decimals),
+            format_units(totals.charged_back, decimals)
+        )?;
+    }
+    writeln!(
+        w,
+        "{} clients are locked, {} disputes are still open",
+        report.locked_clients(),
+        report.open_disputes
+    )?;
+    if !summary.dispute_transitions.is_empty() {
+        writeln!(
+            w,
+            "{} disputes were resolved after their deadline",
+            summary.dispute_transitions.len()
+        )?;
+    }
+    if !summary.fraud_alerts.is_empty() {
+        writeln!(
+            w,
+            "{} chargebacks were for deposits that had already been withdrawn",
+            summary.fraud_alerts.len()
+        )?;
+    }
+    if !summary.fees.is_empty() {
+        writeln!(w, "{} balances were charged fees", summary.fees.len())?;
+    }
+    if !summary.chargebacks.is_empty() {
+        writeln!(w, "Chargebacks by reason code:")?;
+        for (reason_code, count) in report.chargebacks_by_reason() {
+            writeln!(w, "  {}: {}", reason_code.unwrap_or("none"), count)?;
+        }
+    }
+    Ok(())
+}
+
+/// The same as a JSON object. Assets and reason codes are keys, the
+/// default asset and no reason code being `""`.
+fn write_json_report<W: Write>(
+    mut w: W,
+    report: &RunReport,
+    assets: &AssetRegistry,
+) -> io::Result<()> {
+    let summary = report.summary;
+    let totals: serde_json::Map<String, serde_json::Value> = report
+        .totals()
+        .into_iter()
+        .map(|(asset, totals)| {
+            let decimals = assets.decimals(asset);
+            let totals = serde_json::json!({
+                "deposited": format_units(totals.deposited, decimals),
+                "withdrawn": format_units(totals.withdrawn, decimals),
+                "held": format_units(totals.held, decimals),
+                "charged_back": format_units(totals.charged_back, decimals),
+            });
+            (asset.to_string(), totals)
+        })
+        .collect();
+    let json = serde_json::json!({
+        "rows": summary.rows,
+        "parse_failures": summary.parse_failures,
+        "rejected": summary.rejected(),
+        "applied": summary
+            .applied
+            .iter()
+            .map(|(transaction_type, count)| (transaction_type.to_string(), *count))
+            .collect::<BTreeMap<_, _>>(),
+        "rejections": summary
+            .rejections
+            .iter()
+            .map(|(rejection, count)| (rejection.to_string(), *count))
+            .collect::<BTreeMap<_, _>>(),
+        "totals": totals,
+        "locked_clients": report.locked_clients(),
+        "open_disputes": report.open_disputes,
+        "disputes_resolved_after_deadline": summary.dispute_transitions.len(),
+        "fraud_alerts": summary.fraud_alerts.len(),
+        "balances_charged_fees": summary.fees.len(),
+        "chargebacks_by_reason": report
+            .chargebacks_by_reason()
+            .into_iter()
+            .map(|(reason_code, count)| (reason_code.unwrap_or_default(), count))
+            .collect::<BTreeMap<_, _>>(),
+        "elapsed_seconds": report.elapsed.as_secs_f64(),
+        "rows_per_second": report.throughput(),
+    });
+    serde_json::to_writer_pretty(&mut w, &json)?;
+    writeln!(w)
+}
@@ -0,0 +1,167 @@
+use crate::asset::{Asset, AssetRegistry};
+use crate::convert::{RawAmount, UnitsError, parse_amount};
+use crate::ids::ClientId;
+use crate::ingest::csv_reader;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::io::Read;
+
+/// How far below zero withdrawals may take a client's available balance.
+///
+/// Limits are per client and asset, with a fallback per asset for clients
+/// that don't have one of their own. Anything not covered can't go below
+/// zero at all, which is what the exercise asked for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OverdraftLimits {
+    clients: HashMap<(ClientId, Asset), i128>,
+    defaults: BTreeMap<Asset, i128>,
+    unlimited: bool,
+}
+
+/// Whether a dispute may take the available balance below zero, which it
+/// does when the disputed deposit has already been spent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DisputeOverdraft {
+    /// As far as it needs to. Holding the funds matters more than the balance.
+    #[default]
+    Allow,
+    /// Only as far as the client's overdraft limit.
+    WithinLimit,
+    /// Not at all.
+    Never,
+}
+
+#[derive(Debug, Deserialize)]
+struct LimitRow {
+    client: String,
+    #[serde(default)]
+    asset: String,
+    limit: String,
+}
+
+impl OverdraftLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// No limit for anyone. For replaying events that were already
+    /// accepted under whatever limits applied back then.
+    pub fn unlimited() -> Self {
+        OverdraftLimits {
+            unlimited: true,
+            ..Self::default()
+        }
+    }
+
+    /// Sets the limit of `client`, or the default one if `None`, in units
+    /// of the asset's smallest fraction.
+    pub fn insert(&mut self, client: Option<ClientId>, asset: Asset, limit: i128) {
+        match client {
+            Some(client_id) => self.clients.insert((client_id, asset), limit),
+            None => self.defaults.insert(asset, limit),
+        };
+    }
+
+    /// How far below zero `client_id` may go in `asset`, in units of
+    /// its smallest fraction.
+    pub fn limit(&self, client_id: ClientId, asset: Asset) -> i128 {
+        if self.unlimited {
+            return i128::MAX;
+        }
+        self.clients
+            .get(&(client_id, asset))
+            .or_else(|| self.defaults.get(&asset))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Reads a CSV with `client`, `asset` and `limit` columns, limits being
+    /// in whole units of the asset. A client of `*` sets the default for
+    /// the asset, an empty asset is the default asset.
+    pub fn from_csv<R: Read>(rdr: R, assets: &AssetRegistry) -> Result<Self, Box<dyn Error>> {
+        let mut limits = OverdraftLimits::new();
+        for row in csv_reader(rdr).deserialize() {
+            let row: LimitRow = row?;
+            let client = match row.client.as_str() {
+                "*" => None,
+                id => Some(ClientId(
+                    id.parse()
+                        .map_err(|_| format!("invalid client '{}'", row.client))?,
+                )),
+            };
+            let asset =
+                Asset::new(&row.asset).ok_or(format!("invalid asset code '{}'", row.asset))?;
+            let limit = match parse_amount(&row.limit) {
+                Some(RawAmount::Decimal(limit)) => limit,
+                _ => return Err(format!("invalid limit '{}'", row.limit).into()),
+            };
+            let limit = match limit.to_units(assets.decimals(asset)) {
+                Ok(limit) if limit >= 0 => limit,
+                Ok(_) => return Err(format!("negative limit '{}'", row.limit).into()),
+                Err(UnitsError::TooPrecise) => {
+                    return Err(
+                        format!("limit '{}' has more decimals than {}", row.limit, asset).into(),
+                    );
+                }
+                Err(UnitsError::Overflow) => {
+                    return Err(format!("limit '{}' is too large", row.limit).into());
+                }
+            };
+            limits.insert(client, asset, limit);
+        }
+        Ok(limits)
+    }
+}
+
+impl DisputeOverdraft {
+    /// How far below zero a dispute may take `client_id`'s available
+    /// balance in `asset`, `None` meaning there's no limit.
+    pub fn limit(
+        self,
+        limits: &OverdraftLimits,
+        client_id: ClientId,
+        asset: Asset,
+    ) -> Option<i128> {
+        match self {
+            DisputeOverdraft::Allow => None,
+            DisputeOverdraft::WithinLimit => Some(limits.limit(client_id, asset)),
+            DisputeOverdraft::Never => Some(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overdraft_limits() {
+        let usd = Asset::new("USD").unwrap();
+        let mut assets = AssetRegistry::new();
+        assets.insert(usd, 2).unwrap();
+
+        let csv = "client, asset, limit\n\
+                   *, USD, 100\n\
+                   7, USD, 2500.50\n\
+                   7, , 1\n";
+        let limits = OverdraftLimits::from_csv(csv.as_bytes(), &assets).unwrap();
+        assert_eq!(limits.limit(ClientId(7), usd), 250050);
+        assert_eq!(limits.limit(ClientId(8), usd), 10000);
+        assert_eq!(limits.limit(ClientId(7), Asset::DEFAULT), 10000);
+        assert_eq!(limits.limit(ClientId(8), Asset::DEFAULT), 0);
+        assert_eq!(
+            OverdraftLimits::unlimited().limit(ClientId(8), usd),
+            i128::MAX
+        );
+
+        for bad in ["x, USD, 1", "1, USD, 0.001", "1, USD, -1", "1, USD, NaN"] {
+            let csv = format!("client, asset, limit\n{}\n", bad);
+            assert!(
+                OverdraftLimits::from_csv(csv.as_bytes(), &assets).is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+}
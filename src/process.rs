@@ -0,0 +1,348 @@
+use crate::asset::Asset;
+use crate::clearing::PendingDeposits;
+use crate::config::Config;
+use crate::convert::{Decimal, RawAmount};
+use crate::expiry::{DisputeTransition, OpenDisputes};
+use crate::fraud::FraudAlert;
+use crate::handlers::{HandleError, handle_transaction, row_asset, transfer_amount};
+use crate::history::{History, HistoryEvent};
+use crate::ids::ClientId;
+use crate::ingest::{InputFormat, read_transactions};
+use crate::raw_transaction::RawTransactionType;
+use crate::rejection::Rejection;
+use crate::rules::RuleState;
+use crate::store::{AccountStore, StoreError, TransactionStore};
+use crate::trace;
+use std::collections::BTreeMap;
+use std::io::Read;
+
+/// What happened to the rows of a run, besides the resulting balances.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub rows: usize,
+    pub parse_failures: usize,
+    /// Rows that went through, per type.
+    pub applied: BTreeMap<RawTransactionType, usize>,
+    pub rejections: BTreeMap<Rejection, usize>,
+    /// What the deposits that went through added up to, per asset.
+    pub deposited: BTreeMap<Asset, i128>,
+    /// Same for withdrawals. Transfers are in neither.
+    pub withdrawn: BTreeMap<Asset, i128>,
+    /// Disputes resolved because they were open past the deadline.
+    pub dispute_transitions: Vec<DisputeTransition>,
+    /// Chargebacks of deposits that had already been withdrawn.
+    pub fraud_alerts: Vec<FraudAlert>,
+    /// What each client paid in fees, per asset, see `FeeSchedule`.
+    pub fees: BTreeMap<(ClientId, Asset), i128>,
+    /// Chargebacks per reason code (see `ProcessedTransaction::reason_code`)
+    /// and asset.
+    pub chargebacks: BTreeMap<(Option<String>, Asset), ChargebackTotal>,
+}
+
+/// How many chargebacks there were for a reason code, and what they took away.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChargebackTotal {
+    pub count: usize,
+    /// In units of the asset's smallest fraction.
+    pub amount: i128,
+}
+
+impl Summary {
+    pub fn rejected(&self) -> usize {
+        self.rejections.values().sum()
+    }
+}
+
+/// Reads every row from `rdr` and applies it with `handle_transaction`.
+///
+/// A bad row should never abort the run, so rows that don't parse
+/// or get rejected are only counted in the returned `Summary`.
+/// A failing store does abort it, since we can't trust the state anymore.
+///
+/// Rows with a timestamp move time forward: before such a row is applied,
+/// disputes open for longer than `config.dispute_deadline` are resolved.
+///
+/// With `config.clearing`, deposits go into pending and are made available
+/// once enough rows or time have gone by.
+///
+/// Deposits and withdrawals have to pass `config.rules` before they get
+/// to the handlers, and so do transfers, as withdrawals of their sender.
+///
+/// Everything that got applied, those automatic resolutions included,
+/// is recorded in `history`, with the fees charged by `config.fees` as
+/// `fee` rows after the row they were for and the funds that cleared as
+/// `clear` rows before the row that made them.
+pub fn process_transactions<R: Read>(
+    rdr: R,
+    format: InputFormat,
+    config: &Config,
+    transactions: &mut impl TransactionStore,
+    clients: &mut impl AccountStore,
+    history: &mut impl History,
+) -> Result<Summary, StoreError> {
+    let mut summary = Summary::default();
+    let mut open_disputes = OpenDisputes::load(config, transactions)?;
+    let mut rule_state = RuleState::new();
+    let mut pending_deposits = PendingDeposits::load(config.clearing, clients)?;
+
+    for result in read_transactions(rdr, format) {
+        summary.rows += 1;
+        let raw_tx = match result {
+            Ok(tx) => tx,
+            Err(e) => {
+                trace!("Error parsing row {}: {}", summary.rows, e);
+                summary.parse_failures += 1;
+                // Still a row as far as clearing goes.
+                pending_deposits.advance(None);
+                continue;
+            }
+        };
+
+        trace!("Row {}, {:?}", summary.rows, raw_tx);
+
+        pending_deposits.advance(raw_tx.timestamp);
+        for cleared in pending_deposits.clear(transactions, clients)? {
+            history.record(HistoryEvent {
+                transaction_type: RawTransactionType::Clear,
+                client_id: cleared.client_id,
+                transaction_id: cleared.transaction_id,
+                amount: Some(Decimal::from_units(
+                    cleared.amount,
+                    config.assets.decimals(cleared.asset),
+                )),
+                timestamp: raw_tx.timestamp,
+                asset: cleared.asset,
+                destination: None,
+                reason: None,
+            })?;
+        }
+
+        if let Some(now) = raw_tx.timestamp {
+            for transition in open_disputes.expire(now, transactions, clients)? {
+                history.record(HistoryEvent {
+                    transaction_type: RawTransactionType::Resolve,
+                    client_id: transition.client_id,
+                    transaction_id: transition.transaction_id,
+                    amount: None,
+                    timestamp: Some(transition.at),
+                    asset: transition.asset,
+                    destination: None,
+                    reason: None,
+                })?;
+                summary.dispute_transitions.push(transition);
+            }
+        }
+
+        let result = rule_state
+            .check(&raw_tx, config)
+            .map_err(HandleError::Rejected)
+            .and_then(|()| handle_transaction(&raw_tx, config, transactions, clients));
+        match result {
+            Ok(applied) => {
+                rule_state.record(&raw_tx, config);
+                *summary
+                    .applied
+                    .entry(raw_tx.transaction_type.clone())
+                    .or_insert(0) += 1;
+                summary.fraud_alerts.extend(applied.fraud_alert);
+                if let Some(chargeback) = applied.chargeback {
+                    let total = summary
+                        .chargebacks
+                        .entry((chargeback.reason_code, chargeback.asset))
+                        .or_default();
+                    total.count += 1;
+                    total.amount = total.amount.saturating_add(chargeback.amount);
+                }
+                // It was accepted, so the IDs and the asset code are valid.
+                let client_id = raw_tx.client_id.get().unwrap_or_default();
+                let transaction_id = raw_tx.transaction_id.get().unwrap_or_default();
+                match raw_tx.transaction_type {
+                    RawTransactionType::Dispute => {
+                        open_disputes.track(transaction_id, raw_tx.timestamp)
+                    }
+                    RawTransactionType::Deposit => {
+                        let asset = row_asset(&raw_tx).unwrap_or_default();
+                        pending_deposits.track(client_id, transaction_id, asset);
+                        if let Ok(amount) = transfer_amount(&raw_tx, config, asset) {
+                            let deposited = summary.deposited.entry(asset).or_insert(0);
+                            *deposited = deposited.saturating_add(amount);
+                        }
+                    }
+                    RawTransactionType::Withdrawal => {
+                        let asset = row_asset(&raw_tx).unwrap_or_default();
+                        if let Ok(amount) = transfer_amount(&raw_tx, config, asset) {
+                            let withdrawn = summary.withdrawn.entry(asset).or_insert(0);
+                            *withdrawn = withdrawn.saturating_add(amount);
+                        }
+                    }
+                    _ => {}
+                }
+                history.record(HistoryEvent {
+                    transaction_type: raw_tx.transaction_type.clone(),
+                    client_id,
+                    transaction_id,
+                    amount: match raw_tx.amount {
+                        Some(RawAmount::Decimal(amount)) => Some(amount),
+                        _ => None,
+                    },
+                    timestamp: raw_tx.timestamp,
+                    asset: raw_tx
+                        .asset
+                        .as_deref()
+                        .and_then(Asset::new)
+                        .unwrap_or_default(),
+                    destination: raw_tx.destination.and_then(|destination| destination.get()),
+                    reason: raw_tx.reason.clone(),
+                })?;
+                if let Some(fee) = applied.fee {
+                    // A fee row is a line of its own already.
+                    if raw_tx.transaction_type != RawTransactionType::Fee {
+                        history.record(fee.history_event(raw_tx.timestamp, &config.assets))?;
+                    }
+                    let fees = summary.fees.entry((fee.client_id, fee.asset)).or_insert(0);
+                    *fees = fees.saturating_add(fee.amount);
+                }
+            }
+            Err(HandleError::Rejected(rejection)) => {
+                trace!("Rejected row {}: {}", summary.rows, rejection);
+                *summary.rejections.entry(rejection).or_insert(0) += 1;
+            }
+            Err(HandleError::Store(e)) => return Err(e),
+        }
+        transactions.row_done()?;
+        clients.row_done()?;
+    }
+
+    history.flush()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::compact_store::CompactTransactionStore;
+    use crate::history::NoHistory;
+    use crate::ids::TransactionId;
+    use crate::processed_transaction::ProcessedTransaction;
+    use std::collections::HashMap;
+
+    pub type Transactions = HashMap<TransactionId, ProcessedTransaction>;
+    pub type Clients = HashMap<ClientId, Client>;
+
+    /// Runs the rows in `csv` through fresh in-memory stores, for tests
+    /// that only care about what a run does to them.
+    pub fn run(csv: &str, config: &Config) -> (Summary, Transactions, Clients) {
+        run_with_history(csv, config, &mut NoHistory)
+    }
+
+    /// `run`, recording into `history`.
+    pub fn run_with_history(
+        csv: &str,
+        config: &Config,
+        history: &mut impl History,
+    ) -> (Summary, Transactions, Clients) {
+        let mut transactions = Transactions::new();
+        let mut clients = Clients::new();
+        let summary = run_on(csv, config, &mut transactions, &mut clients, history);
+        (summary, transactions, clients)
+    }
+
+    /// Runs the rows in `csv` through the given stores.
+    pub fn run_on(
+        csv: &str,
+        config: &Config,
+        transactions: &mut impl TransactionStore,
+        clients: &mut impl AccountStore,
+        history: &mut impl History,
+    ) -> Summary {
+        process_transactions(
+            csv.as_bytes(),
+            InputFormat::Csv,
+            config,
+            transactions,
+            clients,
+            history,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_chargebacks_are_counted_by_reason_code() {
+        // The compact store keeps reason codes on the side
+        let mut transactions = CompactTransactionStore::new();
+
+        // Tx 1 keeps the code of its dispute, tx 2 gets a new one with
+        // its chargeback and tx 3 never had any.
+        let csv = "type,client,tx,amount,reason\n\
+                   deposit,1,1,10.0,\n\
+                   deposit,2,2,5.0,\n\
+                   deposit,3,3,1.0,\n\
+                   dispute,1,1,,4837\n\
+                   chargeback,1,1,,\n\
+                   dispute,2,2,2.0,10.4\n\
+                   dispute,2,2,,\n\
+                   chargeback,2,2,,4837\n\
+                   dispute,3,3,,\n\
+                   chargeback,3,3,,\n";
+        let summary = run_on(
+            csv,
+            &Config::default(),
+            &mut transactions,
+            &mut Clients::new(),
+            &mut NoHistory,
+        );
+
+        assert_eq!(summary.rejected(), 0);
+        let reason_code = |transaction_id| {
+            transactions
+                .get(TransactionId(transaction_id))
+                .unwrap()
+                .unwrap()
+                .reason_code
+        };
+        assert_eq!(reason_code(1).as_deref(), Some("4837"));
+        assert_eq!(reason_code(2).as_deref(), Some("4837"));
+        assert_eq!(reason_code(3), None);
+        assert_eq!(
+            summary.chargebacks.into_iter().collect::<Vec<_>>(),
+            vec![
+                (
+                    (None, Asset::DEFAULT),
+                    ChargebackTotal {
+                        count: 1,
+                        amount: 10000
+                    }
+                ),
+                (
+                    (Some("4837".to_string()), Asset::DEFAULT),
+                    ChargebackTotal {
+                        count: 2,
+                        amount: 150000
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expired_disputes_are_recorded_in_their_asset() {
+        let mut history: Vec<HistoryEvent> = Vec::new();
+        let config = Config {
+            dispute_deadline: Some(100),
+            ..Config::default()
+        };
+
+        let csv = "type, client, tx, amount, timestamp, asset\n\
+                   deposit, 1, 1, 10.0, 1000, BTC\n\
+                   dispute, 1, 1, , 1100,\n\
+                   deposit, 1, 2, 1.0, 1300,\n";
+        run_with_history(csv, &config, &mut history);
+
+        let resolve = history
+            .iter()
+            .find(|event| event.transaction_type == RawTransactionType::Resolve)
+            .unwrap();
+        assert_eq!(resolve.asset, Asset::new("BTC").unwrap());
+    }
+}
@@ -1,10 +1,18 @@
-#[derive(Debug, Clone)]
+use crate::asset::Asset;
+use crate::ids::ClientId;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum ProcessedTransactionType {
     Deposit,
     Withdrawal,
+    /// From the transaction's client to another one.
+    Transfer {
+        to: ClientId,
+    },
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum DisputeStatus {
     Valid,
     Disputed,
@@ -12,28 +20,93 @@ pub enum DisputeStatus {
     ChargedBack,
 }
 
-#[derive(Debug, Clone)]
+impl std::fmt::Display for DisputeStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DisputeStatus::Valid => write!(f, "valid"),
+            DisputeStatus::Disputed => write!(f, "disputed"),
+            DisputeStatus::Resolved => write!(f, "resolved"),
+            DisputeStatus::ChargedBack => write!(f, "charged_back"),
+        }
+    }
+}
+
+/// A deposit/withdrawal/transfer we've applied. The transaction ID isn't in here,
+/// it's the key it is stored under (see `TransactionStore`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ProcessedTransaction {
     pub transaction_type: ProcessedTransactionType,
     pub dispute_status: DisputeStatus,
-    pub client_id: u16,
-    pub transaction_id: u32,
-    pub amount: i64,
+    pub client_id: ClientId,
+    pub asset: Asset,
+    pub amount: i128,
+    /// How much of `amount` is under dispute. Disputes may be for part of
+    /// the transaction, and several of them can add up, never past `amount`.
+    pub disputed: i128,
+    /// How much of `amount` was refunded, or all that wasn't charged back
+    /// once reversed. That part can't be disputed anymore.
+    pub refunded: i128,
+    /// How much of `amount` was charged back. Can't be disputed anymore either.
+    pub charged_back: i128,
+    /// How much of a deposit hasn't cleared yet (see `ClearingPeriod`) and
+    /// isn't held by a dispute either.
+    pub pending: i128,
+    /// When the transaction happened, if the input said.
+    pub timestamp: Option<u64>,
+    /// When it was last disputed, if it was and a dispute had a timestamp.
+    pub disputed_at: Option<u64>,
+    /// The reason code (e.g. a card network's dispute code) of the last
+    /// dispute, resolve or chargeback that had one.
+    pub reason_code: Option<String>,
 }
 
 impl ProcessedTransaction {
     pub fn new(
-        transaction_id: u32,
-        client_id: u16,
-        amount: i64,
+        client_id: ClientId,
+        amount: i128,
         transaction_type: ProcessedTransactionType,
     ) -> Self {
         Self {
-            transaction_id,
             client_id,
+            asset: Asset::DEFAULT,
             transaction_type,
             amount,
+            disputed: 0,
+            refunded: 0,
+            charged_back: 0,
+            pending: 0,
             dispute_status: DisputeStatus::Valid,
+            timestamp: None,
+            disputed_at: None,
+            reason_code: None,
+        }
+    }
+
+    /// What can still be disputed: whatever isn't under dispute, refunded
+    /// or charged back. A resolved dispute gives its part back.
+    pub fn undisputed(&self) -> i128 {
+        self.amount - self.disputed - self.refunded - self.charged_back
+    }
+
+    /// Closes the dispute once a resolve or chargeback left nothing of the
+    /// transaction under it. It's charged back if any of it ever was.
+    pub fn settle(&mut self) {
+        if self.disputed == 0 {
+            self.dispute_status = if self.charged_back > 0 {
+                DisputeStatus::ChargedBack
+            } else {
+                DisputeStatus::Resolved
+            };
+        }
+    }
+
+    /// The client whose funds a dispute of the transaction holds. That's
+    /// its own client, except for a transfer, which its sender disputes
+    /// but whose funds are with the recipient.
+    pub fn holder(&self) -> ClientId {
+        match self.transaction_type {
+            ProcessedTransactionType::Transfer { to } => to,
+            _ => self.client_id,
         }
     }
 }
@@ -1,6 +1,9 @@
+use crate::convert::{RawAmount, deserialize_amount};
+use crate::ids::{ClientId, RawId, TransactionId};
+use crate::timestamp::deserialize_timestamp;
 use serde::Deserialize;
 
-#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[serde(rename_all = "lowercase")]
 pub enum RawTransactionType {
     Deposit,
@@ -8,15 +11,83 @@ pub enum RawTransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    Refund,
+    Reversal,
+    Transfer,
+    /// A fee paid into the house account, see `FeeSchedule`.
+    Fee,
+    /// Pending funds becoming available, see `Config::clearing`.
+    Clear,
+    /// The rest are an operator moving the account to another state,
+    /// see `AccountState`.
+    Activate,
+    Freeze,
+    Dormant,
+    Close,
+}
+
+impl RawTransactionType {
+    /// Whether it's an operator moving the account to another state.
+    pub fn is_state_change(&self) -> bool {
+        matches!(
+            self,
+            RawTransactionType::Activate
+                | RawTransactionType::Freeze
+                | RawTransactionType::Dormant
+                | RawTransactionType::Close
+        )
+    }
+}
+
+/// The same spelling as in the input.
+impl std::fmt::Display for RawTransactionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RawTransactionType::Deposit => write!(f, "deposit"),
+            RawTransactionType::Withdrawal => write!(f, "withdrawal"),
+            RawTransactionType::Dispute => write!(f, "dispute"),
+            RawTransactionType::Resolve => write!(f, "resolve"),
+            RawTransactionType::Chargeback => write!(f, "chargeback"),
+            RawTransactionType::Refund => write!(f, "refund"),
+            RawTransactionType::Reversal => write!(f, "reversal"),
+            RawTransactionType::Transfer => write!(f, "transfer"),
+            RawTransactionType::Fee => write!(f, "fee"),
+            RawTransactionType::Clear => write!(f, "clear"),
+            RawTransactionType::Activate => write!(f, "activate"),
+            RawTransactionType::Freeze => write!(f, "freeze"),
+            RawTransactionType::Dormant => write!(f, "dormant"),
+            RawTransactionType::Close => write!(f, "close"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct RawTransaction {
     #[serde(rename = "type")]
     pub transaction_type: RawTransactionType,
+    /// Out of range IDs are rejected, see `RawId`.
     #[serde(rename = "client")]
-    pub client_id: u16,
+    pub client_id: RawId<ClientId>,
     #[serde(rename = "tx")]
-    pub transaction_id: u32,
-    pub amount: Option<f64>,
+    pub transaction_id: RawId<TransactionId>,
+    /// Exactly as written, see `convert.rs`.
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    pub amount: Option<RawAmount>,
+    /// Seconds since the Unix epoch (see `timestamp.rs` for the formats we
+    /// take). The column is optional, rows without one are never subject
+    /// to the time based rules.
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    pub timestamp: Option<u64>,
+    /// The asset code (see `Asset`), optional too. Rows without one are in
+    /// the default asset, effects are always in the asset of their transaction.
+    #[serde(default, alias = "currency")]
+    pub asset: Option<String>,
+    /// Who a transfer or fee is to, `client` being who it's from.
+    /// Only those have one, the column is optional like the two above.
+    #[serde(default, rename = "to")]
+    pub destination: Option<RawId<ClientId>>,
+    /// Why an operator moved the account to another state, or the reason
+    /// code of a dispute, resolve or chargeback. Also optional.
+    #[serde(default)]
+    pub reason: Option<String>,
 }
@@ -0,0 +1,102 @@
+use crate::client::ClientError;
+
+/// Why a row was not applied.
+///
+/// Malformed input and business rule violations both end up here so that
+/// a bad row never takes the whole run down with it. `main` counts these
+/// and prints a summary at the end.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Rejection {
+    IdOutOfRange,
+    MissingAmount,
+    UnexpectedAmount,
+    NonPositiveAmount,
+    NonFiniteAmount,
+    TooPrecise,
+    AmountTooLarge,
+    Overflow,
+    DuplicateTransaction,
+    MissingDestination,
+    SelfTransfer,
+    UnknownClient,
+    UnknownTransaction,
+    ClientMismatch,
+    NotDisputable,
+    NotDisputed,
+    ExceedsUndisputed,
+    ExceedsDisputed,
+    NotRefundable,
+    ExceedsRefundable,
+    NotReversible,
+    DisputeWindowExpired,
+    InvalidAsset,
+    AssetMismatch,
+    /// Broke the rule with this ID, see `rules.rs`.
+    Rule(String),
+    Client(ClientError),
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Rejection::IdOutOfRange => write!(f, "Client or transaction ID is out of range"),
+            Rejection::MissingAmount => {
+                write!(f, "Deposit/Withdrawal/Transfer/Fee is missing an amount")
+            }
+            Rejection::UnexpectedAmount => {
+                write!(f, "Reversal or state change should not have an amount")
+            }
+            Rejection::NonPositiveAmount => write!(f, "Amount must be greater than zero"),
+            Rejection::NonFiniteAmount => write!(f, "Amount must be a finite number"),
+            Rejection::TooPrecise => {
+                write!(f, "Amount has more decimals than its asset")
+            }
+            Rejection::AmountTooLarge => write!(f, "Amount exceeds the per-transaction maximum"),
+            Rejection::Overflow => write!(f, "Amount or balance would overflow"),
+            Rejection::DuplicateTransaction => write!(f, "Duplicate transaction ID"),
+            Rejection::MissingDestination => write!(f, "Transfer is missing a recipient"),
+            Rejection::SelfTransfer => write!(f, "Transfer is to the same client"),
+            Rejection::UnknownClient => write!(f, "Client not found"),
+            Rejection::UnknownTransaction => write!(f, "Transaction not found"),
+            Rejection::ClientMismatch => {
+                write!(f, "Transaction belongs to a different client")
+            }
+            Rejection::NotDisputable => write!(f, "Transaction cannot be disputed"),
+            Rejection::NotDisputed => write!(f, "Transaction is not under dispute"),
+            Rejection::ExceedsUndisputed => {
+                write!(f, "Amount exceeds the undisputed part of the transaction")
+            }
+            Rejection::ExceedsDisputed => {
+                write!(f, "Amount exceeds the disputed part of the transaction")
+            }
+            Rejection::NotRefundable => write!(f, "Transaction cannot be refunded"),
+            Rejection::ExceedsRefundable => {
+                write!(
+                    f,
+                    "Amount exceeds what is left to refund of the transaction"
+                )
+            }
+            Rejection::NotReversible => write!(f, "Transaction cannot be reversed"),
+            Rejection::DisputeWindowExpired => {
+                write!(f, "Transaction is too old to be disputed")
+            }
+            Rejection::InvalidAsset => write!(f, "Asset code is not valid"),
+            Rejection::AssetMismatch => {
+                write!(f, "Transaction is in a different asset")
+            }
+            Rejection::Rule(id) => write!(f, "Broke rule '{}'", id),
+            Rejection::Client(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<ClientError> for Rejection {
+    fn from(e: ClientError) -> Self {
+        match e {
+            // Whether the amount itself or the resulting balance doesn't fit,
+            // it's the same problem from the caller's point of view.
+            ClientError::Overflow => Rejection::Overflow,
+            e => Rejection::Client(e),
+        }
+    }
+}
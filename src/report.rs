@@ -0,0 +1,164 @@
+use crate::asset::Asset;
+use crate::client::Client;
+use crate::process::Summary;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// How `write_report` writes a run's report.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ReportFormat {
+    /// Lines for people to read.
+    #[default]
+    Human,
+    /// A single JSON object, with amounts as strings so they stay exact.
+    Json,
+}
+
+/// What a run's amounts in one asset added up to, in units of its smallest
+/// fraction.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AssetTotals {
+    pub deposited: i128,
+    pub withdrawn: i128,
+    /// Held by disputes once the run was over.
+    pub held: i128,
+    pub charged_back: i128,
+}
+
+/// Everything worth knowing about a run once it's over, see `write_report`.
+pub struct RunReport<'a> {
+    pub summary: &'a Summary,
+    /// Every account as the run left it.
+    pub accounts: &'a [Client],
+    /// Transactions still under dispute once the run was over.
+    pub open_disputes: usize,
+    pub elapsed: Duration,
+}
+
+impl RunReport<'_> {
+    /// The totals of every asset that had any.
+    pub fn totals(&self) -> BTreeMap<Asset, AssetTotals> {
+        let mut totals: BTreeMap<Asset, AssetTotals> = BTreeMap::new();
+        for (asset, amount) in &self.summary.deposited {
+            totals.entry(*asset).or_default().deposited = *amount;
+        }
+        for (asset, amount) in &self.summary.withdrawn {
+            totals.entry(*asset).or_default().withdrawn = *amount;
+        }
+        for ((_, asset), total) in &self.summary.chargebacks {
+            let charged_back = &mut totals.entry(*asset).or_default().charged_back;
+            *charged_back = charged_back.saturating_add(total.amount);
+        }
+        for client in self.accounts {
+            for (asset, balance) in &client.balances {
+                if balance.held != 0 {
+                    let held = &mut totals.entry(*asset).or_default().held;
+                    *held = held.saturating_add(balance.held);
+                }
+            }
+        }
+        totals
+    }
+
+    pub fn locked_clients(&self) -> usize {
+        self.accounts
+            .iter()
+            .filter(|client| client.is_locked())
+            .count()
+    }
+
+    /// How many chargebacks there were per reason code, whatever the asset.
+    pub fn chargebacks_by_reason(&self) -> BTreeMap<Option<&str>, usize> {
+        let mut by_reason = BTreeMap::new();
+        for ((reason_code, _), total) in &self.summary.chargebacks {
+            *by_reason.entry(reason_code.as_deref()).or_insert(0) += total.count;
+        }
+        by_reason
+    }
+
+    /// Rows per second, parse failures included. A run too quick to time
+    /// counts as having taken a microsecond.
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.elapsed.max(Duration::from_micros(1)).as_secs_f64();
+        self.summary.rows as f64 / seconds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::AssetRegistry;
+    use crate::config::Config;
+    use crate::output::write_report;
+    use crate::process::tests::run;
+    use crate::store::{AccountStore, TransactionStore};
+
+    #[test]
+    fn test_report_adds_up_the_run() {
+        let csv = "type,client,tx,amount,asset,reason\n\
+                   deposit,1,1,10.0,,\n\
+                   deposit,1,2,2.0,,\n\
+                   deposit,2,3,1.5,BTC,\n\
+                   withdrawal,1,4,3.0,,\n\
+                   withdrawal,2,5,9.0,BTC,\n\
+                   dispute,1,2,,,4837\n\
+                   chargeback,1,2,,,\n\
+                   dispute,2,3,,,\n\
+                   oops,1,6,,,\n";
+        let (summary, transactions, clients) = run(csv, &Config::default());
+        let accounts = clients.clients().unwrap();
+        let report = RunReport {
+            summary: &summary,
+            accounts: &accounts,
+            open_disputes: transactions.disputed().unwrap().len(),
+            elapsed: Duration::from_millis(500),
+        };
+
+        let btc = Asset::new("BTC").unwrap();
+        assert_eq!(
+            report.totals().into_iter().collect::<Vec<_>>(),
+            vec![
+                (
+                    Asset::DEFAULT,
+                    AssetTotals {
+                        deposited: 120000,
+                        withdrawn: 30000,
+                        held: 0,
+                        charged_back: 20000,
+                    }
+                ),
+                (
+                    btc,
+                    AssetTotals {
+                        deposited: 15000,
+                        withdrawn: 0,
+                        held: 15000,
+                        charged_back: 0,
+                    }
+                ),
+            ]
+        );
+        assert_eq!(report.locked_clients(), 1);
+        assert_eq!(report.throughput(), 18.0);
+
+        let mut json = Vec::new();
+        write_report(
+            &mut json,
+            &report,
+            ReportFormat::Json,
+            &AssetRegistry::new(),
+        )
+        .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["rows"], 9);
+        assert_eq!(json["parse_failures"], 1);
+        assert_eq!(json["rejected"], 1);
+        assert_eq!(json["applied"]["deposit"], 3);
+        assert_eq!(json["applied"]["chargeback"], 1);
+        assert_eq!(json["rejections"]["Insufficient funds available"], 1);
+        assert_eq!(json["totals"][""]["charged_back"], "2.0000");
+        assert_eq!(json["totals"]["BTC"]["held"], "1.5000");
+        assert_eq!(json["open_disputes"], 1);
+        assert_eq!(json["chargebacks_by_reason"]["4837"], 1);
+    }
+}
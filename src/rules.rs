@@ -0,0 +1,453 @@
+use crate::asset::{Asset, AssetRegistry};
+use crate::config::Config;
+use crate::convert::{RawAmount, parse_amount};
+use crate::handlers::{row_asset, row_ids, transfer_amount};
+use crate::ids::ClientId;
+use crate::ingest::csv_reader;
+use crate::processed_transaction::ProcessedTransactionType;
+use crate::raw_transaction::{RawTransaction, RawTransactionType};
+use crate::rejection::Rejection;
+use crate::timestamp::parse_duration;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::io::Read;
+
+/// What a rule limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleKind {
+    /// The amount of a single transfer.
+    MaxAmount,
+    /// How many transfers there are within the window.
+    MaxCount,
+    /// How much they add up to within the window.
+    MaxTotal,
+    /// How many withdrawals there are within the window after a deposit.
+    MaxAfterDeposit,
+}
+
+/// How far back a rule looks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleWindow {
+    /// The client's last this many transfers, this one included.
+    Rows(u64),
+    /// This many seconds, going by the timestamps of the rows.
+    /// Rows without a timestamp aren't subject to the rule.
+    Seconds(u64),
+}
+
+/// A limit on deposits and withdrawals, checked before they're applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// Shows up in the rejection, so it's clear which rule a row broke.
+    pub id: String,
+    pub kind: RuleKind,
+    /// Deposits or withdrawals, `None` for both.
+    /// `MaxAfterDeposit` is always about withdrawals.
+    pub transaction_type: Option<ProcessedTransactionType>,
+    /// Only transfers in this asset count.
+    pub asset: Asset,
+    /// In units of the asset's smallest fraction for `MaxAmount` and
+    /// `MaxTotal`, a number of transfers otherwise.
+    pub limit: i128,
+    /// Always there, except for `MaxAmount`.
+    pub window: Option<RuleWindow>,
+}
+
+/// Every rule, see `Rule`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RuleRow {
+    id: String,
+    kind: String,
+    #[serde(default, rename = "type")]
+    transaction_type: String,
+    #[serde(default)]
+    asset: String,
+    limit: String,
+    #[serde(default)]
+    window: String,
+}
+
+impl Rules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Reads a CSV with `id`, `kind`, `type`, `asset`, `limit` and `window`
+    /// columns, e.g. `burst,max_count,withdrawal,,3,1h`.
+    ///
+    /// Kinds are `max_amount`, `max_count`, `max_total` and `after_deposit`.
+    /// An empty type is both deposits and withdrawals, an empty asset the
+    /// default asset. Amounts are in whole units of the asset, windows are
+    /// durations (`90s`, `1h`, `7d`) or a number of transfers (`10rows`).
+    pub fn from_csv<R: Read>(rdr: R, assets: &AssetRegistry) -> Result<Self, Box<dyn Error>> {
+        let mut rules = Rules::new();
+        for row in csv_reader(rdr).deserialize() {
+            let row: RuleRow = row?;
+            let invalid = |what: &str, value: &str| {
+                format!("rule '{}': invalid {} '{}'", row.id, what, value)
+            };
+
+            let kind = match row.kind.as_str() {
+                "max_amount" => RuleKind::MaxAmount,
+                "max_count" => RuleKind::MaxCount,
+                "max_total" => RuleKind::MaxTotal,
+                "after_deposit" => RuleKind::MaxAfterDeposit,
+                other => return Err(invalid("kind", other).into()),
+            };
+            let transaction_type = match row.transaction_type.as_str() {
+                "" => None,
+                "deposit" => Some(ProcessedTransactionType::Deposit),
+                "withdrawal" => Some(ProcessedTransactionType::Withdrawal),
+                other => return Err(invalid("type", other).into()),
+            };
+            let asset = Asset::new(&row.asset).ok_or(invalid("asset", &row.asset))?;
+            let limit = match (kind, parse_amount(&row.limit)) {
+                (RuleKind::MaxAmount | RuleKind::MaxTotal, Some(RawAmount::Decimal(limit))) => {
+                    limit.to_units(assets.decimals(asset)).ok()
+                }
+                (_, _) => row.limit.parse::<u64>().ok().map(i128::from),
+            }
+            .filter(|limit| *limit >= 0)
+            .ok_or(invalid("limit", &row.limit))?;
+            let window = match row.window.as_str() {
+                "" => None,
+                window => Some(match window.strip_suffix("rows") {
+                    Some(rows) => {
+                        RuleWindow::Rows(rows.parse().map_err(|_| invalid("window", window))?)
+                    }
+                    None => RuleWindow::Seconds(
+                        parse_duration(window).ok_or(invalid("window", window))?,
+                    ),
+                }),
+            };
+            if window.is_some() == (kind == RuleKind::MaxAmount) {
+                return Err(
+                    format!("rule '{}': only max_amount goes without a window", row.id).into(),
+                );
+            }
+
+            rules.push(Rule {
+                id: row.id.clone(),
+                kind,
+                transaction_type,
+                asset,
+                limit,
+                window,
+            });
+        }
+        Ok(rules)
+    }
+}
+
+/// A transfer as far as the rules are concerned.
+#[derive(Debug, Clone, Copy)]
+struct Transfer {
+    /// Counts the client's applied transfers, for windows in rows.
+    seq: u64,
+    transaction_type: ProcessedTransactionType,
+    asset: Asset,
+    amount: i128,
+    timestamp: Option<u64>,
+}
+
+impl Transfer {
+    /// Whether `self` is in `window` as seen from `now`.
+    fn within(&self, window: RuleWindow, now: &Transfer) -> bool {
+        match window {
+            RuleWindow::Rows(rows) => now.seq - self.seq < rows,
+            RuleWindow::Seconds(seconds) => match (self.timestamp, now.timestamp) {
+                (Some(then), Some(now)) => now.saturating_sub(then) <= seconds,
+                _ => false,
+            },
+        }
+    }
+
+    /// Whether a later transfer could still find `self` in `window`,
+    /// `self` being older than `latest`.
+    fn visible(&self, window: RuleWindow, latest: &Transfer) -> bool {
+        match (window, latest.timestamp) {
+            // Without a timestamp there's no telling how much time passed.
+            (RuleWindow::Seconds(_), None) => self.timestamp.is_some(),
+            _ => self.within(window, latest),
+        }
+    }
+}
+
+impl Rule {
+    fn applies(&self, transfer: &Transfer) -> bool {
+        transfer.asset == self.asset
+            && self
+                .transaction_type
+                .is_none_or(|transaction_type| transfer.transaction_type == transaction_type)
+    }
+
+    /// Whether `now` breaks the rule, given the client's `recent` transfers.
+    fn broken_by(&self, now: &Transfer, recent: &VecDeque<Transfer>) -> bool {
+        match (self.kind, self.window) {
+            (RuleKind::MaxAmount, _) => self.applies(now) && now.amount > self.limit,
+            (_, None) => false,
+            (_, Some(RuleWindow::Seconds(_))) if now.timestamp.is_none() => false,
+            (RuleKind::MaxCount, Some(window)) => {
+                self.applies(now)
+                    && in_window(window, now, recent)
+                        .filter(|transfer| self.applies(transfer))
+                        .count() as i128
+                        + 1
+                        > self.limit
+            }
+            (RuleKind::MaxTotal, Some(window)) => {
+                self.applies(now)
+                    && in_window(window, now, recent)
+                        .filter(|transfer| self.applies(transfer))
+                        .try_fold(now.amount, |total, transfer| {
+                            total.checked_add(transfer.amount)
+                        })
+                        .is_none_or(|total| total > self.limit)
+            }
+            (RuleKind::MaxAfterDeposit, Some(window)) => self
+                .withdrawals_after_deposit(window, now, recent)
+                .is_some_and(|withdrawals| withdrawals as i128 + 1 > self.limit),
+        }
+    }
+
+    /// If `now` is a withdrawal, how many others came since the client's
+    /// last deposit in `window`, or `None` if there's no such deposit.
+    fn withdrawals_after_deposit(
+        &self,
+        window: RuleWindow,
+        now: &Transfer,
+        recent: &VecDeque<Transfer>,
+    ) -> Option<usize> {
+        let is = |transfer: &Transfer, transaction_type| {
+            transfer.asset == self.asset && transfer.transaction_type == transaction_type
+        };
+        if !is(now, ProcessedTransactionType::Withdrawal) {
+            return None;
+        }
+        let deposit = in_window(window, now, recent)
+            .rfind(|transfer| is(transfer, ProcessedTransactionType::Deposit))?;
+        Some(
+            recent
+                .iter()
+                .filter(|transfer| {
+                    transfer.seq > deposit.seq && is(transfer, ProcessedTransactionType::Withdrawal)
+                })
+                .count(),
+        )
+    }
+}
+
+/// The `recent` transfers that are in `window` as seen from `now`.
+fn in_window<'a>(
+    window: RuleWindow,
+    now: &'a Transfer,
+    recent: &'a VecDeque<Transfer>,
+) -> impl DoubleEndedIterator<Item = &'a Transfer> {
+    recent
+        .iter()
+        .filter(move |transfer| transfer.within(window, now))
+}
+
+#[derive(Debug, Default)]
+struct ClientTransfers {
+    next_seq: u64,
+    /// Oldest first, only as far back as some rule looks.
+    recent: VecDeque<Transfer>,
+}
+
+/// What the rules need to remember about each client's recent transfers.
+///
+/// This only lives as long as a run, so windows start out empty every
+/// run, even with `--state`.
+#[derive(Debug, Default)]
+pub struct RuleState {
+    clients: HashMap<ClientId, ClientTransfers>,
+}
+
+impl RuleState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks a row against every rule in `config.rules`, before it's
+    /// applied. Rows that aren't valid deposits or withdrawals pass, the
+    /// handlers reject them for the right reason.
+    pub fn check(&self, raw_tx: &RawTransaction, config: &Config) -> Result<(), Rejection> {
+        if config.rules.is_empty() {
+            return Ok(());
+        }
+        let Some((client_id, now)) = self.transfer(raw_tx, config) else {
+            return Ok(());
+        };
+        let empty = VecDeque::new();
+        let recent = self
+            .clients
+            .get(&client_id)
+            .map_or(&empty, |client| &client.recent);
+
+        match config
+            .rules
+            .rules
+            .iter()
+            .find(|rule| rule.broken_by(&now, recent))
+        {
+            Some(rule) => Err(Rejection::Rule(rule.id.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Remembers a row that was applied, for the windows of later ones.
+    pub fn record(&mut self, raw_tx: &RawTransaction, config: &Config) {
+        if config.rules.is_empty() {
+            return;
+        }
+        let Some((client_id, transfer)) = self.transfer(raw_tx, config) else {
+            return;
+        };
+        let client = self.clients.entry(client_id).or_default();
+        client.next_seq += 1;
+        client.recent.push_back(transfer);
+
+        // Anything no rule can see anymore won't ever be seen again.
+        while let Some(oldest) = client.recent.front()
+            && !config.rules.rules.iter().any(|rule| {
+                rule.window
+                    .is_some_and(|window| oldest.visible(window, &transfer))
+            })
+        {
+            client.recent.pop_front();
+        }
+    }
+
+    /// The row as a transfer by its client, if it is a valid deposit, withdrawal
+    /// or transfer.
+    fn transfer(&self, raw_tx: &RawTransaction, config: &Config) -> Option<(ClientId, Transfer)> {
+        let transaction_type = match raw_tx.transaction_type {
+            RawTransactionType::Deposit => ProcessedTransactionType::Deposit,
+            // For the sender, sending funds to someone else is as good as
+            // withdrawing them.
+            RawTransactionType::Withdrawal | RawTransactionType::Transfer => {
+                ProcessedTransactionType::Withdrawal
+            }
+            _ => return None,
+        };
+        let (client_id, _) = row_ids(raw_tx).ok()?;
+        let asset = row_asset(raw_tx).ok()?;
+        let amount = transfer_amount(raw_tx, config, asset).ok()?;
+        let seq = self
+            .clients
+            .get(&client_id)
+            .map_or(0, |client| client.next_seq);
+        Some((
+            client_id,
+            Transfer {
+                seq,
+                transaction_type,
+                asset,
+                amount,
+                timestamp: raw_tx.timestamp,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::tests::run;
+
+    #[test]
+    fn test_rules_from_csv() {
+        let csv = "id, kind, type, asset, limit, window\n\
+                   big, max_amount, withdrawal, , 1000,\n\
+                   burst, max_count, , USD, 3, 1h\n\
+                   rows, max_total, deposit, , 0.5, 10rows\n\
+                   fresh, after_deposit, , , 0, 10m\n";
+        let rules = Rules::from_csv(csv.as_bytes(), &AssetRegistry::new()).unwrap();
+        assert_eq!(rules.rules.len(), 4);
+        assert_eq!(rules.rules[0].limit, 10_000_000);
+        assert_eq!(rules.rules[1].window, Some(RuleWindow::Seconds(3600)));
+        assert_eq!(rules.rules[2].limit, 5000);
+        assert_eq!(rules.rules[2].window, Some(RuleWindow::Rows(10)));
+        assert_eq!(rules.rules[3].kind, RuleKind::MaxAfterDeposit);
+
+        for bad in [
+            "x, max_speed, , , 1, 1h",
+            "x, max_count, , , 1,",
+            "x, max_amount, , , 1, 1h",
+            "x, max_count, refund, , 1, 1h",
+            "x, max_count, , , 1.5, 1h",
+            "x, max_total, , , 1, 3weeks",
+        ] {
+            let csv = format!("id, kind, type, asset, limit, window\n{}\n", bad);
+            assert!(
+                Rules::from_csv(csv.as_bytes(), &AssetRegistry::new()).is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_rules_reject_rows() {
+        let rules = "id, kind, type, asset, limit, window\n\
+                     big, max_amount, withdrawal, , 100,\n\
+                     burst, max_count, deposit, , 2, 3rows\n\
+                     hourly, max_total, withdrawal, , 50, 1h\n\
+                     fresh, after_deposit, , , 1, 10m\n";
+        let config = Config {
+            rules: Rules::from_csv(rules.as_bytes(), &AssetRegistry::new()).unwrap(),
+            ..Config::default()
+        };
+
+        // tx 3 is the second withdrawal right after a deposit, tx 5 takes the
+        // hour's withdrawals over 50, tx 6 is too large on its own and tx 9
+        // is the third deposit in three rows. tx 10 has no timestamp, so only
+        // the rules that don't go by time apply to it.
+        let csv = "type, client, tx, amount, timestamp\n\
+                   deposit, 1, 1, 1000, 0\n\
+                   withdrawal, 1, 2, 30, 10\n\
+                   withdrawal, 1, 3, 10, 20\n\
+                   withdrawal, 1, 4, 10, 700\n\
+                   withdrawal, 1, 5, 20, 800\n\
+                   withdrawal, 1, 6, 200, 5000\n\
+                   deposit, 1, 7, 1, 5000\n\
+                   deposit, 1, 8, 1, 5000\n\
+                   deposit, 1, 9, 1, 5000\n\
+                   withdrawal, 1, 10, 1,\n";
+        let (summary, _, clients) = run(csv, &config);
+
+        let rejections: Vec<(String, usize)> = summary
+            .rejections
+            .into_iter()
+            .map(|(rejection, count)| (rejection.to_string(), count))
+            .collect();
+        assert_eq!(
+            rejections,
+            vec![
+                ("Broke rule 'big'".to_string(), 1),
+                ("Broke rule 'burst'".to_string(), 1),
+                ("Broke rule 'fresh'".to_string(), 1),
+                ("Broke rule 'hourly'".to_string(), 1),
+            ]
+        );
+        assert_eq!(
+            clients[&ClientId(1)].balance(Asset::DEFAULT).available,
+            9610000
+        );
+    }
+}
@@ -0,0 +1,564 @@
+use crate::asset::{Asset, AssetRegistry};
+use crate::client::{Balance, Client};
+use crate::ids::{ClientId, TransactionId};
+use crate::lifecycle::AccountState;
+use crate::processed_transaction::{DisputeStatus, ProcessedTransaction, ProcessedTransactionType};
+use crate::store::{AccountStore, BATCH_SIZE, StoreError, TransactionStore};
+use rusqlite::types::{FromSql, FromSqlResult, ToSqlOutput, Value, ValueRef};
+use rusqlite::{Connection, Row, ToSql, params};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::rc::Rc;
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError(e.to_string())
+    }
+}
+
+/// Schema changes, in order. A database remembers how many it has had
+/// applied in `PRAGMA user_version`, so only append to this list.
+///
+/// Amounts are stored the same way the engine keeps them, in units of their
+/// asset's smallest fraction (see `AssetRegistry`), as text if they don't fit
+/// in 64 bits, which is why the amount columns have no type: INTEGER
+/// affinity would turn those into (lossy) REALs. The `assets` table has the
+/// decimals of every asset that has ever been used and the `accounts` view
+/// has the amounts as decimals, like the CSV output. Assets are stored as
+/// their code, the default asset being `''`.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE clients (
+        client_id INTEGER PRIMARY KEY,
+        state TEXT NOT NULL,
+        reason TEXT
+    );
+    CREATE TABLE balances (
+        client_id INTEGER NOT NULL,
+        asset TEXT NOT NULL,
+        available NOT NULL,
+        pending NOT NULL,
+        held NOT NULL,
+        total NOT NULL,
+        PRIMARY KEY (client_id, asset)
+    );
+    CREATE TABLE transactions (
+        tx_id INTEGER PRIMARY KEY,
+        client_id INTEGER NOT NULL,
+        type TEXT NOT NULL,
+        -- Who a transfer is to, NULL for everything else
+        to_client_id INTEGER,
+        asset TEXT NOT NULL,
+        amount NOT NULL,
+        disputed NOT NULL,
+        refunded NOT NULL,
+        charged_back NOT NULL,
+        -- What's left of a deposit to clear
+        pending NOT NULL,
+        dispute_status TEXT NOT NULL,
+        timestamp INTEGER,
+        disputed_at INTEGER,
+        reason_code TEXT
+    );
+    -- The primary keys already index tx_id and clients.client_id
+    CREATE INDEX transactions_client_id ON transactions (client_id);
+    CREATE INDEX transactions_disputed ON transactions (tx_id)
+        WHERE dispute_status = 'disputed';
+    CREATE TABLE assets (
+        asset TEXT PRIMARY KEY,
+        decimals INTEGER NOT NULL
+    );
+    -- Clients without any balance get a row of zeros, like in the CSV output
+    CREATE VIEW accounts AS
+        SELECT client_id AS client,
+               coalesce(asset, '') AS asset,
+               coalesce(available, 0) / coalesce(scale, 1e4) AS available,
+               coalesce(pending, 0) / coalesce(scale, 1e4) AS pending,
+               coalesce(held, 0) / coalesce(scale, 1e4) AS held,
+               coalesce(total, 0) / coalesce(scale, 1e4) AS total,
+               state = 'locked' AS locked,
+               state,
+               reason
+        FROM clients
+        LEFT JOIN balances USING (client_id)
+        LEFT JOIN (SELECT asset, CAST('1e' || decimals AS REAL) AS scale FROM assets)
+            USING (asset);
+"];
+
+/// The connection both stores write through, with a transaction always open
+/// so writes are batched instead of hitting the disk one row at a time.
+struct Database {
+    conn: Connection,
+    pending: Cell<usize>,
+    assets: AssetRegistry,
+    /// The assets already in the `assets` table.
+    recorded: RefCell<HashSet<Asset>>,
+}
+
+impl Database {
+    /// Adds `asset` to the `assets` table, if it isn't there yet.
+    fn record_asset(&self, asset: Asset) -> Result<(), StoreError> {
+        if self.recorded.borrow().contains(&asset) {
+            return Ok(());
+        }
+        self.conn
+            .prepare_cached("INSERT OR IGNORE INTO assets (asset, decimals) VALUES (?1, ?2)")?
+            .execute(params![asset.to_string(), self.assets.decimals(asset)])?;
+        self.recorded.borrow_mut().insert(asset);
+        Ok(())
+    }
+
+    /// Only counts the write, see `row_done`.
+    fn written(&self) -> Result<(), StoreError> {
+        self.pending.set(self.pending.get() + 1);
+        Ok(())
+    }
+
+    /// Commits once `BATCH_SIZE` writes piled up. Only called between rows,
+    /// so a commit never has half of one.
+    fn row_done(&self) -> Result<(), StoreError> {
+        if self.pending.get() >= BATCH_SIZE {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<(), StoreError> {
+        self.conn.execute_batch("COMMIT; BEGIN")?;
+        self.pending.set(0);
+        Ok(())
+    }
+}
+
+/// Opens (or creates) the SQLite database at `path`, brings its schema
+/// up to date and returns a transaction store and an account store backed by it.
+///
+/// The database is the state for the next run pointed at the same file,
+/// and can be queried directly, e.g. `SELECT * FROM accounts`.
+///
+/// Amounts in the database only make sense with the decimals they were
+/// stored with, so it's an error for `assets` to disagree with those.
+pub fn open_sqlite_stores(
+    path: impl AsRef<Path>,
+    assets: &AssetRegistry,
+) -> Result<(SqliteTransactionStore, SqliteAccountStore), StoreError> {
+    let mut conn = Connection::open(path)?;
+    migrate(&mut conn)?;
+
+    let mut recorded = HashSet::new();
+    let mut statement = conn.prepare("SELECT asset, decimals FROM assets")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let asset = asset_from_sql(&row.get::<_, String>(0)?)?;
+        let decimals: u32 = row.get(1)?;
+        if assets.decimals(asset) != decimals {
+            return Err(StoreError(format!(
+                "asset '{}' has {} decimals in the database but {} are configured",
+                asset,
+                decimals,
+                assets.decimals(asset)
+            )));
+        }
+        recorded.insert(asset);
+    }
+    drop(rows);
+    drop(statement);
+    conn.execute_batch("BEGIN")?;
+
+    let db = Rc::new(Database {
+        conn,
+        pending: Cell::new(0),
+        assets: assets.clone(),
+        recorded: RefCell::new(recorded),
+    });
+    Ok((
+        SqliteTransactionStore { db: db.clone() },
+        SqliteAccountStore { db },
+    ))
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), StoreError> {
+    let applied: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let known = MIGRATIONS.len() as i64;
+    if applied > known {
+        return Err(StoreError(format!(
+            "database schema version {} is newer than this binary ({})",
+            applied, known
+        )));
+    }
+
+    for (version, migration) in (1..).zip(MIGRATIONS).skip(applied as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn type_to_sql(transaction_type: ProcessedTransactionType) -> &'static str {
+    match transaction_type {
+        ProcessedTransactionType::Deposit => "deposit",
+        ProcessedTransactionType::Withdrawal => "withdrawal",
+        ProcessedTransactionType::Transfer { .. } => "transfer",
+    }
+}
+
+fn status_to_sql(status: DisputeStatus) -> &'static str {
+    match status {
+        DisputeStatus::Valid => "valid",
+        DisputeStatus::Disputed => "disputed",
+        DisputeStatus::Resolved => "resolved",
+        DisputeStatus::ChargedBack => "charged_back",
+    }
+}
+
+fn asset_from_sql(code: &str) -> Result<Asset, StoreError> {
+    Asset::new(code).ok_or_else(|| StoreError(format!("invalid asset '{}'", code)))
+}
+
+/// SQLite integers are signed, so IDs past `i64::MAX` are stored as the
+/// negative number with the same bits. Nobody is going to get near that.
+macro_rules! id_sql {
+    ($($id:ident),*) => {
+        $(impl ToSql for $id {
+            fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+                Ok(ToSqlOutput::from(self.0 as i64))
+            }
+        }
+
+        impl FromSql for $id {
+            fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+                i64::column_result(value).map(|id| $id(id as u64))
+            }
+        })*
+    };
+}
+
+id_sql!(ClientId, TransactionId);
+
+/// Amounts go in as integers when they fit, which they nearly always do,
+/// and as text when they don't.
+fn amount_to_sql(amount: i128) -> Value {
+    match i64::try_from(amount) {
+        Ok(amount) => Value::Integer(amount),
+        Err(_) => Value::Text(amount.to_string()),
+    }
+}
+
+fn amount_from_sql(row: &Row, column: &str) -> Result<i128, StoreError> {
+    match row.get_ref(column)? {
+        ValueRef::Integer(amount) => Ok(i128::from(amount)),
+        ValueRef::Text(text) => std::str::from_utf8(text)
+            .ok()
+            .and_then(|text| text.parse().ok())
+            .ok_or_else(|| StoreError(format!("invalid {} in the database", column))),
+        other => Err(StoreError(format!(
+            "invalid {} in the database: {:?}",
+            column, other
+        ))),
+    }
+}
+
+fn transaction_from_row(row: &Row) -> Result<ProcessedTransaction, StoreError> {
+    let transaction_type: String = row.get("type")?;
+    let dispute_status: String = row.get("dispute_status")?;
+    let asset: String = row.get("asset")?;
+
+    Ok(ProcessedTransaction {
+        transaction_type: match transaction_type.as_str() {
+            "deposit" => ProcessedTransactionType::Deposit,
+            "withdrawal" => ProcessedTransactionType::Withdrawal,
+            "transfer" => ProcessedTransactionType::Transfer {
+                to: row.get("to_client_id")?,
+            },
+            other => return Err(StoreError(format!("unknown transaction type '{}'", other))),
+        },
+        dispute_status: match dispute_status.as_str() {
+            "valid" => DisputeStatus::Valid,
+            "disputed" => DisputeStatus::Disputed,
+            "resolved" => DisputeStatus::Resolved,
+            "charged_back" => DisputeStatus::ChargedBack,
+            other => return Err(StoreError(format!("unknown dispute status '{}'", other))),
+        },
+        client_id: row.get("client_id")?,
+        asset: asset_from_sql(&asset)?,
+        amount: amount_from_sql(row, "amount")?,
+        disputed: amount_from_sql(row, "disputed")?,
+        refunded: amount_from_sql(row, "refunded")?,
+        charged_back: amount_from_sql(row, "charged_back")?,
+        pending: amount_from_sql(row, "pending")?,
+        timestamp: row.get("timestamp")?,
+        disputed_at: row.get("disputed_at")?,
+        reason_code: row.get("reason_code")?,
+    })
+}
+
+fn client_from_row(row: &Row) -> Result<Client, StoreError> {
+    let state: String = row.get("state")?;
+
+    let mut client = Client::new(row.get("client_id")?);
+    client.state = match state.as_str() {
+        "active" => AccountState::Active,
+        "frozen" => AccountState::Frozen,
+        "locked" => AccountState::Locked,
+        "dormant" => AccountState::Dormant,
+        "closed" => AccountState::Closed,
+        other => return Err(StoreError(format!("unknown account state '{}'", other))),
+    };
+    client.reason = row.get("reason")?;
+    Ok(client)
+}
+
+fn balance_from_row(row: &Row) -> Result<(ClientId, Asset, Balance), StoreError> {
+    let asset: String = row.get("asset")?;
+    let balance = Balance {
+        available: amount_from_sql(row, "available")?,
+        held: amount_from_sql(row, "held")?,
+        total: amount_from_sql(row, "total")?,
+        pending: amount_from_sql(row, "pending")?,
+    };
+    Ok((row.get("client_id")?, asset_from_sql(&asset)?, balance))
+}
+
+/// A `TransactionStore` kept in the `transactions` table of a SQLite database.
+pub struct SqliteTransactionStore {
+    db: Rc<Database>,
+}
+
+impl TransactionStore for SqliteTransactionStore {
+    fn get(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<ProcessedTransaction>, StoreError> {
+        let mut statement = self
+            .db
+            .conn
+            .prepare_cached("SELECT * FROM transactions WHERE tx_id = ?1")?;
+        let mut rows = statement.query([transaction_id])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(transaction_from_row(row)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn disputed(&self) -> Result<Vec<(TransactionId, ProcessedTransaction)>, StoreError> {
+        let mut statement = self
+            .db
+            .conn
+            .prepare_cached("SELECT * FROM transactions WHERE dispute_status = 'disputed'")?;
+        let mut rows = statement.query([])?;
+        let mut disputed = Vec::new();
+        while let Some(row) = rows.next()? {
+            disputed.push((row.get("tx_id")?, transaction_from_row(row)?));
+        }
+        Ok(disputed)
+    }
+
+    fn insert(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: ProcessedTransaction,
+    ) -> Result<(), StoreError> {
+        self.db.record_asset(transaction.asset)?;
+        self.db
+            .conn
+            .prepare_cached(
+                "INSERT OR REPLACE INTO transactions
+                     (tx_id, client_id, type, to_client_id, asset, amount, disputed, refunded,
+                      charged_back, pending, dispute_status, timestamp, disputed_at, reason_code)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+            )?
+            .execute(params![
+                transaction_id,
+                transaction.client_id,
+                type_to_sql(transaction.transaction_type),
+                match transaction.transaction_type {
+                    ProcessedTransactionType::Transfer { to } => Some(to),
+                    _ => None,
+                },
+                transaction.asset.to_string(),
+                amount_to_sql(transaction.amount),
+                amount_to_sql(transaction.disputed),
+                amount_to_sql(transaction.refunded),
+                amount_to_sql(transaction.charged_back),
+                amount_to_sql(transaction.pending),
+                status_to_sql(transaction.dispute_status),
+                transaction.timestamp,
+                transaction.disputed_at,
+                transaction.reason_code,
+            ])?;
+        self.db.written()
+    }
+
+    fn row_done(&mut self) -> Result<(), StoreError> {
+        self.db.row_done()
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        self.db.commit()
+    }
+}
+
+/// An `AccountStore` kept in the `clients` and `balances` tables of a SQLite database.
+pub struct SqliteAccountStore {
+    db: Rc<Database>,
+}
+
+impl AccountStore for SqliteAccountStore {
+    fn get(&self, client_id: ClientId) -> Result<Option<Client>, StoreError> {
+        let mut statement = self
+            .db
+            .conn
+            .prepare_cached("SELECT * FROM clients WHERE client_id = ?1")?;
+        let mut rows = statement.query([client_id])?;
+        let Some(row) = rows.next()? else {
+            return Ok(None);
+        };
+        let mut client = client_from_row(row)?;
+
+        let mut statement = self
+            .db
+            .conn
+            .prepare_cached("SELECT * FROM balances WHERE client_id = ?1")?;
+        let mut rows = statement.query([client_id])?;
+        while let Some(row) = rows.next()? {
+            let (_, asset, balance) = balance_from_row(row)?;
+            client.balances.insert(asset, balance);
+        }
+        Ok(Some(client))
+    }
+
+    fn insert(&mut self, client_id: ClientId, client: Client) -> Result<(), StoreError> {
+        self.db
+            .conn
+            .prepare_cached(
+                "INSERT OR REPLACE INTO clients (client_id, state, reason) VALUES (?1, ?2, ?3)",
+            )?
+            .execute(params![client_id, client.state.to_string(), client.reason])?;
+        // A client never loses a balance, so there's nothing to delete.
+        for (asset, balance) in &client.balances {
+            self.db.record_asset(*asset)?;
+            self.db
+                .conn
+                .prepare_cached(
+                    "INSERT OR REPLACE INTO balances
+                        (client_id, asset, available, pending, held, total)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                )?
+                .execute(params![
+                    client_id,
+                    asset.to_string(),
+                    amount_to_sql(balance.available),
+                    amount_to_sql(balance.pending),
+                    amount_to_sql(balance.held),
+                    amount_to_sql(balance.total),
+                ])?;
+        }
+        self.db.written()
+    }
+
+    fn clients(&self) -> Result<Vec<Client>, StoreError> {
+        let mut statement = self.db.conn.prepare_cached("SELECT * FROM clients")?;
+        let mut rows = statement.query([])?;
+        let mut clients = BTreeMap::new();
+        while let Some(row) = rows.next()? {
+            let client = client_from_row(row)?;
+            clients.insert(client.client_id(), client);
+        }
+
+        let mut statement = self.db.conn.prepare_cached("SELECT * FROM balances")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let (client_id, asset, balance) = balance_from_row(row)?;
+            if let Some(client) = clients.get_mut(&client_id) {
+                client.balances.insert(asset, balance);
+            }
+        }
+        Ok(clients.into_values().collect())
+    }
+
+    fn row_done(&mut self) -> Result<(), StoreError> {
+        self.db.row_done()
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        self.db.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::history::NoHistory;
+    use crate::process::tests::run_on;
+    use std::fs;
+
+    #[test]
+    fn test_sqlite_state_is_kept_and_queryable() {
+        let path = std::env::temp_dir().join(format!("tx2acc-sqlite-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let runs = [
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 10.0\n\
+             deposit, 2, 2, 5.0\n",
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 10.0\n\
+             dispute, 1, 1,\n\
+             chargeback, 1, 1,\n\
+             withdrawal, 2, 3, 1.5\n",
+        ];
+        for run in runs {
+            let (mut transactions, mut clients) =
+                open_sqlite_stores(&path, &AssetRegistry::new()).unwrap();
+            run_on(
+                run,
+                &Config::default(),
+                &mut transactions,
+                &mut clients,
+                &mut NoHistory,
+            );
+            transactions.flush().unwrap();
+            clients.flush().unwrap();
+        }
+
+        let conn = Connection::open(&path).unwrap();
+        let accounts: Vec<(u16, f64, f64, f64, bool)> = conn
+            .prepare("SELECT client, available, held, total, locked FROM accounts ORDER BY client")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            accounts,
+            vec![(1, 0.0, 0.0, 0.0, true), (2, 3.5, 0.0, 3.5, false)]
+        );
+
+        let status: String = conn
+            .query_row(
+                "SELECT dispute_status FROM transactions WHERE tx_id = 1",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(status, "charged_back");
+
+        // The amounts in there are in units of 4 decimals
+        let mut assets = AssetRegistry::new();
+        assets.insert(Asset::DEFAULT, 2).unwrap();
+        assert!(open_sqlite_stores(&path, &assets).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
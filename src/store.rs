@@ -0,0 +1,137 @@
+use crate::client::Client;
+use crate::ids::{ClientId, TransactionId};
+use crate::processed_transaction::{DisputeStatus, ProcessedTransaction};
+use std::collections::HashMap;
+
+/// On-disk backends commit once this many writes piled up, at the next
+/// row boundary (see `TransactionStore::row_done`), so a run's uncommitted
+/// changes don't pile up in memory.
+pub const BATCH_SIZE: usize = 100_000;
+
+/// A backend failed to read or write. Unlike a `Rejection` this isn't
+/// about the row, so it aborts the run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreError(pub String);
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Storage error: {}", self.0)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+/// Where the handlers keep the deposits/withdrawals they've applied,
+/// keyed by transaction ID.
+///
+/// Transactions are handed out by value so that backends are free to
+/// store them in whatever shape they like (see `CompactTransactionStore`).
+/// To change one, `get` it, modify it and `insert` it back.
+pub trait TransactionStore {
+    fn get(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<ProcessedTransaction>, StoreError>;
+
+    fn contains(&self, transaction_id: TransactionId) -> Result<bool, StoreError> {
+        Ok(self.get(transaction_id)?.is_some())
+    }
+
+    /// Every transaction currently under dispute, so that the open disputes
+    /// of an earlier run can be picked up again, and counted once it's over.
+    /// Only called at the start and end of a run.
+    fn disputed(&self) -> Result<Vec<(TransactionId, ProcessedTransaction)>, StoreError>;
+
+    /// Inserts the transaction, replacing any previous one with the same ID.
+    fn insert(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: ProcessedTransaction,
+    ) -> Result<(), StoreError>;
+
+    /// Called by `process_transactions` once it's done with a row. On-disk
+    /// backends only commit here or in `flush`, so whatever they persist is
+    /// made of whole rows. Nothing to do for in-memory stores.
+    fn row_done(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Makes sure everything inserted so far is persisted.
+    /// Nothing to do for in-memory stores.
+    fn flush(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// Where the handlers keep client balances, keyed by client ID.
+///
+/// Same idea as `TransactionStore`: `get` a copy, change it, `insert` it back.
+pub trait AccountStore {
+    fn get(&self, client_id: ClientId) -> Result<Option<Client>, StoreError>;
+
+    /// Inserts the client, replacing any previous one with the same ID.
+    fn insert(&mut self, client_id: ClientId, client: Client) -> Result<(), StoreError>;
+
+    /// Every client, sorted by client ID.
+    fn clients(&self) -> Result<Vec<Client>, StoreError>;
+
+    /// See `TransactionStore::row_done`.
+    fn row_done(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Makes sure everything inserted so far is persisted.
+    /// Nothing to do for in-memory stores.
+    fn flush(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// The original storage, a plain `HashMap`. Simple but roughly 40 bytes
+/// per transaction once you count the hashing overhead.
+impl TransactionStore for HashMap<TransactionId, ProcessedTransaction> {
+    fn get(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<ProcessedTransaction>, StoreError> {
+        Ok(HashMap::get(self, &transaction_id).cloned())
+    }
+
+    fn contains(&self, transaction_id: TransactionId) -> Result<bool, StoreError> {
+        Ok(self.contains_key(&transaction_id))
+    }
+
+    fn disputed(&self) -> Result<Vec<(TransactionId, ProcessedTransaction)>, StoreError> {
+        Ok(self
+            .iter()
+            .filter(|(_, transaction)| transaction.dispute_status == DisputeStatus::Disputed)
+            .map(|(transaction_id, transaction)| (*transaction_id, transaction.clone()))
+            .collect())
+    }
+
+    fn insert(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: ProcessedTransaction,
+    ) -> Result<(), StoreError> {
+        HashMap::insert(self, transaction_id, transaction);
+        Ok(())
+    }
+}
+
+impl AccountStore for HashMap<ClientId, Client> {
+    fn get(&self, client_id: ClientId) -> Result<Option<Client>, StoreError> {
+        Ok(HashMap::get(self, &client_id).cloned())
+    }
+
+    fn insert(&mut self, client_id: ClientId, client: Client) -> Result<(), StoreError> {
+        HashMap::insert(self, client_id, client);
+        Ok(())
+    }
+
+    fn clients(&self) -> Result<Vec<Client>, StoreError> {
+        let mut clients: Vec<Client> = self.values().cloned().collect();
+        clients.sort_by_key(Client::client_id);
+        Ok(clients)
+    }
+}
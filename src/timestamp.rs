@@ -0,0 +1,117 @@
+use chrono::{DateTime, NaiveDateTime};
+use serde::Deserializer;
+use serde::de::{self, Visitor};
+
+/// Parses a point in time into seconds since the Unix epoch.
+///
+/// Accepts plain seconds (`1727740740`), RFC 3339 (`2024-09-30T23:59:00Z`)
+/// and, for humans, `2024-09-30 23:59` or `2024-09-30 23:59:00`, taken as UTC.
+pub fn parse_timestamp(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(seconds);
+    }
+
+    let seconds = match DateTime::parse_from_rfc3339(value) {
+        Ok(datetime) => datetime.timestamp(),
+        Err(_) => NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+            .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M"))
+            .ok()?
+            .and_utc()
+            .timestamp(),
+    };
+    seconds.try_into().ok()
+}
+
+/// Parses a duration: seconds, optionally with an `s`, `m`, `h` or `d`
+/// suffix, e.g. `90d`.
+pub fn parse_duration(value: &str) -> Option<u64> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, 's')) => (&value[..i], 1),
+        Some((i, 'm')) => (&value[..i], 60),
+        Some((i, 'h')) => (&value[..i], 60 * 60),
+        Some((i, 'd')) => (&value[..i], 24 * 60 * 60),
+        _ => (value, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// Formats seconds since the Unix epoch as RFC 3339, for reports.
+pub fn format_timestamp(seconds: u64) -> String {
+    i64::try_from(seconds)
+        .ok()
+        .and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+        .map(|datetime| datetime.to_rfc3339())
+        .unwrap_or(seconds.to_string())
+}
+
+/// Lets the optional `timestamp` column be anything `parse_timestamp` takes.
+///
+/// The CSV reader hands us digits as numbers and everything else as strings,
+/// and an empty or missing column as `None`.
+pub fn deserialize_timestamp<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    deserializer.deserialize_option(TimestampVisitor)
+}
+
+struct TimestampVisitor;
+
+impl<'de> Visitor<'de> for TimestampVisitor {
+    type Value = Option<u64>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "seconds since the Unix epoch or an RFC 3339 date")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_u64<E: de::Error>(self, seconds: u64) -> Result<Self::Value, E> {
+        Ok(Some(seconds))
+    }
+
+    fn visit_i64<E: de::Error>(self, seconds: i64) -> Result<Self::Value, E> {
+        u64::try_from(seconds)
+            .map(Some)
+            .map_err(|_| E::custom(format!("timestamp {} is before 1970", seconds)))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        if value.trim().is_empty() {
+            return Ok(None);
+        }
+        parse_timestamp(value)
+            .map(Some)
+            .ok_or_else(|| E::custom(format!("invalid timestamp '{}'", value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timestamp_formats() {
+        assert_eq!(parse_timestamp("1727740740"), Some(1727740740));
+        assert_eq!(parse_timestamp("2024-09-30T23:59:00Z"), Some(1727740740));
+        assert_eq!(
+            parse_timestamp("2024-10-01T01:59:00+02:00"),
+            Some(1727740740)
+        );
+        assert_eq!(parse_timestamp("2024-09-30 23:59"), Some(1727740740));
+        assert_eq!(parse_timestamp("2024-09-30 23:59:00"), Some(1727740740));
+        assert_eq!(parse_timestamp("1969-12-31 23:59"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+        assert_eq!(format_timestamp(1727740740), "2024-09-30T23:59:00+00:00");
+    }
+}